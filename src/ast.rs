@@ -1,3 +1,5 @@
+use std::collections::HashSet;
+
 use crate::common::*;
 use crate::phase::sourceq::SpanIdx;
 use crate::phase::id::PackageId;
@@ -70,7 +72,10 @@ pub enum Item {
     ModDef(Ast<ModDef>),
     StructDef(Ast<StructDef>),
     UnionDef(Ast<UnionDef>),
+    EnumDef(Ast<EnumDef>),
     PortDef(Ast<PortDef>),
+    FnDef(Ast<FnDef>),
+    ConstDef(Ast<ConstDef>),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Copy)]
@@ -82,14 +87,33 @@ pub enum Visibility {
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ModDef {
     pub name: Ident,
+    /// The names of the Nat parameters, as in `mod Fifo[W, DEPTH]`.
+    pub params: Vec<Ident>,
     pub decls: Vec<Decl>,
     pub ext: bool,
+    pub attrs: Vec<Ast<Attr>>,
     pub doc: Option<DocComment>,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Attr(pub Ident, pub Vec<AttrArg>);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AttrArg {
+    Positional(AttrValue),
+    Named(Ident, AttrValue),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AttrValue {
+    Str(String),
+    Nat(u64),
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct StructDef {
     pub name: Ident,
+    pub params: Vec<Ident>,
     pub fields: Vec<Field>,
     pub doc: Option<DocComment>,
 }
@@ -100,13 +124,28 @@ pub struct Field(pub Ident, pub Ast<Type>);
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct UnionDef {
     pub name: Ident,
+    pub params: Vec<Ident>,
     pub alts: Vec<Alt>,
+    pub attrs: Vec<Ast<Attr>>,
     pub doc: Option<DocComment>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Alt(pub Ident, pub Vec<Ast<Type>>);
 
+/// An enum type, written `enum type Op : Word[4] { ADD = 0; SUB = 1; }`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct EnumDef {
+    pub name: Ident,
+    /// The type of the encodings, which must be a Word.
+    pub typ: Ast<Type>,
+    pub values: Vec<EnumValue>,
+    pub doc: Option<DocComment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct EnumValue(pub Ident, pub Nat);
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct PortDef {
     pub name: Ident,
@@ -130,22 +169,100 @@ pub enum Decl {
     Submodule(Ast<Submodule>),
     Port(Ast<Port>),
     Wire(Ast<Wire>),
+    MemWrite(Ast<MemWrite>),
+    Connect(Ast<Connect>),
+    For(Ast<For>),
+    When(Ast<When>),
+    Property(Ast<Property>),
+    /// A wire whose target is a copy of a replicated declaration, as in `x[i] <= e` or `adder[i].inp := e`.
+    IndexedWire(Ast<IndexedWire>),
+}
+
+impl Decl {
+    /// This declaration, or else the declarations inside of a `for` or `when` which hasn't been expanded.
+    pub fn flatten(&self) -> Vec<Decl> {
+        match self {
+            Decl::For(for_decl) => for_decl.decls.iter().flat_map(|decl| decl.flatten()).collect(),
+            Decl::When(when) => when.decls.iter().chain(&when.else_decls).flat_map(|decl| decl.flatten()).collect(),
+            decl => vec![decl.clone()],
+        }
+    }
+}
+
+/// A replicated group of declarations, written `for i in 0..N { ... }`.
+/// The index may be used as a Nat, as a word, and inside of indexed names like `x[i]`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct For {
+    pub var: Ident,
+    pub start: Nat,
+    /// The end of the range, which is excluded.
+    pub end: Nat,
+    pub decls: Vec<Decl>,
+}
+
+/// A group of wires which are only driven under a condition, written `when c { ... } else { ... }`.
+/// An `else when` is an `else` holding a single `when`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct When {
+    pub cond: Ast<Expr>,
+    pub decls: Vec<Decl>,
+    pub else_decls: Vec<Decl>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Component {
     pub name: Ident,
+    /// The index of a replicated component, as in `reg x[i]`, until its `for` is expanded.
+    pub index: Option<NameIndex>,
     pub kind: ComponentKind,
     pub typ: Ast<Type>,
     pub clock: Option<Ast<Expr>>,
+    /// The hex file a `mem` is initialized from.
+    pub init: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Type {
     Clock,
-    Word(Width),
-    Vec(Ast<Type>, usize),
-    TypeRef(QualIdent),
+    Word(Nat),
+    Vec(Ast<Type>, Nat),
+    TypeRef(QualIdent, Vec<TypeArg>),
+}
+
+/// An argument to a generic type, as in `Valid[Word[8]]` or `Buffer[4]`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TypeArg {
+    Type(Ast<Type>),
+    Nat(Nat),
+}
+
+/// A natural number known at elaboration time.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Nat {
+    Lit(u64),
+    /// A parameter of the enclosing definition, or else a constant in the same package.
+    Param(Ident),
+    /// A constant in another package, as in `uart::DIVIDER_WIDTH`.
+    Const(QualIdent),
+}
+
+/// A pure function, written `fn name(x : T, ...) -> T = expr;`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FnDef {
+    pub name: Ident,
+    pub args: Vec<(Ident, Ast<Type>)>,
+    pub ret: Ast<Type>,
+    pub body: Ast<Expr>,
+    pub doc: Option<DocComment>,
+}
+
+/// A constant, written `const NAME : T = expr;`, whose value is computed at compile time.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ConstDef {
+    pub name: Ident,
+    pub typ: Ast<Type>,
+    pub value: Ast<Expr>,
+    pub doc: Option<DocComment>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Copy)]
@@ -154,23 +271,41 @@ pub enum ComponentKind {
     Outgoing,
     Node,
     Reg,
+    Mem,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Expr {
     Reference(Path),
+    /// A reference to a constant in another package, as in `uart::BAUD`.
+    QualReference(QualIdent),
     Word(WordLit),
     Vec(Vec<Ast<Expr>>),
     Struct(Option<QualIdent>, Vec<(Ident, Ast<Expr>)>),
-    MethodCall(Ast<Expr>, Ident, Vec<Ast<Expr>>),
+    /// A method call `subject->method(args)`, or `subject->method[w](args)` for methods which take a width.
+    MethodCall(Ast<Expr>, Ident, Option<Width>, Vec<Ast<Expr>>),
     Ctor(Ident, Vec<Ast<Expr>>),
+    FnCall(QualIdent, Vec<Ast<Expr>>),
     As(Ast<Expr>, Ast<Type>),
     Idx(Ast<Expr>, StaticIndex),
     IdxRange(Ast<Expr>, StaticIndex, StaticIndex),
     Cat(Vec<Ast<Expr>>),
+    /// A copy of a value with some of its parts replaced, as in `x->with([8..4] = y, [0] = b)`.
+    With(Ast<Expr>, Vec<(Update, Ast<Expr>)>),
     If(Ast<Expr>, Ast<Expr>, Ast<Expr>),
     Let(Ident, Option<Ast<Type>>, Ast<Expr>, Ast<Expr>),
     Match(Ast<Expr>, Option<Ast<Type>>, Vec<MatchArm>),
+    /// A reference into a copy of a replicated declaration, as in `x[i]` or `adder[3].out`.
+    /// These are resolved to ordinary references when the moddef's loops are expanded.
+    IndexedReference(IndexedName, Option<Path>),
+}
+
+/// The part of a value replaced by `->with`: a bit of a word or an element of a vec, a range of bits, or a field of a struct.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Update {
+    Idx(StaticIndex),
+    IdxRange(StaticIndex, StaticIndex),
+    Field(Ident),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -186,6 +321,53 @@ pub enum Pat {
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Wire(pub Path, pub WireType, pub Ast<Expr>);
 
+/// The target is the copy `name[index]`, followed by the rest of the path, if any.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct IndexedWire(pub IndexedName, pub Option<Path>, pub WireType, pub Ast<Expr>);
+
+/// One copy of a replicated declaration, as in `x[i]` or `x[3]`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct IndexedName(pub Ident, pub NameIndex);
+
+/// The index in the name of a replicated declaration: a number, or the variable of an enclosing `for`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum NameIndex {
+    Lit(u64),
+    Var(Ident),
+}
+
+/// The name of the copy of `name` with index `i`.
+/// Once its `for` is expanded, the rest of the compiler treats it as an ordinary name, printed as `x[3]`.
+pub fn replicated_name(name: &Ident, i: u64) -> Ident {
+    format!("{name}[{i}]").as_str().into()
+}
+
+/// A write port of a `mem`, written `m[addr] <= data when enable;`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MemWrite {
+    pub mem: Ident,
+    pub addr: Ast<Expr>,
+    pub data: Ast<Expr>,
+    pub enable: Option<Ast<Expr>>,
+}
+
+/// An assertion or a cover property, written `assert cond on clock;` or `cover cond on clock;`.
+/// It is checked on each edge of the clock, but only where `enable` holds.
+/// The enable comes from an enclosing `when`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Property {
+    pub kind: PropertyKind,
+    pub cond: Ast<Expr>,
+    pub clock: Ast<Expr>,
+    pub enable: Option<Ast<Expr>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Copy)]
+pub enum PropertyKind {
+    Assert,
+    Cover,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Copy)]
 pub enum WireType {
     Continuous,
@@ -195,7 +377,9 @@ pub enum WireType {
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Submodule {
     pub name: Ident,
+    pub index: Option<NameIndex>,
     pub moddef: QualIdent,
+    pub args: Vec<Nat>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Copy)]
@@ -205,13 +389,158 @@ pub enum PortRole {
 }
 
 
+impl PortRole {
+    /// The role of a port of a submodule, as seen from the module which instantiates it.
+    pub fn flip(self) -> PortRole {
+        match self {
+            PortRole::Master => PortRole::Slave,
+            PortRole::Slave => PortRole::Master,
+        }
+    }
+
+    /// Whether a module drives a channel of a port it plays this role on.
+    pub fn drives(self, dir: ChannelDir) -> bool {
+        matches!((self, dir), (PortRole::Master, ChannelDir::Mosi) | (PortRole::Slave, ChannelDir::Miso))
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Port {
     pub name: Ident,
+    pub index: Option<NameIndex>,
     pub role: PortRole,
     pub portdef: QualIdent,
 }
 
+/// A bulk connection between two port instances, written `a :=: b`.
+/// Each channel is wired from whichever side drives it to the other.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Connect(pub Path, pub Path);
+
+impl Ast<Expr> {
+    /// The immediate subexpressions of an expression.
+    pub fn subexprs(&self) -> Vec<&Ast<Expr>> {
+        match self.as_ref() {
+            Expr::Reference(_) | Expr::QualReference(_) | Expr::Word(_) | Expr::IndexedReference(..) => vec![],
+            Expr::Vec(es) | Expr::Cat(es) | Expr::Ctor(_, es) | Expr::FnCall(_, es) => es.iter().collect(),
+            Expr::Struct(_name, fields) => fields.iter().map(|(_field, e)| e).collect(),
+            Expr::MethodCall(subject, _method, _width, args) => std::iter::once(subject).chain(args).collect(),
+            Expr::As(e, _) | Expr::Idx(e, _) | Expr::IdxRange(e, _, _) => vec![e],
+            Expr::With(subject, updates) => std::iter::once(subject).chain(updates.iter().map(|(_update, e)| e)).collect(),
+            Expr::If(c, a, b) => vec![c, a, b],
+            Expr::Let(_x, _typ, e, body) => vec![e, body],
+            Expr::Match(subject, _typ, arms) => std::iter::once(subject).chain(arms.iter().map(|MatchArm(_pat, e)| e)).collect(),
+        }
+    }
+
+    /// Rebuilds an expression with `f` applied to each of its immediate subexpressions.
+    pub fn map_subexprs(&self, f: &mut dyn FnMut(&Ast<Expr>) -> Ast<Expr>) -> Ast<Expr> {
+        let e = match self.as_ref() {
+            Expr::Reference(_) | Expr::QualReference(_) | Expr::Word(_) | Expr::IndexedReference(..) => return self.clone(),
+            Expr::Vec(es) => Expr::Vec(es.iter().map(&mut *f).collect()),
+            Expr::Cat(es) => Expr::Cat(es.iter().map(&mut *f).collect()),
+            Expr::Ctor(ctor, es) => Expr::Ctor(ctor.clone(), es.iter().map(&mut *f).collect()),
+            Expr::FnCall(name, es) => Expr::FnCall(name.clone(), es.iter().map(&mut *f).collect()),
+            Expr::Struct(name, fields) => {
+                Expr::Struct(name.clone(), fields.iter().map(|(field, e)| (field.clone(), f(e))).collect())
+            },
+            Expr::MethodCall(subject, method, width, args) => {
+                let subject = f(subject);
+                Expr::MethodCall(subject, method.clone(), *width, args.iter().map(&mut *f).collect())
+            },
+            Expr::As(e, typ) => Expr::As(f(e), typ.clone()),
+            Expr::Idx(e, i) => Expr::Idx(f(e), *i),
+            Expr::IdxRange(e, j, i) => Expr::IdxRange(f(e), *j, *i),
+            Expr::With(subject, updates) => {
+                let subject = f(subject);
+                Expr::With(subject, updates.iter().map(|(update, e)| (update.clone(), f(e))).collect())
+            },
+            Expr::If(c, a, b) => Expr::If(f(c), f(a), f(b)),
+            Expr::Let(x, typ, e, body) => Expr::Let(x.clone(), typ.clone(), f(e), f(body)),
+            Expr::Match(subject, typ, arms) => {
+                let subject = f(subject);
+                let arms = arms.iter().map(|MatchArm(pat, e)| MatchArm(pat.clone(), f(e))).collect();
+                Expr::Match(subject, typ.clone(), arms)
+            },
+        };
+        self.derive(e)
+    }
+}
+
+/// The index of a name written like an expression, as in `x[3]` or `x[i]`.
+pub fn name_index(expr: &Ast<Expr>) -> Option<NameIndex> {
+    match expr.as_ref() {
+        Expr::Word(lit) if lit.width.is_none() => Some(NameIndex::Lit(lit.value)),
+        Expr::Reference(path) => path.as_ident().map(NameIndex::Var),
+        _ => None,
+    }
+}
+
+/// A reference into a replicated component, as in `x[i]` or `adder[3].out`.
+pub fn indexed_reference<T>(subject: &Ast<Expr>, index: NameIndex, rest: Option<Path>) -> Result<Expr, lalrpop_util::ParseError<usize, T, &'static str>> {
+    match subject.as_ref() {
+        Expr::Reference(path) => match path.as_ident() {
+            Some(name) => Ok(Expr::IndexedReference(IndexedName(name, index), rest)),
+            None => Err(lalrpop_util::ParseError::User { error: "Only names may be indexed by a for variable" }),
+        },
+        _ => Err(lalrpop_util::ParseError::User { error: "Only names may be indexed by a for variable" }),
+    }
+}
+
+/// In the body of a mod, `x[i] <= e` is a write to the mem `x`, unless `x` is declared with an index.
+/// Then it drives the copy `x[i]` of a replicated reg instead.
+pub fn resolve_indexed_writes(decls: Vec<Decl>) -> Vec<Decl> {
+    fn indexed_names(decls: &[Decl], names: &mut HashSet<Ident>) {
+        for decl in decls {
+            match decl {
+                Decl::Component(component) if component.index.is_some() => {
+                    names.insert(component.name.clone());
+                },
+                Decl::Submodule(submodule) if submodule.index.is_some() => {
+                    names.insert(submodule.name.clone());
+                },
+                Decl::Port(port) if port.index.is_some() => {
+                    names.insert(port.name.clone());
+                },
+                Decl::For(for_decl) => indexed_names(&for_decl.decls, names),
+                Decl::When(when) => {
+                    indexed_names(&when.decls, names);
+                    indexed_names(&when.else_decls, names);
+                },
+                _ => (),
+            }
+        }
+    }
+
+    fn resolve(decls: Vec<Decl>, names: &HashSet<Ident>) -> Vec<Decl> {
+        decls.into_iter().map(|decl| match decl {
+            Decl::MemWrite(mem_write) if names.contains(&mem_write.mem) && mem_write.enable.is_none() => {
+                match name_index(&mem_write.addr) {
+                    Some(index) => {
+                        let target = IndexedName(mem_write.mem.clone(), index);
+                        Decl::IndexedWire(mem_write.derive(IndexedWire(target, None, WireType::Latched, mem_write.data.clone())))
+                    },
+                    None => Decl::MemWrite(mem_write),
+                }
+            },
+            Decl::For(for_decl) => Decl::For(for_decl.derive(For {
+                decls: resolve(for_decl.decls.clone(), names),
+                ..for_decl.as_ref().clone()
+            })),
+            Decl::When(when) => Decl::When(when.derive(When {
+                cond: when.cond.clone(),
+                decls: resolve(when.decls.clone(), names),
+                else_decls: resolve(when.else_decls.clone(), names),
+            })),
+            decl => decl,
+        }).collect()
+    }
+
+    let mut names = HashSet::new();
+    indexed_names(&decls, &mut names);
+    resolve(decls, &names)
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct WordLit {
     pub value: u64,
@@ -219,6 +548,28 @@ pub struct WordLit {
     pub spelling: String,
 }
 
+/// Parses a literal like `42`, `0xff`, `0b1010w4` or `1_000w16`, keeping its spelling.
+pub fn word_lit<T>(spelling: &str) -> Result<WordLit, lalrpop_util::ParseError<usize, T, &'static str>> {
+    let user_error = |error| lalrpop_util::ParseError::User { error };
+    let digits: String = spelling.chars().filter(|&c| c != '_').collect();
+    let (digits, width) = match digits.split_once('w') {
+        Some((digits, width)) => (digits, Some(width.parse().map_err(|_| user_error("Width is too large"))?)),
+        None => (digits.as_str(), None),
+    };
+    let value = if let Some(digits) = digits.strip_prefix("0x") {
+        u64::from_str_radix(digits, 16)
+    } else if let Some(digits) = digits.strip_prefix("0b") {
+        u64::from_str_radix(digits, 2)
+    } else {
+        digits.parse()
+    };
+    Ok(WordLit {
+        value: value.map_err(|_| user_error("Literal is too large"))?,
+        width,
+        spelling: spelling.to_string(),
+    })
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct DocComment(pub String);
 
@@ -226,4 +577,9 @@ impl<T: Clone> Ast<T> {
     pub fn id(&self) -> AstId {
         self.2.clone()
     }
+
+    /// A node generated from this one during desugaring, which shares its span.
+    pub fn derive<U>(&self, u: U) -> Ast<U> {
+        Ast(Arc::new(u), self.1.clone(), self.2)
+    }
 }
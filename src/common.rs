@@ -309,11 +309,11 @@ impl Default for ExprPath {
 }
 
 pub fn clog2(n: u64) -> u64 {
-    let mut result = 0;
-    while n > (1 << result) {
-        result += 1;
+    if n <= 1 {
+        0
+    } else {
+        (u64::BITS - (n - 1).leading_zeros()) as u64
     }
-    result
 }
 
 pub fn is_pow2(n: u64) -> bool {
@@ -7,6 +7,8 @@ pub mod parse;
 pub mod context;
 pub mod phase;
 pub mod verilog;
+pub mod verilog_header;
+pub mod verilog_ast;
 
 // pub mod vcd;
 // pub mod mlir;
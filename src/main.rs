@@ -1,13 +1,8 @@
-use std::collections::HashSet;
-
 use virdant::common::*;
-use virdant::phase;
-use virdant::phase::imports::ImportsQ;
 use virdant::phase::Db;
 use virdant::phase::check::CheckQ;
 
 use clap::Parser;
-use virdant::phase::PackageId;
 
 #[derive(Parser, Debug)]
 #[command(name = "vir", author, version, about, long_about = None)]
@@ -31,6 +26,9 @@ struct Args {
 
     #[arg(long)]
     trace: Option<String>,
+
+    #[arg(long)]
+    out_dir: Option<String>,
 }
 
 fn main() {
@@ -38,15 +36,25 @@ fn main() {
     if args.compile || true {
 
         let path = std::path::PathBuf::from(args.filename.clone());
-        let db = load_from_top_source(&path).unwrap();
+        let db = Db::load(&path).unwrap();
 
         if let Err(e) = db.check() {
             print_errors(&e);
             std::process::exit(-1);
         }
 
-        let mut stdout = std::io::stdout();
-        if let Err(e) = db.verilog(&mut stdout) {
+        for moddef_id in db.unchecked_moddefs().unwrap() {
+            eprintln!("Warning: {moddef_id} is never instantiated, so its body was not checked. Errors in it are only reported once it is.");
+        }
+
+        let result = if let Some(out_dir) = &args.out_dir {
+            db.verilog_files(std::path::Path::new(out_dir))
+        } else {
+            let mut stdout = std::io::stdout();
+            db.verilog(&mut stdout)
+        };
+
+        if let Err(e) = result {
             eprintln!("{e:?}");
             std::process::exit(-1);
         }
@@ -59,42 +67,6 @@ fn main() {
     }
 }
 
-fn load_from_top_source(path: &std::path::Path) -> VirdantResult<Db> {
-    let mut db = phase::Db::new();
-    let mut queue = vec![];
-    let mut imported = HashSet::new();
-
-    let source_dir = path.parent().unwrap();
-
-    let package = import_from_fillepath(&mut db, path);
-    imported.insert(package.clone());
-
-    for package in db.package_imports(package)? {
-        if !imported.contains(&package) {
-            queue.push(package.clone());
-        }
-    }
-
-    while let Some(package) = queue.pop() {
-        let path = source_dir.join(format!("{package}.vir"));
-        let package = import_from_fillepath(&mut db, &path);
-        for package in db.package_imports(package)? {
-            if !imported.contains(&package) {
-                queue.push(package.clone());
-            }
-        }
-    }
-
-    Ok(db)
-}
-
-fn import_from_fillepath(db: &mut Db, path: &std::path::Path) -> PackageId {
-    let package_name = path.file_stem().unwrap().to_string_lossy();
-    eprintln!("LOADING PACKAGE: {package_name} ({})", path.to_string_lossy());
-    let package_text = std::fs::read_to_string(&path).unwrap();
-    db.set_source(&package_name, &package_text)
-}
-
 fn print_errors(e: &VirdantError) {
     match e {
         VirdantError::Multiple(es) => {
@@ -15,3 +15,147 @@ pub fn parse_package(package_name: &str, package_text: &str) -> VirdantResult<As
         Err(err) => Err(VirdantError::ParseError(format!("{err:?}"))),
     }
 }
+
+#[test]
+fn parse_operators() {
+    use crate::ast::Expr;
+
+    fn show(expr: &Ast<Expr>) -> String {
+        match expr.as_ref() {
+            Expr::Reference(path) => path.to_string(),
+            Expr::Word(lit) => lit.value.to_string(),
+            Expr::Idx(subject, i) => format!("{}[{i}]", show(subject)),
+            Expr::MethodCall(subject, method, _width, args) => {
+                let args: Vec<String> = args.iter().map(show).collect();
+                format!("{}->{method}({})", show(subject), args.join(", "))
+            },
+            _ => panic!("Unexpected expression"),
+        }
+    }
+
+    let parse = |text: &str| grammar::ExprParser::new().parse(&mut AstGen::new("test"), text).map(|expr| show(&expr)).ok();
+
+    let cases = [
+        ("a + b & c", "a->add(b)->and(c)"),
+        ("a & b + c", "a->and(b->add(c))"),
+        ("a | b ^ c & d", "a->or(b->xor(c->and(d)))"),
+        ("a - b - c", "a->sub(b)->sub(c)"),
+        ("a << 1 + b", "a->sll(1->add(b))"),
+        ("a + b == c | d", "a->add(b)->eq(c->or(d))"),
+        ("a != b", "a->neq(b)"),
+        ("a <= b", "a->lte(b)"),
+        ("!a & -b", "a->not()->and(b->neg())"),
+        ("x->inc() + y[0]", "x->inc()->add(y[0])"),
+    ];
+
+    for (text, expected) in cases {
+        assert_eq!(parse(text).unwrap(), expected, "{text}");
+    }
+
+    assert!(parse("a == b == c").is_none());
+}
+
+#[test]
+fn parse_literals() {
+    use crate::ast::Expr;
+
+    let parse = |text: &str| match grammar::ExprParser::new().parse(&mut AstGen::new("test"), text).ok()?.as_ref() {
+        Expr::Word(lit) => Some((lit.value, lit.width, lit.spelling.clone())),
+        _ => None,
+    };
+
+    let cases = [
+        ("42", 42, None),
+        ("1_000", 1000, None),
+        ("0xff", 255, None),
+        ("0xffw16", 255, Some(16)),
+        ("0b1010_1010", 170, None),
+        ("0b1111_0000", 240, None),
+        ("0x0_fw8", 15, Some(8)),
+        ("0b1010w4", 10, Some(4)),
+    ];
+
+    for (text, value, width) in cases {
+        assert_eq!(parse(text), Some((value, width, text.to_string())), "{text}");
+    }
+
+    assert!(parse("0x1_0000_0000_0000_0000").is_none());
+    assert!(parse("1w100000000000000000000").is_none());
+}
+
+#[test]
+fn parse_for_loops() {
+    use crate::ast::{Decl, Expr, Item, IndexedName, IndexedWire, NameIndex, Nat, WireType};
+
+    let package = parse_package("top", "
+        mod Top {
+            for i in 0..N {
+                reg r[i] : Word[8] on clock;
+                r[i] <= inp;
+                mod delay[i] of Delay;
+                delay[i].inp := r[i];
+            }
+            out := delay[0].out->add(x[1]);
+        }
+    ").unwrap();
+    let Item::ModDef(moddef) = &package.items[0] else { panic!() };
+
+    let Decl::For(for_decl) = &moddef.decls[0] else { panic!() };
+    assert_eq!(for_decl.var.to_string(), "i");
+    assert_eq!((&for_decl.start, &for_decl.end), (&Nat::Lit(0), &Nat::Param("N".into())));
+
+    let i = NameIndex::Var("i".into());
+    let Decl::Component(reg) = &for_decl.decls[0] else { panic!() };
+    assert_eq!((reg.name.to_string(), &reg.index), ("r".to_string(), &Some(i.clone())));
+
+    let Decl::IndexedWire(wire) = &for_decl.decls[1] else { panic!() };
+    let IndexedWire(target, rest, wire_type, _e) = wire.as_ref();
+    assert_eq!(target, &IndexedName("r".into(), i.clone()));
+    assert_eq!((rest, wire_type), (&None, &WireType::Latched));
+
+    let Decl::IndexedWire(wire) = &for_decl.decls[3] else { panic!() };
+    let IndexedWire(target, rest, wire_type, e) = wire.as_ref();
+    assert_eq!(target, &IndexedName("delay".into(), i.clone()));
+    assert_eq!((rest.as_ref().map(|rest| rest.to_string()), wire_type), (Some("inp".to_string()), &WireType::Continuous));
+    assert_eq!(e.as_ref(), &Expr::IndexedReference(IndexedName("r".into(), i), None));
+
+    // Outside of a loop, `x[1]` may be a copy or an index, which is only known once the loops are expanded.
+    let Decl::Wire(wire) = &moddef.decls[1] else { panic!() };
+    let Expr::MethodCall(subject, _method, _width, args) = wire.2.as_ref() else { panic!() };
+    let Expr::IndexedReference(name, Some(rest)) = subject.as_ref() else { panic!() };
+    assert_eq!((name, rest.to_string()), (&IndexedName("delay".into(), NameIndex::Lit(0)), "out".to_string()));
+    assert!(matches!(args[0].as_ref(), Expr::Idx(_x, 1)));
+
+    assert!(parse_package("top", "mod Top { reg r[a->inc()] : Word[8] on clock; }").is_err());
+}
+
+#[test]
+fn parse_whens() {
+    use crate::ast::{Decl, Expr, Item};
+
+    let package = parse_package("top", "
+        mod Top {
+            when c {
+                out := a;
+            } else when d {
+                out := b;
+                r <= b;
+            } else {
+                out := e;
+            }
+        }
+    ").unwrap();
+    let Item::ModDef(moddef) = &package.items[0] else { panic!() };
+
+    let Decl::When(when) = &moddef.decls[0] else { panic!() };
+    assert_eq!(when.cond.as_ref(), &Expr::Reference("c".into()));
+    assert!(matches!(when.decls.as_slice(), [Decl::Wire(_)]));
+
+    // An `else when` is a `when` nested in the else branch.
+    let [Decl::When(else_when)] = when.else_decls.as_slice() else { panic!() };
+    assert_eq!(else_when.cond.as_ref(), &Expr::Reference("d".into()));
+    assert!(matches!(else_when.decls.as_slice(), [Decl::Wire(_), Decl::Wire(_)]));
+    assert!(matches!(else_when.else_decls.as_slice(), [Decl::Wire(_)]));
+
+    assert!(parse_package("top", "mod Top { when c { out := a; } else out := b; }").is_err());
+}
@@ -16,8 +16,9 @@ pub mod id;
 pub use id::*;
 
 use crate::common::*;
+use crate::virdant_error;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 #[salsa::database(
     sourceq::SourceQStorage,
@@ -47,6 +48,7 @@ impl Db {
         };
         let sources = HashMap::new();
         db.set_sources(sources);
+        db.set_source_dirs(HashMap::new());
         db
     }
 
@@ -57,6 +59,52 @@ impl Db {
         self.set_sources(sources);
         PackageId::from_ident(package.to_string().into())
     }
+
+    /// Loads the package at `path`, along with the packages it imports, which are found next to it.
+    pub fn load(path: &std::path::Path) -> VirdantResult<Db> {
+        use self::imports::ImportsQ;
+        let mut db = Db::new();
+        let mut queue = vec![];
+        let mut imported = HashSet::new();
+
+        let source_dir = path.parent().unwrap_or(std::path::Path::new("."));
+
+        let package = db.load_package(path)?;
+        imported.insert(package.clone());
+        queue.extend(db.package_imports(package)?);
+
+        while let Some(package) = queue.pop() {
+            if !imported.insert(package.clone()) {
+                continue;
+            }
+            let path = source_dir.join(format!("{package}.vir"));
+            let package = db.load_package(&path)?;
+            queue.extend(db.package_imports(package)?);
+        }
+
+        Ok(db)
+    }
+
+    fn load_package(&mut self, path: &std::path::Path) -> VirdantResult<PackageId> {
+        let package_name = path.file_stem().unwrap().to_string_lossy().to_string();
+        eprintln!("LOADING PACKAGE: {package_name} ({})", path.to_string_lossy());
+        let Ok(package_text) = std::fs::read_to_string(path) else {
+            return Err(virdant_error!("Could not read {}", path.to_string_lossy()));
+        };
+        if let Some(dir) = path.parent() {
+            self.set_source_dir(&package_name, dir);
+        }
+        Ok(self.set_source(&package_name, &package_text))
+    }
+
+    /// Sets the directory the package was loaded from.
+    /// Paths written in the package (such as the sources of `ext mod`s) are relative to it.
+    pub fn set_source_dir(&mut self, package: &str, dir: &std::path::Path) {
+        use self::sourceq::*;
+        let mut source_dirs = self.source_dirs();
+        source_dirs.insert(package.into(), dir.to_path_buf());
+        self.set_source_dirs(source_dirs);
+    }
 }
 
 #[derive(Clone, PartialEq, Eq, Hash)]
@@ -85,8 +133,10 @@ pub enum Type {
     Clock,
     Bool,
     Word(Width),
+    Vec(Arc<Type>, u64),
     Union(UnionDefId, Vec<TypeArg>),
     Struct(StructDefId, Vec<TypeArg>),
+    Enum(EnumDefId),
 }
 
 #[derive(Clone, Hash, PartialEq, Eq, Debug)]
@@ -95,6 +145,9 @@ pub struct MethodSig(Vec<Type>, Type);
 #[derive(Clone, Hash, PartialEq, Eq, Debug)]
 pub struct CtorSig(Vec<Type>, Type);
 
+#[derive(Clone, Hash, PartialEq, Eq, Debug)]
+pub struct FnSig(Vec<Type>, Type);
+
 
 impl std::fmt::Display for Type {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
@@ -102,25 +155,21 @@ impl std::fmt::Display for Type {
             Type::Clock => write!(f, "Clock"),
             Type::Bool => write!(f, "Bool"),
             Type::Word(width) => write!(f, "Word[{width}]"),
+            Type::Vec(typ, n) => write!(f, "Vec[{typ}, {n}]"),
             Type::Struct(structdef, args) => {
                 write!(f, "{structdef}")?;
                 if args.len() > 0 {
-                    write!(f, "[")?;
-                    for arg in args {
-                        write!(f, "{arg}")?;
-                    }
-                    write!(f, "]")?;
+                    let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                    write!(f, "[{}]", args.join(", "))?;
                 }
                 Ok(())
             },
+            Type::Enum(enumdef) => write!(f, "{enumdef}"),
             Type::Union(uniondef, args) => {
                 write!(f, "{uniondef}")?;
                 if args.len() > 0 {
-                    write!(f, "[")?;
-                    for arg in args {
-                        write!(f, "{arg}")?;
-                    }
-                    write!(f, "]")?;
+                    let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                    write!(f, "[{}]", args.join(", "))?;
                 }
                 Ok(())
             },
@@ -177,3 +226,33 @@ fn phase() {
 
     db.check().unwrap();
 }
+
+#[test]
+fn fndef_and_constdef_item_dependencies() {
+    use self::item_resolution::*;
+    use self::item_dependency::*;
+
+    let mut db = Db::new();
+    db.set_source("top", "
+        union type Flag { On(); Off(); }
+        const ONE : Word[8] = 1;
+        fn inc(x : Word[8]) -> Word[8] = x->add(ONE);
+        fn flag(x : Word[8]) -> Flag = @On();
+        fn both(x : Word[8]) -> Word[8] = inc(inc(x));
+        const TWO : Word[8] = ONE->add(ONE);
+        const BIG : Word[8] = inc(TWO);
+    ");
+
+    let dependency_names = |name: &str| {
+        let item = db.items().unwrap().into_iter().find(|item| item.name().as_str() == name).unwrap();
+        let mut names: Vec<String> = db.item_dependencies(item).unwrap().iter().map(|item| item.to_string()).collect();
+        names.sort();
+        names
+    };
+
+    assert_eq!(dependency_names("inc"), vec!["top::ONE"]);
+    assert_eq!(dependency_names("flag"), vec!["top::Flag"]);
+    assert_eq!(dependency_names("both"), vec!["top::inc"]);
+    assert_eq!(dependency_names("TWO"), vec!["top::ONE"]);
+    assert_eq!(dependency_names("BIG"), vec!["top::TWO", "top::inc"]);
+}
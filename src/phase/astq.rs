@@ -1,9 +1,13 @@
+use std::collections::HashSet;
+
 use crate::ast::ComponentKind;
 use crate::parse;
 use crate::ast;
 use crate::ast::Ast;
 use crate::common::*;
 use crate::virdant_error;
+use crate::virdant_error_at;
+use crate::phase::sourceq::SpanIdx;
 use super::*;
 
 #[salsa::query_group(AstQStorage)]
@@ -16,6 +20,9 @@ pub trait AstQ: sourceq::SourceQ {
     fn uniondef_ast(&self, uniondef_id: UnionDefId) -> VirdantResult<Ast<ast::UnionDef>>;
     fn structdef_ast(&self, structdef_id: StructDefId) -> VirdantResult<Ast<ast::StructDef>>;
     fn portdef_ast(&self, portdef_id: PortDefId) -> VirdantResult<Ast<ast::PortDef>>;
+    fn fndef_ast(&self, fndef_id: FnDefId) -> VirdantResult<Ast<ast::FnDef>>;
+    fn constdef_ast(&self, constdef_id: ConstDefId) -> VirdantResult<Ast<ast::ConstDef>>;
+    fn enumdef_ast(&self, enumdef_id: EnumDefId) -> VirdantResult<Ast<ast::EnumDef>>;
 
     fn component_ast(&self, component_id: ComponentId) -> VirdantResult<Ast<ast::Component>>;
 
@@ -86,11 +93,40 @@ fn item_ast(db: &dyn AstQ, item_id: ItemId) -> VirdantResult<ast::Item> {
                     }
                 }
             },
-            _ => (),
+            ast::Item::FnDef(fndef_ast) => {
+                if fndef_ast.name == item_id.name() {
+                    if result.is_none() {
+                        result = Some(ast::Item::FnDef(fndef_ast.clone()));
+                    } else {
+                        return Err(virdant_error!("Uh oh"));
+                    }
+                }
+            },
+            ast::Item::ConstDef(constdef_ast) => {
+                if constdef_ast.name == item_id.name() {
+                    if result.is_none() {
+                        result = Some(ast::Item::ConstDef(constdef_ast.clone()));
+                    } else {
+                        return Err(virdant_error!("Uh oh"));
+                    }
+                }
+            },
+            ast::Item::EnumDef(enumdef_ast) => {
+                if enumdef_ast.name == item_id.name() {
+                    if result.is_none() {
+                        result = Some(ast::Item::EnumDef(enumdef_ast.clone()));
+                    } else {
+                        return Err(virdant_error!("Uh oh"));
+                    }
+                }
+            },
         }
     }
 
-    result.ok_or_else(|| virdant_error!("Unknown item {item_id}"))
+    match (result, item_id.clone()) {
+        (Some(ast::Item::ModDef(_moddef_ast)), ItemId::ModDef(moddef_id)) => Ok(ast::Item::ModDef(db.moddef_ast(moddef_id)?)),
+        (result, _item_id) => result.ok_or_else(|| virdant_error!("Unknown item {item_id}")),
+    }
 }
 
 fn moddef_ast(db: &dyn AstQ, moddef_id: ModDefId) -> VirdantResult<Ast<ast::ModDef>> {
@@ -112,7 +148,8 @@ fn moddef_ast(db: &dyn AstQ, moddef_id: ModDefId) -> VirdantResult<Ast<ast::ModD
         }
     }
 
-    result.ok_or_else(|| virdant_error!("Unknown moddef {moddef_id}"))
+    let moddef_ast = result.ok_or_else(|| virdant_error!("Unknown moddef {moddef_id}"))?;
+    expand_moddef(db, &moddef_ast, &moddef_id)
 }
 
 fn uniondef_ast(db: &dyn AstQ, uniontype_id: UnionDefId) -> VirdantResult<Ast<ast::UnionDef>> {
@@ -181,6 +218,63 @@ fn portdef_ast(db: &dyn AstQ, portdef_id: PortDefId) -> VirdantResult<Ast<ast::P
     result.ok_or_else(|| virdant_error!("Unknown portdef {portdef_id}"))
 }
 
+fn fndef_ast(db: &dyn AstQ, fndef_id: FnDefId) -> VirdantResult<Ast<ast::FnDef>> {
+    let package_ast = db.package_ast(fndef_id.package())?;
+    let mut result: Option<Ast<ast::FnDef>> = None;
+
+    for item in &package_ast.items {
+        if let ast::Item::FnDef(fndef_ast) = item {
+            if fndef_ast.name == fndef_id.name() {
+                if result.is_none() {
+                    result = Some(fndef_ast.clone());
+                } else {
+                    return Err(virdant_error!("Uh oh"));
+                }
+            }
+        }
+    }
+
+    result.ok_or_else(|| virdant_error!("Unknown fndef {fndef_id}"))
+}
+
+fn constdef_ast(db: &dyn AstQ, constdef_id: ConstDefId) -> VirdantResult<Ast<ast::ConstDef>> {
+    let package_ast = db.package_ast(constdef_id.package())?;
+    let mut result: Option<Ast<ast::ConstDef>> = None;
+
+    for item in &package_ast.items {
+        if let ast::Item::ConstDef(constdef_ast) = item {
+            if constdef_ast.name == constdef_id.name() {
+                if result.is_none() {
+                    result = Some(constdef_ast.clone());
+                } else {
+                    return Err(virdant_error!("Uh oh"));
+                }
+            }
+        }
+    }
+
+    result.ok_or_else(|| virdant_error!("Unknown constdef {constdef_id}"))
+}
+
+fn enumdef_ast(db: &dyn AstQ, enumdef_id: EnumDefId) -> VirdantResult<Ast<ast::EnumDef>> {
+    let package_ast = db.package_ast(enumdef_id.package())?;
+    let mut result: Option<Ast<ast::EnumDef>> = None;
+
+    for item in &package_ast.items {
+        if let ast::Item::EnumDef(enumdef_ast) = item {
+            if enumdef_ast.name == enumdef_id.name() {
+                if result.is_none() {
+                    result = Some(enumdef_ast.clone());
+                } else {
+                    return Err(virdant_error!("Uh oh"));
+                }
+            }
+        }
+    }
+
+    result.ok_or_else(|| virdant_error!("Unknown enumdef {enumdef_id}"))
+}
+
 fn component_ast(db: &dyn AstQ, component_id: ComponentId) -> VirdantResult<Ast<ast::Component>> {
     let moddef_ast = db.moddef_ast(component_id.moddef())?;
     for decl in &moddef_ast.decls {
@@ -205,7 +299,9 @@ fn wire_ast(db: &dyn AstQ, moddef_id: ModDefId, path: Path) -> VirdantResult<Opt
                 }
             },
             ast::Decl::Component(component) => {
-                if component.kind == ComponentKind::Incoming && component.name.as_path() == path {
+                // Incoming components are driven from outside, and mems through their write ports.
+                let undriven = component.kind == ComponentKind::Incoming || component.kind == ComponentKind::Mem;
+                if undriven && component.name.as_path() == path {
                     return Ok(None);
                 }
             },
@@ -214,3 +310,537 @@ fn wire_ast(db: &dyn AstQ, moddef_id: ModDefId, path: Path) -> VirdantResult<Opt
     }
     Err(virdant_error!("No such wire: {}", path))
 }
+
+////////////////////////////////////////////////////////////////////////////////
+// Replication
+////////////////////////////////////////////////////////////////////////////////
+
+/// Expands each `for` in a moddef into one copy of its body for each value of the index,
+/// so that later phases only see plain components, named like `x[3]`.
+/// Loops whose bounds depend on parameters are only expanded in specializations of the moddef.
+fn expand_moddef(db: &dyn AstQ, moddef_ast: &Ast<ast::ModDef>, moddef_id: &ModDefId) -> VirdantResult<Ast<ast::ModDef>> {
+    let expanded = |decl: &ast::Decl| match decl {
+        ast::Decl::For(_) | ast::Decl::When(_) | ast::Decl::IndexedWire(_) => true,
+        ast::Decl::Component(component) => component.index.is_some(),
+        ast::Decl::Submodule(submodule) => submodule.index.is_some(),
+        ast::Decl::Port(port) => port.index.is_some(),
+        _ => false,
+    };
+    if !moddef_ast.decls.iter().any(expanded) {
+        return Ok(moddef_ast.clone());
+    }
+
+    let args = moddef_id.args();
+    let params: Vec<(Ident, Option<u64>)> = moddef_ast.params.iter().enumerate()
+        .map(|(i, param)| (param.clone(), args.get(i).copied()))
+        .collect();
+
+    let mut decls = vec![];
+    for decl in &moddef_ast.decls {
+        expand_decl(decl, &[], &params, &mut decls)?;
+    }
+
+    let names: HashSet<Ident> = decls.iter().filter_map(decl_name).collect();
+    let mut errors = ErrorReport::new();
+    let mut named_decls = vec![];
+    for decl in &decls {
+        match name_decl(db, decl, &names) {
+            Ok(decl) => named_decls.push(decl),
+            Err(err) => errors.add(err),
+        }
+    }
+    errors.check()?;
+
+    let decls = desugar_whens(db, &named_decls)?;
+    Ok(moddef_ast.derive(ast::ModDef {
+        decls,
+        ..moddef_ast.as_ref().clone()
+    }))
+}
+
+/// The name a declaration has once its loops are expanded, as in `x` or `x[3]`.
+fn decl_name(decl: &ast::Decl) -> Option<Ident> {
+    let (name, index) = match decl {
+        ast::Decl::Component(component) => (&component.name, &component.index),
+        ast::Decl::Submodule(submodule) => (&submodule.name, &submodule.index),
+        ast::Decl::Port(port) => (&port.name, &port.index),
+        _ => return None,
+    };
+    match index {
+        None => Some(name.clone()),
+        Some(ast::NameIndex::Lit(i)) => Some(ast::replicated_name(name, *i)),
+        Some(ast::NameIndex::Var(_var)) => None,
+    }
+}
+
+fn expand_decl(
+    decl: &ast::Decl,
+    vars: &[(Ident, u64)],
+    params: &[(Ident, Option<u64>)],
+    decls: &mut Vec<ast::Decl>,
+) -> VirdantResult<()> {
+    let for_decl = match decl {
+        ast::Decl::For(for_decl) => for_decl,
+        ast::Decl::When(when) => {
+            let mut when_decls = vec![];
+            for decl in &when.decls {
+                expand_decl(decl, vars, params, &mut when_decls)?;
+            }
+            let mut else_decls = vec![];
+            for decl in &when.else_decls {
+                expand_decl(decl, vars, params, &mut else_decls)?;
+            }
+            decls.push(ast::Decl::When(when.derive(ast::When {
+                cond: subst_expr(&when.cond, vars),
+                decls: when_decls,
+                else_decls,
+            })));
+            return Ok(());
+        },
+        decl => {
+            decls.push(subst_decl(decl, vars));
+            return Ok(());
+        },
+    };
+
+    let (Some(start), Some(end)) = (for_bound(&for_decl.start, vars, params)?, for_bound(&for_decl.end, vars, params)?) else {
+        decls.push(subst_decl(decl, vars));
+        return Ok(());
+    };
+
+    for i in start..end {
+        let mut vars = vars.to_vec();
+        vars.push((for_decl.var.clone(), i));
+        for decl in &for_decl.decls {
+            expand_decl(decl, &vars, params, decls)?;
+        }
+    }
+    Ok(())
+}
+
+/// The value of a bound of a `for`, or `None` when it is a parameter of a moddef which hasn't been specialized.
+fn for_bound(nat: &ast::Nat, vars: &[(Ident, u64)], params: &[(Ident, Option<u64>)]) -> VirdantResult<Option<u64>> {
+    match nat {
+        ast::Nat::Lit(n) => Ok(Some(*n)),
+        ast::Nat::Param(name) => {
+            if let Some(i) = lookup_var(vars, name) {
+                Ok(Some(i))
+            } else if let Some((_param, value)) = params.iter().find(|(param, _value)| param == name) {
+                Ok(*value)
+            } else {
+                Err(virdant_error!("The bounds of a for must be numbers or parameters of the mod, not {name}"))
+            }
+        },
+        ast::Nat::Const(name) => Err(virdant_error!("The bounds of a for must be numbers or parameters of the mod, not {name}")),
+    }
+}
+
+fn lookup_var(vars: &[(Ident, u64)], name: &Ident) -> Option<u64> {
+    vars.iter().rev().find(|(var, _i)| var == name).map(|(_var, i)| *i)
+}
+
+fn subst_index(index: &ast::NameIndex, vars: &[(Ident, u64)]) -> ast::NameIndex {
+    match index {
+        ast::NameIndex::Var(var) => match lookup_var(vars, var) {
+            Some(i) => ast::NameIndex::Lit(i),
+            None => index.clone(),
+        },
+        ast::NameIndex::Lit(_i) => index.clone(),
+    }
+}
+
+fn subst_nat(nat: &ast::Nat, vars: &[(Ident, u64)]) -> ast::Nat {
+    match nat {
+        ast::Nat::Param(name) => match lookup_var(vars, name) {
+            Some(i) => ast::Nat::Lit(i),
+            None => nat.clone(),
+        },
+        _ => nat.clone(),
+    }
+}
+
+fn subst_type(typ: &Ast<ast::Type>, vars: &[(Ident, u64)]) -> Ast<ast::Type> {
+    match typ.as_ref() {
+        ast::Type::Clock => typ.clone(),
+        ast::Type::Word(n) => typ.derive(ast::Type::Word(subst_nat(n, vars))),
+        ast::Type::Vec(elem_typ, n) => typ.derive(ast::Type::Vec(subst_type(elem_typ, vars), subst_nat(n, vars))),
+        ast::Type::TypeRef(name, args) => {
+            let args = args.iter().map(|arg| match arg {
+                ast::TypeArg::Type(typ) => ast::TypeArg::Type(subst_type(typ, vars)),
+                ast::TypeArg::Nat(n) => ast::TypeArg::Nat(subst_nat(n, vars)),
+            }).collect();
+            typ.derive(ast::Type::TypeRef(name.clone(), args))
+        },
+    }
+}
+
+fn subst_decl(decl: &ast::Decl, vars: &[(Ident, u64)]) -> ast::Decl {
+    if vars.is_empty() {
+        return decl.clone();
+    }
+
+    let expr = |e: &Ast<ast::Expr>| subst_expr(e, vars);
+    match decl {
+        ast::Decl::Component(component) => ast::Decl::Component(component.derive(ast::Component {
+            name: component.name.clone(),
+            index: component.index.as_ref().map(|index| subst_index(index, vars)),
+            kind: component.kind,
+            typ: subst_type(&component.typ, vars),
+            clock: component.clock.as_ref().map(expr),
+            init: component.init.clone(),
+        })),
+        ast::Decl::Submodule(submodule) => ast::Decl::Submodule(submodule.derive(ast::Submodule {
+            name: submodule.name.clone(),
+            index: submodule.index.as_ref().map(|index| subst_index(index, vars)),
+            moddef: submodule.moddef.clone(),
+            args: submodule.args.iter().map(|arg| subst_nat(arg, vars)).collect(),
+        })),
+        ast::Decl::Port(port) => ast::Decl::Port(port.derive(ast::Port {
+            name: port.name.clone(),
+            index: port.index.as_ref().map(|index| subst_index(index, vars)),
+            role: port.role,
+            portdef: port.portdef.clone(),
+        })),
+        ast::Decl::Wire(wire) => {
+            let ast::Wire(target, wire_type, e) = wire.as_ref();
+            ast::Decl::Wire(wire.derive(ast::Wire(target.clone(), *wire_type, expr(e))))
+        },
+        ast::Decl::IndexedWire(wire) => {
+            let ast::IndexedWire(ast::IndexedName(name, index), rest, wire_type, e) = wire.as_ref();
+            let target = ast::IndexedName(name.clone(), subst_index(index, vars));
+            ast::Decl::IndexedWire(wire.derive(ast::IndexedWire(target, rest.clone(), *wire_type, expr(e))))
+        },
+        ast::Decl::MemWrite(mem_write) => ast::Decl::MemWrite(mem_write.derive(ast::MemWrite {
+            mem: mem_write.mem.clone(),
+            addr: expr(&mem_write.addr),
+            data: expr(&mem_write.data),
+            enable: mem_write.enable.as_ref().map(expr),
+        })),
+        ast::Decl::Connect(_connect) => decl.clone(),
+        ast::Decl::For(for_decl) => ast::Decl::For(for_decl.derive(ast::For {
+            var: for_decl.var.clone(),
+            start: subst_nat(&for_decl.start, vars),
+            end: subst_nat(&for_decl.end, vars),
+            decls: for_decl.decls.iter().map(|decl| {
+                let vars: Vec<_> = vars.iter().filter(|(var, _i)| var != &for_decl.var).cloned().collect();
+                subst_decl(decl, &vars)
+            }).collect(),
+        })),
+        ast::Decl::When(when) => ast::Decl::When(when.derive(ast::When {
+            cond: expr(&when.cond),
+            decls: when.decls.iter().map(|decl| subst_decl(decl, vars)).collect(),
+            else_decls: when.else_decls.iter().map(|decl| subst_decl(decl, vars)).collect(),
+        })),
+        ast::Decl::Property(property) => ast::Decl::Property(property.derive(ast::Property {
+            kind: property.kind,
+            cond: expr(&property.cond),
+            clock: expr(&property.clock),
+            enable: property.enable.as_ref().map(expr),
+        })),
+    }
+}
+
+fn subst_expr(expr: &Ast<ast::Expr>, vars: &[(Ident, u64)]) -> Ast<ast::Expr> {
+    let shadow = |vars: &[(Ident, u64)], names: &[Ident]| -> Vec<(Ident, u64)> {
+        vars.iter().filter(|(var, _i)| !names.contains(var)).cloned().collect()
+    };
+
+    match expr.as_ref() {
+        ast::Expr::Reference(path) => match path.as_ident().and_then(|name| lookup_var(vars, &name)) {
+            Some(i) => expr.derive(ast::Expr::Word(ast::WordLit { value: i, width: None, spelling: i.to_string() })),
+            None => expr.clone(),
+        },
+        ast::Expr::IndexedReference(ast::IndexedName(name, index), rest) => {
+            expr.derive(ast::Expr::IndexedReference(ast::IndexedName(name.clone(), subst_index(index, vars)), rest.clone()))
+        },
+        ast::Expr::As(e, typ) => expr.derive(ast::Expr::As(subst_expr(e, vars), subst_type(typ, vars))),
+        ast::Expr::Let(x, typ, e, body) => expr.derive(ast::Expr::Let(
+            x.clone(),
+            typ.as_ref().map(|typ| subst_type(typ, vars)),
+            subst_expr(e, vars),
+            subst_expr(body, &shadow(vars, std::slice::from_ref(x))),
+        )),
+        ast::Expr::Match(subject, typ, arms) => expr.derive(ast::Expr::Match(
+            subst_expr(subject, vars),
+            typ.as_ref().map(|typ| subst_type(typ, vars)),
+            arms.iter().map(|ast::MatchArm(pat, e)| {
+                ast::MatchArm(pat.clone(), subst_expr(e, &shadow(vars, &pat_binds(pat))))
+            }).collect(),
+        )),
+        _ => expr.map_subexprs(&mut |e| subst_expr(e, vars)),
+    }
+}
+
+fn pat_binds(pat: &ast::Pat) -> Vec<Ident> {
+    match pat {
+        ast::Pat::At(_ctor, pats) => pats.iter().flat_map(pat_binds).collect(),
+        ast::Pat::Bind(x) => vec![x.clone()],
+        ast::Pat::Otherwise => vec![],
+    }
+}
+
+/// Once the loops are expanded, gives each copy of a replicated declaration its name, as in `x[3]`.
+/// Outside of a `for`, a reference to a copy like `x[3]` parses as an index, which is sorted out here, too.
+/// Loops which aren't expanded, because their bounds are parameters, are left alone.
+fn name_decl(db: &dyn AstQ, decl: &ast::Decl, names: &HashSet<Ident>) -> VirdantResult<ast::Decl> {
+    let expr = |e: &Ast<ast::Expr>| name_expr(e, names);
+    let name = |name: &Ident, index: &Option<ast::NameIndex>, span: SpanIdx| match index {
+        None => Ok(name.clone()),
+        Some(index) => indexed_name(db, &ast::IndexedName(name.clone(), index.clone()), span),
+    };
+    Ok(match decl {
+        ast::Decl::Component(component) => ast::Decl::Component(component.derive(ast::Component {
+            name: name(&component.name, &component.index, component.span())?,
+            index: None,
+            clock: component.clock.as_ref().map(expr),
+            ..component.as_ref().clone()
+        })),
+        ast::Decl::Submodule(submodule) => ast::Decl::Submodule(submodule.derive(ast::Submodule {
+            name: name(&submodule.name, &submodule.index, submodule.span())?,
+            index: None,
+            ..submodule.as_ref().clone()
+        })),
+        ast::Decl::Port(port) => ast::Decl::Port(port.derive(ast::Port {
+            name: name(&port.name, &port.index, port.span())?,
+            index: None,
+            ..port.as_ref().clone()
+        })),
+        ast::Decl::Wire(wire) => {
+            let ast::Wire(target, wire_type, e) = wire.as_ref();
+            ast::Decl::Wire(wire.derive(ast::Wire(target.clone(), *wire_type, expr(e))))
+        },
+        ast::Decl::IndexedWire(wire) => {
+            let ast::IndexedWire(target, rest, wire_type, e) = wire.as_ref();
+            let target = indexed_name(db, target, wire.span())?.as_path();
+            let target = match rest {
+                Some(rest) => target.join(rest),
+                None => target,
+            };
+            ast::Decl::Wire(wire.derive(ast::Wire(target, *wire_type, expr(e))))
+        },
+        ast::Decl::MemWrite(mem_write) => ast::Decl::MemWrite(mem_write.derive(ast::MemWrite {
+            mem: mem_write.mem.clone(),
+            addr: expr(&mem_write.addr),
+            data: expr(&mem_write.data),
+            enable: mem_write.enable.as_ref().map(expr),
+        })),
+        ast::Decl::When(when) => {
+            let mut decls = vec![];
+            for decl in &when.decls {
+                decls.push(name_decl(db, decl, names)?);
+            }
+            let mut else_decls = vec![];
+            for decl in &when.else_decls {
+                else_decls.push(name_decl(db, decl, names)?);
+            }
+            ast::Decl::When(when.derive(ast::When {
+                cond: expr(&when.cond),
+                decls,
+                else_decls,
+            }))
+        },
+        ast::Decl::Property(property) => ast::Decl::Property(property.derive(ast::Property {
+            kind: property.kind,
+            cond: expr(&property.cond),
+            clock: expr(&property.clock),
+            enable: property.enable.as_ref().map(expr),
+        })),
+        ast::Decl::Connect(_) | ast::Decl::For(_) => decl.clone(),
+    })
+}
+
+/// The name of a copy, whose index must be known by now.
+fn indexed_name(db: &dyn AstQ, indexed_name: &ast::IndexedName, span: SpanIdx) -> VirdantResult<Ident> {
+    match indexed_name {
+        ast::IndexedName(name, ast::NameIndex::Lit(i)) => Ok(ast::replicated_name(name, *i)),
+        ast::IndexedName(name, ast::NameIndex::Var(var)) => {
+            let span = db.span(span);
+            Err(virdant_error_at!("{name}[{var}] is indexed by {var}, which is not the variable of an enclosing for", span))
+        },
+    }
+}
+
+/// A reference to a copy, as in `x[3]` or `adder[3].out`, becomes a reference to its name.
+/// When `x` isn't replicated, `x[3]` is an index into the word `x` instead.
+fn name_expr(expr: &Ast<ast::Expr>, names: &HashSet<Ident>) -> Ast<ast::Expr> {
+    match expr.as_ref() {
+        ast::Expr::Idx(subject, i) => {
+            if let ast::Expr::Reference(path) = subject.as_ref() {
+                if let Some(name) = path.as_ident().map(|name| ast::replicated_name(&name, *i)) {
+                    if names.contains(&name) {
+                        return expr.derive(ast::Expr::Reference(name.as_path()));
+                    }
+                }
+            }
+            expr.map_subexprs(&mut |e| name_expr(e, names))
+        },
+        ast::Expr::IndexedReference(ast::IndexedName(name, ast::NameIndex::Lit(i)), rest) => {
+            let replicated_name = ast::replicated_name(name, *i);
+            match rest {
+                Some(rest) => expr.derive(ast::Expr::Reference(replicated_name.as_path().join(rest))),
+                None if names.contains(&replicated_name) => expr.derive(ast::Expr::Reference(replicated_name.as_path())),
+                None => {
+                    let subject = expr.derive(ast::Expr::Reference(name.as_path()));
+                    expr.derive(ast::Expr::Idx(subject, *i))
+                },
+            }
+        },
+        _ => expr.map_subexprs(&mut |e| name_expr(e, names)),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Conditional wires
+////////////////////////////////////////////////////////////////////////////////
+
+/// Replaces each `when` with one wire for each target driven inside of it,
+/// whose expression is an `if` on the conditions.
+/// A latched target which isn't driven in one of the branches keeps its value,
+/// while a continuous one must be driven in every branch.
+/// A mem write or a property inside of a `when` only happens when its condition holds.
+/// A target driven inside of a `when` may not be driven anywhere else.
+fn desugar_whens(db: &dyn AstQ, decls: &[ast::Decl]) -> VirdantResult<Vec<ast::Decl>> {
+    let mut errors = ErrorReport::new();
+    let mut results = vec![];
+    // Each target, along with the index of the `when` it is driven in, if any.
+    let mut drivers: Vec<(Path, Option<usize>)> = vec![];
+    for (i, decl) in decls.iter().enumerate() {
+        let (decls, when) = match decl {
+            ast::Decl::When(when) => match when_decls(db, when) {
+                Ok(decls) => (decls, Some(i)),
+                Err(err) => {
+                    errors.add(err);
+                    continue;
+                },
+            },
+            decl => (vec![decl.clone()], None),
+        };
+
+        for decl in &decls {
+            if let ast::Decl::Wire(wire) = decl {
+                let target = &wire.0;
+                match drivers.iter().find(|(other, _when)| other == target) {
+                    Some((_target, Some(_other_when))) if when.is_some() => {
+                        let span = db.span(wire.span());
+                        errors.add(virdant_error_at!("{target} is driven inside of more than one when", span));
+                    },
+                    Some((_target, other_when)) if when.is_some() || other_when.is_some() => {
+                        let span = db.span(wire.span());
+                        errors.add(virdant_error_at!("{target} is driven both inside and outside of a when", span));
+                    },
+                    Some(_) => (),
+                    None => drivers.push((target.clone(), when)),
+                }
+            }
+        }
+        results.extend(decls);
+    }
+    errors.check()?;
+    Ok(results)
+}
+
+fn when_decls(db: &dyn AstQ, when: &Ast<ast::When>) -> VirdantResult<Vec<ast::Decl>> {
+    let then_decls = branch_decls(db, when, &when.decls)?;
+    let else_decls = branch_decls(db, when, &when.else_decls)?;
+
+    let mut targets: Vec<(Path, ast::WireType, Ast<ast::Wire>)> = vec![];
+    let mut results = vec![];
+    for decl in then_decls.iter().chain(&else_decls) {
+        match decl {
+            ast::Decl::Wire(wire) => {
+                let ast::Wire(target, wire_type, _e) = wire.as_ref();
+                match targets.iter().find(|(other, _wire_type, _wire)| other == target) {
+                    Some((_target, other_wire_type, _wire)) if other_wire_type != wire_type => {
+                        let span = db.span(wire.span());
+                        return Err(virdant_error_at!("{target} is driven with both := and <= inside of a when", span));
+                    },
+                    Some(_) => (),
+                    None => targets.push((target.clone(), *wire_type, wire.clone())),
+                }
+            },
+            ast::Decl::MemWrite(_) | ast::Decl::Property(_) => (),
+            _ => unreachable!(),
+        }
+    }
+
+    for (target, wire_type, wire) in targets {
+        let then_expr = branch_driver(db, when, &then_decls, &target, wire_type, &wire)?;
+        let else_expr = branch_driver(db, when, &else_decls, &target, wire_type, &wire)?;
+        let expr = when.cond.derive(ast::Expr::If(when.cond.clone(), then_expr, else_expr));
+        results.push(ast::Decl::Wire(wire.derive(ast::Wire(target, wire_type, expr))));
+    }
+
+    let not_cond = when.cond.derive(ast::Expr::MethodCall(when.cond.clone(), "not".into(), None, vec![]));
+    for (decls, cond) in [(&then_decls, &when.cond), (&else_decls, &not_cond)] {
+        let enable_for = |enable: &Option<Ast<ast::Expr>>| match enable {
+            Some(enable) => cond.derive(ast::Expr::MethodCall(cond.clone(), "and".into(), None, vec![enable.clone()])),
+            None => cond.clone(),
+        };
+        for decl in decls {
+            match decl {
+                ast::Decl::MemWrite(mem_write) => results.push(ast::Decl::MemWrite(mem_write.derive(ast::MemWrite {
+                    enable: Some(enable_for(&mem_write.enable)),
+                    ..mem_write.as_ref().clone()
+                }))),
+                ast::Decl::Property(property) => results.push(ast::Decl::Property(property.derive(ast::Property {
+                    enable: Some(enable_for(&property.enable)),
+                    ..property.as_ref().clone()
+                }))),
+                _ => (),
+            }
+        }
+    }
+
+    Ok(results)
+}
+
+/// The wires, mem writes and properties in one branch of a `when`, with any nested `when` desugared.
+fn branch_decls(db: &dyn AstQ, when: &Ast<ast::When>, decls: &[ast::Decl]) -> VirdantResult<Vec<ast::Decl>> {
+    let mut results: Vec<ast::Decl> = vec![];
+    for decl in decls {
+        let branch = match decl {
+            ast::Decl::When(inner_when) => when_decls(db, inner_when)?,
+            ast::Decl::Wire(_) | ast::Decl::MemWrite(_) | ast::Decl::Property(_) => vec![decl.clone()],
+            _ => {
+                let span = db.span(when.span());
+                return Err(virdant_error_at!("Only wires, mem writes, asserts and covers may appear inside of a when", span));
+            },
+        };
+        for decl in branch {
+            if let ast::Decl::Wire(wire) = &decl {
+                let target = &wire.0;
+                if results.iter().any(|other| matches!(other, ast::Decl::Wire(other) if &other.0 == target)) {
+                    let span = db.span(wire.span());
+                    return Err(virdant_error_at!("{target} is driven more than once inside of a when", span));
+                }
+            }
+            results.push(decl);
+        }
+    }
+    Ok(results)
+}
+
+fn branch_driver(
+    db: &dyn AstQ,
+    when: &Ast<ast::When>,
+    decls: &[ast::Decl],
+    target: &Path,
+    wire_type: ast::WireType,
+    wire: &Ast<ast::Wire>,
+) -> VirdantResult<Ast<ast::Expr>> {
+    for decl in decls {
+        if let ast::Decl::Wire(wire) = decl {
+            if &wire.0 == target {
+                return Ok(wire.2.clone());
+            }
+        }
+    }
+
+    match wire_type {
+        ast::WireType::Latched => Ok(wire.derive(ast::Expr::Reference(target.clone()))),
+        ast::WireType::Continuous => {
+            let span = db.span(when.span());
+            Err(virdant_error_at!("{target} is not driven in every branch of a when", span))
+        },
+    }
+}
@@ -1,10 +1,17 @@
 //use crate::topological_sort::topological_sort;
 use crate::{ast, common::*, context::Context, virdant_error, virdant_error_at};
 use super::*;
+use super::typecheck::{TypedExpr, TypedMatchArm};
 
 #[salsa::query_group(CheckQStorage)]
 pub trait CheckQ: typecheck::TypecheckQ {
     fn check(&self) -> VirdantResult<()>;
+
+    /// Parametric moddefs which are never instantiated.
+    /// Their bodies are only checked per specialization, so these haven't been checked at all.
+    /// There is no symbolic check of a body against its parameters,
+    /// so any error in one of these, even one not involving a parameter, goes unreported.
+    fn unchecked_moddefs(&self) -> VirdantResult<Vec<ModDefId>>;
 }
 
 fn check(db: &dyn CheckQ) -> VirdantResult<()> {
@@ -18,22 +25,56 @@ fn check(db: &dyn CheckQ) -> VirdantResult<()> {
         check_no_dup_imports(db, package_id.clone())?;
 
         for item_id in db.package_items(package_id.clone())? {
+            if let ItemId::ModDef(moddef_id) = item_id.clone() {
+                check_moddef_expands(db, moddef_id)?;
+            }
             check_item_no_dup_names(db, item_id.clone())?;
             check_all_dep_items_exist(db, item_id.clone())?;
+            if let ItemId::FnDef(fndef_id) = item_id.clone() {
+                check_fndef_typechecks(db, fndef_id.clone())?;
+                check_fndef_not_recursive(db, fndef_id, &mut vec![])?;
+            }
+            if let ItemId::ConstDef(constdef_id) = item_id.clone() {
+                check_constdef(db, constdef_id)?;
+            }
+            if let ItemId::EnumDef(enumdef_id) = item_id {
+                db.enumdef_values(enumdef_id)?;
+            }
         }
 
-        for moddef_id in db.package_moddefs(package_id)? {
-            check_all_targets_uniquely_driven(db, moddef_id.clone())?;
-            check_wires_typecheck(db, moddef_id.clone())?;
-            check_wires_correct_wiretype(db, moddef_id.clone())?;
-            check_clocks_typecheck(db, moddef_id.clone())?;
-            check_no_reads_from_sinks(db, moddef_id.clone())?;
-        }
+    }
+
+    // Parametric moddefs are checked once for each specialization.
+    for moddef_id in db.elaborated_moddefs()? {
+        check_moddef_expands(db, moddef_id.clone())?;
+        check_all_targets_uniquely_driven(db, moddef_id.clone())?;
+        check_wires_typecheck(db, moddef_id.clone())?;
+        check_wires_correct_wiretype(db, moddef_id.clone())?;
+        check_clocks_typecheck(db, moddef_id.clone())?;
+        check_no_reads_from_sinks(db, moddef_id.clone())?;
+        check_mems(db, moddef_id.clone())?;
+        check_properties(db, moddef_id.clone())?;
+        check_port_channels_driven_from_here(db, moddef_id.clone())?;
     }
 
     Ok(())
 }
 
+fn unchecked_moddefs(db: &dyn CheckQ) -> VirdantResult<Vec<ModDefId>> {
+    let elaborated_moddefs = db.elaborated_moddefs()?;
+    let mut unchecked = vec![];
+    for package_id in db.packages() {
+        for moddef_id in db.package_moddefs(package_id)? {
+            let is_generic = !db.moddef_ast(moddef_id.clone())?.params.is_empty();
+            let is_instantiated = elaborated_moddefs.iter().any(|elaborated| elaborated.specialize(vec![]) == moddef_id);
+            if is_generic && !is_instantiated {
+                unchecked.push(moddef_id);
+            }
+        }
+    }
+    Ok(unchecked)
+}
+
 fn check_no_import_cycles(_db: &dyn CheckQ) -> VirdantResult<()> {
     eprintln!("SKIP check_no_import_cycles");
     Ok(())
@@ -63,6 +104,13 @@ fn check_no_dup_imports(db: &dyn CheckQ, package_id: PackageId) -> VirdantResult
     Ok(())
 }
 
+/// The `for`s and `when`s of a moddef are expanded along with its AST.
+/// Their errors are reported here, before any check which needs the expanded moddef.
+fn check_moddef_expands(db: &dyn CheckQ, moddef_id: ModDefId) -> VirdantResult<()> {
+    db.moddef_ast(moddef_id)?;
+    Ok(())
+}
+
 fn check_item_no_dup_names(db: &dyn CheckQ, item_id: ItemId) -> VirdantResult<()> {
     db.item_elements(item_id)
         .map_err(|err| virdant_error!("Failed Check: Item No Dup Names").because(err))?;
@@ -82,22 +130,192 @@ fn check_all_targets_uniquely_driven(_db: &dyn CheckQ, _moddef_id: ModDefId) ->
 
 fn check_wires_typecheck(db: &dyn CheckQ, moddef_id: ModDefId) -> VirdantResult<()> {
     let mut errors = ErrorReport::new();
+
+    for wire in db.moddef_wires(moddef_id.clone())? {
+        let ast::Wire(target, _wire_type, expr) = wire.as_ref();
+        let element_id = db.resolve_component_by_path(moddef_id.clone(), target.clone())?;
+        let target_typ = db.component_typ(element_id)?;
+        let typed_expr = db.typecheck_expr(moddef_id.as_item(), expr.clone(), target_typ, Context::empty());
+        if let Err(e) = typed_expr {
+            // errors.add(e);
+            let span = db.span(wire.span());
+            errors.add(virdant_error_at!("Typecheck failed", span).because(e));
+        }
+    }
+
+    errors.check()?;
+    Ok(())
+}
+
+fn check_fndef_typechecks(db: &dyn CheckQ, fndef_id: FnDefId) -> VirdantResult<()> {
+    if let Err(e) = db.fndef_body(fndef_id.clone()) {
+        let span = db.span(db.fndef_ast(fndef_id.clone())?.span());
+        return Err(virdant_error_at!("Typecheck failed for fn {fndef_id}", span).because(e));
+    }
+    Ok(())
+}
+
+fn check_constdef(db: &dyn CheckQ, constdef_id: ConstDefId) -> VirdantResult<()> {
+    let constdef_ast = db.constdef_ast(constdef_id.clone())?;
+    let span = db.span(constdef_ast.span());
+    let typ = db.constdef_typ(constdef_id.clone())?;
+    db.typecheck_expr(constdef_id.as_item(), constdef_ast.value.clone(), typ, Context::empty())
+        .map_err(|e| virdant_error_at!("Typecheck failed for const {constdef_id}", span.clone()).because(e))?;
+    db.const_value(constdef_id.clone())
+        .map_err(|e| virdant_error_at!("Bad const {constdef_id}", span).because(e))?;
+    Ok(())
+}
+
+/// Calls are inlined, so a fn may not call itself, directly or indirectly.
+fn check_fndef_not_recursive(db: &dyn CheckQ, fndef_id: FnDefId, stack: &mut Vec<FnDefId>) -> VirdantResult<()> {
+    if stack.contains(&fndef_id) {
+        return Err(virdant_error!("Recursive fn {fndef_id}"));
+    }
+    stack.push(fndef_id.clone());
+    for callee in fn_calls(db.fndef_body(fndef_id)?.as_ref()) {
+        check_fndef_not_recursive(db, callee, stack)?;
+    }
+    stack.pop();
+    Ok(())
+}
+
+fn fn_calls(expr: &TypedExpr) -> Vec<FnDefId> {
+    let subexprs: Vec<Arc<TypedExpr>> = match expr {
+        TypedExpr::Reference(_, _) | TypedExpr::Word(_, _) => vec![],
+        TypedExpr::Vec(_, es) | TypedExpr::Cat(_, es) | TypedExpr::Ctor(_, _, es) => es.clone(),
+        TypedExpr::Struct(_, _, fields) => fields.iter().map(|(_field, e)| e.clone()).collect(),
+        TypedExpr::MethodCall(_, subject, _, args) => {
+            let mut es = vec![subject.clone()];
+            es.extend(args.iter().cloned());
+            es
+        },
+        TypedExpr::FnCall(_, fndef_id, args) => {
+            let mut calls = vec![fndef_id.clone()];
+            for arg in args {
+                calls.extend(fn_calls(arg));
+            }
+            return calls;
+        },
+        TypedExpr::MemRead(_, _, e, _) | TypedExpr::As(_, e, _) | TypedExpr::FromBits(_, e) | TypedExpr::Idx(_, e, _) | TypedExpr::IdxRange(_, e, _, _) => vec![e.clone()],
+        TypedExpr::With(_, subject, updates) => {
+            let mut es = vec![subject.clone()];
+            es.extend(updates.iter().map(|(_update, e)| e.clone()));
+            es
+        },
+        TypedExpr::If(_, c, a, b) => vec![c.clone(), a.clone(), b.clone()],
+        TypedExpr::Let(_, _, _, e, b) => vec![e.clone(), b.clone()],
+        TypedExpr::Match(_, subject, _, arms) => {
+            let mut es = vec![subject.clone()];
+            es.extend(arms.iter().map(|TypedMatchArm(_pat, e)| e.clone()));
+            es
+        },
+    };
+    subexprs.iter().flat_map(|e| fn_calls(e)).collect()
+}
+
+/// The condition of an `assert` or a `cover` is a `Word[1]`, and it is checked on a `Clock`.
+fn check_properties(db: &dyn CheckQ, moddef_id: ModDefId) -> VirdantResult<()> {
+    let mut errors = ErrorReport::new();
+    let moddef_ast = db.moddef_ast(moddef_id.clone())?;
+    let item_id = moddef_id.as_item();
+
+    for decl in &moddef_ast.decls {
+        if let ast::Decl::Property(property) = decl {
+            let span = db.span(property.span());
+            let ast::Property { kind, cond, clock, enable } = property.as_ref();
+            let what = match kind {
+                ast::PropertyKind::Assert => "assert",
+                ast::PropertyKind::Cover => "cover",
+            };
+
+            let mut exprs = vec![(cond, Type::Word(1)), (clock, Type::Clock)];
+            if let Some(enable) = enable {
+                exprs.push((enable, Type::Word(1)));
+            }
+            for (expr, typ) in exprs {
+                if let Err(e) = db.typecheck_expr(item_id.clone(), expr.clone(), typ, Context::empty()) {
+                    errors.add(virdant_error_at!("Typecheck failed for {what}", span.clone()).because(e));
+                }
+            }
+        }
+    }
+
+    errors.check()?;
+    Ok(())
+}
+
+/// Mems must be a Vec of at least two elements, and are only written through their write ports.
+fn check_mems(db: &dyn CheckQ, moddef_id: ModDefId) -> VirdantResult<()> {
+    let mut errors = ErrorReport::new();
     let moddef_ast = db.moddef_ast(moddef_id.clone())?;
+    let item_id = moddef_id.as_item();
 
+    let mut mems: HashMap<Ident, (Type, u64)> = HashMap::new();
     for decl in &moddef_ast.decls {
-        if let ast::Decl::Wire(wire) = decl {
-            let ast::Wire(target, _wire_type, expr) = wire.as_ref();
-            let element_id = db.resolve_component_by_path(moddef_id.clone(), target.clone())?;
-            let target_typ = db.component_typ(element_id)?;
-            let typed_expr = db.typecheck_expr(moddef_id.clone(), expr.clone(), target_typ, Context::empty());
-            if let Err(e) = typed_expr {
-                // errors.add(e);
-                let span = db.span(wire.span());
-                errors.add(virdant_error_at!("Typecheck failed", span).because(e));
+        if let ast::Decl::Component(component) = decl {
+            if component.kind == ast::ComponentKind::Mem {
+                let span = db.span(component.span());
+                let name = &component.name;
+                match db.resolve_moddef_typ(component.typ.clone(), moddef_id.clone())? {
+                    Type::Vec(elem_typ, depth) if depth >= 2 => {
+                        mems.insert(name.clone(), (elem_typ.as_ref().clone(), depth));
+                    },
+                    typ => errors.add(virdant_error_at!("Memory {name} must be a Vec of at least 2 elements, not {typ}", span)),
+                }
             }
         }
     }
 
+    for decl in &moddef_ast.decls {
+        match decl {
+            ast::Decl::Wire(wire) => {
+                let ast::Wire(target, _wire_type, _expr) = wire.as_ref();
+                if let Some(name) = target.as_ident() {
+                    if mems.contains_key(&name) {
+                        let span = db.span(wire.span());
+                        errors.add(virdant_error_at!("Memory {name} is written with {name}[addr] <= data", span));
+                    }
+                }
+            },
+            ast::Decl::MemWrite(mem_write) => {
+                let span = db.span(mem_write.span());
+                let ast::MemWrite { mem, addr, data, enable } = mem_write.as_ref();
+                let Some((elem_typ, depth)) = mems.get(mem) else {
+                    errors.add(virdant_error_at!("{mem} is not a memory", span));
+                    continue;
+                };
+
+                let mut exprs = vec![(addr, Type::Word(clog2(*depth))), (data, elem_typ.clone())];
+                if let Some(enable) = enable {
+                    exprs.push((enable, Type::Word(1)));
+                }
+                for (expr, typ) in exprs {
+                    if let Err(e) = db.typecheck_expr(item_id.clone(), expr.clone(), typ, Context::empty()) {
+                        errors.add(virdant_error_at!("Typecheck failed for write to {mem}", span.clone()).because(e));
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+
+    errors.check()?;
+    Ok(())
+}
+
+/// A module only drives the `mosi` channels of its master ports and the `miso` channels of its slave ports,
+/// and the other way around for the ports of its submodules.
+fn check_port_channels_driven_from_here(db: &dyn CheckQ, moddef_id: ModDefId) -> VirdantResult<()> {
+    let mut errors = ErrorReport::new();
+
+    for wire in db.moddef_wires(moddef_id.clone())? {
+        let ast::Wire(target, _wire_type, _expr) = wire.as_ref();
+        if db.port_channel_driven(moddef_id.clone(), target.clone())? == Some(false) {
+            let span = db.span(wire.span());
+            errors.add(virdant_error_at!("Channel {target} is driven from the other side of its port", span));
+        }
+    }
+
     errors.check()?;
     Ok(())
 }
@@ -116,3 +334,107 @@ fn check_no_reads_from_sinks(_db: &dyn CheckQ, _moddef_id: ModDefId) -> VirdantR
     eprintln!("SKIP check_no_reads_from_sinks");
     Ok(())
 }
+
+#[test]
+fn check_fndefs() {
+    let check = |fns: &str| {
+        let mut db = Db::new();
+        db.set_source("top", &format!("
+            {fns}
+
+            mod Top {{
+                incoming a : Word[8];
+                outgoing out : Word[8];
+                out := f(a);
+            }}
+        "));
+        db.check()
+    };
+
+    assert!(check("fn f(x : Word[8]) -> Word[8] = x->inc();").is_ok());
+    let err = format!("{:?}", check("fn f(x : Word[8]) -> Word[8] = f(x);").unwrap_err());
+    assert!(err.contains("Recursive fn top::f"));
+    let err = format!("{:?}", check("fn f(x : Word[8]) -> Word[8] = a;").unwrap_err());
+    assert!(err.contains("Typecheck failed for fn top::f"));
+    assert!(err.contains("Unknown variable a"));
+}
+
+#[test]
+fn check_constdefs() {
+    let check = |consts: &str| {
+        let mut db = Db::new();
+        db.set_source("top", &format!("
+            {consts}
+
+            mod Top {{
+                incoming a : Word[WIDTH];
+                outgoing out : Word[WIDTH];
+                out := a;
+            }}
+        "));
+        db.check()
+    };
+
+    assert!(check("const WIDTH : Word[8] = 8;").is_ok());
+    let err = format!("{:?}", check("const WIDTH : Word[8] = WIDTH->inc();").unwrap_err());
+    assert!(err.contains("Bad const top::WIDTH"));
+    let err = format!("{:?}", check("const WIDTH : Word[8] = a;").unwrap_err());
+    assert!(err.contains("Typecheck failed for const top::WIDTH"));
+    assert!(err.contains("Unknown variable a"));
+}
+
+#[test]
+fn check_mem_decls() {
+    let check = |decls: &str| {
+        let mut db = Db::new();
+        db.set_source("top", &format!("
+            mod Top {{
+                incoming clock : Clock;
+                incoming addr : Word[4];
+                incoming data : Word[8];
+                outgoing out : Word[8];
+                {decls}
+            }}
+        "));
+        db.check()
+    };
+
+    assert!(check("mem m : Vec[Word[8], 16] on clock; m[addr] <= data; out := m->read(addr);").is_ok());
+    let err = |decls: &str| format!("{:?}", check(decls).unwrap_err());
+    assert!(err("mem m : Vec[Word[8], 16] on clock; out := m;").contains("Memory m can only be read with m->read(addr) or m->read_sync(addr)"));
+    assert!(err("mem m : Vec[Word[8], 16] on clock; m := data; out := data;").contains("Wrong types: data is Vec[Word[8], 16] vs Word[8]"));
+    let write_err = err("mem m : Vec[Word[8], 16] on clock; m[data] <= data; out := data;");
+    assert!(write_err.contains("Typecheck failed for write to m"));
+    assert!(write_err.contains("Wrong types: data is Word[4] vs Word[8]"));
+    assert!(err("mem m : Word[8] on clock; out := data;").contains("Memory m must be a Vec of at least 2 elements, not Word[8]"));
+}
+
+#[test]
+fn check_port_channels() {
+    let check = |top: &str| {
+        let mut db = Db::new();
+        db.set_source("top", &format!("
+            port Inc {{
+                mosi inp : Word[8];
+                miso out : Word[8];
+            }}
+
+            mod Top {{
+                slave port inc of Inc;
+                mod incrementer of Incrementer;
+                {top}
+            }}
+
+            mod Incrementer {{
+                slave port inc of Inc;
+                inc.out := inc.inp->inc();
+            }}
+        "));
+        db.check()
+    };
+
+    assert!(check("incrementer.inc.inp := inc.inp; inc.out := incrementer.inc.out;").is_ok());
+    let err = format!("{:?}", check("incrementer.inc.inp := 1; inc.inp := incrementer.inc.out;").unwrap_err());
+    assert!(err.contains("Channel inc.inp is driven from the other side of its port"));
+    assert!(err.contains("Some([10:43:top])"));
+}
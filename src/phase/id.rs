@@ -18,10 +18,16 @@ pub enum ItemId {
     UnionDef(UnionDefId),
     StructDef(StructDefId),
     PortDef(PortDefId),
+    FnDef(FnDefId),
+    ConstDef(ConstDefId),
+    EnumDef(EnumDefId),
 }
 
+/// A moddef, together with the values of its parameters.
+/// The args are empty for a moddef without parameters,
+/// and for a parametric moddef which has not been specialized.
 #[derive(Clone, PartialEq, Eq, Hash)]
-pub struct ModDefId(PackageId, Ident);
+pub struct ModDefId(PackageId, Ident, Vec<u64>);
 
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub struct UnionDefId(PackageId, Ident);
@@ -32,6 +38,15 @@ pub struct StructDefId(PackageId, Ident);
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub struct PortDefId(PackageId, Ident);
 
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct FnDefId(PackageId, Ident);
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct ConstDefId(PackageId, Ident);
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct EnumDefId(PackageId, Ident);
+
 
 ////////////////////////////////////////////////////////////////////////////////
 // Elements
@@ -136,7 +151,21 @@ impl std::fmt::Display for PackageId {
 
 impl std::fmt::Display for ItemId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}::{}", self.package(), self.name())
+        match self {
+            ItemId::ModDef(moddef) => write!(f, "{moddef}"),
+            _ => write!(f, "{}::{}", self.package(), self.name()),
+        }
+    }
+}
+
+impl std::fmt::Display for ModDefId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}::{}", self.package(), self.name())?;
+        if !self.2.is_empty() {
+            let args: Vec<String> = self.2.iter().map(|arg| arg.to_string()).collect();
+            write!(f, "[{}]", args.join(", "))?;
+        }
+        Ok(())
     }
 }
 
@@ -165,6 +194,9 @@ impl HasPackage for ItemId {
             ItemId::UnionDef(uniondef) => uniondef.0.clone(),
             ItemId::StructDef(structdef) => structdef.0.clone(),
             ItemId::PortDef(portdef) => portdef.0.clone(),
+            ItemId::FnDef(fndef) => fndef.0.clone(),
+            ItemId::ConstDef(constdef) => constdef.0.clone(),
+            ItemId::EnumDef(enumdef) => enumdef.0.clone(),
         }
     }
 }
@@ -179,11 +211,14 @@ debug_impl!(PackageId);
 debug_impl!(ItemId);
 debug_impl!(ElementId);
 debug_impl!(ComponentId);
+debug_impl!(ModDefId);
 
-item_id!(ModDef, ModDefId);
 item_id!(UnionDef, UnionDefId);
 item_id!(StructDef, StructDefId);
 item_id!(PortDef, PortDefId);
+item_id!(FnDef, FnDefId);
+item_id!(ConstDef, ConstDefId);
+item_id!(EnumDef, EnumDefId);
 
 impl Named for ItemId {
     fn name(&self) -> Ident {
@@ -192,6 +227,9 @@ impl Named for ItemId {
             ItemId::UnionDef(uniondef) => uniondef.name(),
             ItemId::StructDef(structdef) => structdef.name(),
             ItemId::PortDef(portdef) => portdef.name(),
+            ItemId::FnDef(fndef) => fndef.name(),
+            ItemId::ConstDef(constdef) => constdef.name(),
+            ItemId::EnumDef(enumdef) => enumdef.name(),
         }
     }
 }
@@ -208,6 +246,39 @@ impl Named for PackageId {
     }
 }
 
+impl AsItem for ModDefId {
+    fn as_item(&self) -> ItemId {
+        ItemId::ModDef(self.clone())
+    }
+}
+
+impl Named for ModDefId {
+    fn name(&self) -> Ident {
+        self.1.clone()
+    }
+}
+
+impl HasPackage for ModDefId {
+    fn package(&self) -> PackageId {
+        self.0.clone()
+    }
+}
+
+impl ModDefId {
+    pub(crate) fn from_ident(package_id: PackageId, name: Ident) -> Self {
+        ModDefId(package_id, name, vec![])
+    }
+
+    /// The values of the moddef's parameters.
+    pub fn args(&self) -> Vec<u64> {
+        self.2.clone()
+    }
+
+    pub(crate) fn specialize(&self, args: Vec<u64>) -> Self {
+        ModDefId(self.0.clone(), self.1.clone(), args)
+    }
+}
+
 impl PackageId {
     pub(crate) fn from_ident(package: Ident) -> Self {
         PackageId(package)
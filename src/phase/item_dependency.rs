@@ -15,6 +15,9 @@ fn item_dependencies(db: &dyn ItemDependencyQ, item: ItemId) -> VirdantResult<Ve
         ItemId::UnionDef(uniondef_id) => uniondef_item_dependencies(db, uniondef_id),
         ItemId::StructDef(structdef_id) => structdef_item_dependencies(db, structdef_id),
         ItemId::PortDef(portdef_id) => portdef_item_dependencies(db, portdef_id),
+        ItemId::FnDef(fndef_id) => fndef_item_dependencies(db, fndef_id),
+        ItemId::ConstDef(constdef_id) => constdef_item_dependencies(db, constdef_id),
+        ItemId::EnumDef(_enumdef_id) => Ok(vec![]),
     }
 }
 
@@ -23,8 +26,8 @@ fn moddef_item_dependencies(db: &dyn ItemDependencyQ, moddef: ModDefId) -> Virda
     let mut dependencies: HashSet<ItemId> = HashSet::new();
     let moddef_ast = db.moddef_ast(moddef.clone())?;
 
-    for decl in &moddef_ast.decls {
-        match decl {
+    for decl in moddef_ast.decls.iter().flat_map(|decl| decl.flatten()) {
+        match &decl {
             ast::Decl::Component(component) => {
                 match moddef_item_dependencies_component(db, moddef.clone(), component) {
                     Ok(deps) => dependencies.extend(deps),
@@ -37,7 +40,14 @@ fn moddef_item_dependencies(db: &dyn ItemDependencyQ, moddef: ModDefId) -> Virda
             },
             ast::Decl::Wire(wire) => {
                 let ast::Wire(_target, _wire_type, expr) = wire.as_ref();
-                match expr_item_dependencies(db, expr.clone()) {
+                match expr_item_dependencies(db, expr, moddef.package(), &moddef_ast.params) {
+                    Ok(deps) => dependencies.extend(deps),
+                    Err(e) => errors.add(e),
+                }
+            },
+            ast::Decl::IndexedWire(wire) => {
+                let ast::IndexedWire(_target, _rest, _wire_type, expr) = wire.as_ref();
+                match expr_item_dependencies(db, expr, moddef.package(), &moddef_ast.params) {
                     Ok(deps) => dependencies.extend(deps),
                     Err(e) => errors.add(e),
                 }
@@ -46,6 +56,27 @@ fn moddef_item_dependencies(db: &dyn ItemDependencyQ, moddef: ModDefId) -> Virda
                 let item = db.item(port.portdef.clone(), moddef.package())?;
                 dependencies.insert(item);
             },
+            ast::Decl::MemWrite(mem_write) => {
+                let ast::MemWrite { addr, data, enable, .. } = mem_write.as_ref();
+                for expr in [Some(addr), Some(data), enable.as_ref()].into_iter().flatten() {
+                    match expr_item_dependencies(db, expr, moddef.package(), &moddef_ast.params) {
+                        Ok(deps) => dependencies.extend(deps),
+                        Err(e) => errors.add(e),
+                    }
+                }
+            },
+            ast::Decl::Property(property) => {
+                let ast::Property { cond, clock, enable, .. } = property.as_ref();
+                for expr in [Some(cond), Some(clock), enable.as_ref()].into_iter().flatten() {
+                    match expr_item_dependencies(db, expr, moddef.package(), &moddef_ast.params) {
+                        Ok(deps) => dependencies.extend(deps),
+                        Err(e) => errors.add(e),
+                    }
+                }
+            },
+            ast::Decl::Connect(_connect) => (),
+            ast::Decl::For(_for_decl) => (),
+            ast::Decl::When(_when) => (),
         }
     }
 
@@ -58,22 +89,75 @@ fn moddef_item_dependencies_component(
     moddef: ModDefId,
     simplecomponent: &ast::Component,
 ) -> VirdantResult<Vec<ItemId>> {
-    let mut items = vec![];
-    if let ast::Type::TypeRef(name) = simplecomponent.typ.as_ref() {
-        let item = db.item(name.clone(), moddef.package())?;
-        items.push(item);
-    }
+    let params = db.moddef_ast(moddef.clone())?.params.clone();
+    let mut items = typ_item_dependencies(db, &simplecomponent.typ, moddef.package(), &params)?;
 
     if let Some(clock) = simplecomponent.clock.clone() {
-        let expr_depends = expr_item_dependencies(db, clock.into())?;
+        let expr_depends = expr_item_dependencies(db, &clock, moddef.package(), &params)?;
         items.extend(expr_depends);
     }
 
     Ok(items)
 }
 
-fn expr_item_dependencies(_db: &dyn ItemDependencyQ, _expr: Ast<ast::Expr>) -> VirdantResult<Vec<ItemId>> {
-    Ok(vec![])
+/// The items a type refers to, including those in its type arguments.
+/// Names bound by `params` are parameters rather than items.
+fn typ_item_dependencies(
+    db: &dyn ItemDependencyQ,
+    typ: &ast::Type,
+    package: PackageId,
+    params: &[Ident],
+) -> VirdantResult<Vec<ItemId>> {
+    let mut items = vec![];
+    if let ast::Type::Vec(elem_typ, _n) = typ {
+        items.extend(typ_item_dependencies(db, elem_typ, package.clone(), params)?);
+    }
+    if let ast::Type::TypeRef(name, args) = typ {
+        if name.namespace().is_some() || !params.contains(&name.name()) {
+            items.push(db.item(name.clone(), package.clone())?);
+        }
+        for arg in args {
+            if let ast::TypeArg::Type(arg_typ) = arg {
+                items.extend(typ_item_dependencies(db, arg_typ, package.clone(), params)?);
+            }
+        }
+    }
+    Ok(items)
+}
+
+/// The items an expression refers to: the fns it calls, the consts it reads, and the types it names.
+/// A bare name which doesn't resolve to a const is a local or a component rather than an item.
+fn expr_item_dependencies(
+    db: &dyn ItemDependencyQ,
+    expr: &Ast<ast::Expr>,
+    package: PackageId,
+    params: &[Ident],
+) -> VirdantResult<Vec<ItemId>> {
+    let mut items = vec![];
+    match expr.as_ref() {
+        ast::Expr::Reference(path) => {
+            if let Some(name) = path.as_ident() {
+                if let Ok(item @ ItemId::ConstDef(_)) = db.item(name.into(), package.clone()) {
+                    items.push(item);
+                }
+            }
+        },
+        ast::Expr::QualReference(name) => items.push(db.item(name.clone(), package.clone())?),
+        ast::Expr::Struct(Some(name), _) => items.push(db.item(name.clone(), package.clone())?),
+        ast::Expr::FnCall(name, _) => match name.namespace() {
+            Some(namespace) if name.name() == "from_bits".into() => items.push(db.item(namespace.into(), package.clone())?),
+            _ => items.push(db.item(name.clone(), package.clone())?),
+        },
+        ast::Expr::As(_, typ) | ast::Expr::Let(_, Some(typ), _, _) | ast::Expr::Match(_, Some(typ), _) => {
+            items.extend(typ_item_dependencies(db, typ, package.clone(), params)?);
+        },
+        _ => (),
+    }
+
+    for subexpr in expr.subexprs() {
+        items.extend(expr_item_dependencies(db, subexpr, package.clone(), params)?);
+    }
+    Ok(items)
 }
 
 fn uniondef_item_dependencies(_db: &dyn ItemDependencyQ, _uniondef_id: UnionDefId) -> VirdantResult<Vec<ItemId>> {
@@ -90,3 +174,28 @@ fn portdef_item_dependencies(_db: &dyn ItemDependencyQ, _portdef_id: PortDefId)
     eprintln!("TODO portdef_item_dependencies not implemented");
     Ok(vec![])
 }
+
+fn fndef_item_dependencies(db: &dyn ItemDependencyQ, fndef_id: FnDefId) -> VirdantResult<Vec<ItemId>> {
+    let fndef_ast = db.fndef_ast(fndef_id.clone())?;
+    let package = fndef_id.package();
+    let mut dependencies: HashSet<ItemId> = HashSet::new();
+
+    for (_arg, arg_typ) in &fndef_ast.args {
+        dependencies.extend(typ_item_dependencies(db, arg_typ, package.clone(), &[])?);
+    }
+    dependencies.extend(typ_item_dependencies(db, &fndef_ast.ret, package.clone(), &[])?);
+    dependencies.extend(expr_item_dependencies(db, &fndef_ast.body, package, &[])?);
+
+    Ok(dependencies.into_iter().collect())
+}
+
+fn constdef_item_dependencies(db: &dyn ItemDependencyQ, constdef_id: ConstDefId) -> VirdantResult<Vec<ItemId>> {
+    let constdef_ast = db.constdef_ast(constdef_id.clone())?;
+    let package = constdef_id.package();
+    let mut dependencies: HashSet<ItemId> = HashSet::new();
+
+    dependencies.extend(typ_item_dependencies(db, &constdef_ast.typ, package.clone(), &[])?);
+    dependencies.extend(expr_item_dependencies(db, &constdef_ast.value, package, &[])?);
+
+    Ok(dependencies.into_iter().collect())
+}
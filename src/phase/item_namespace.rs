@@ -20,6 +20,18 @@ fn item_elements(db: &dyn ItemNamespaceQ, item_id: ItemId) -> VirdantResult<Vec<
         ItemId::UnionDef(uniondef) => uniondef_elements(db, uniondef),
         ItemId::StructDef(structdef) => structdef_elements(db, structdef),
         ItemId::PortDef(portdef) => portdef_elements(db, portdef),
+        ItemId::FnDef(_fndef) => Ok(vec![]),
+        ItemId::ConstDef(_constdef) => Ok(vec![]),
+        ItemId::EnumDef(enumdef) => enumdef_elements(db, enumdef),
+    }
+}
+
+/// Copies whose index is still a `for` variable are only named in specializations.
+fn component_name(name: &Ident, index: &Option<ast::NameIndex>) -> Option<Ident> {
+    match index {
+        None => Some(name.clone()),
+        Some(ast::NameIndex::Lit(i)) => Some(ast::replicated_name(name, *i)),
+        Some(ast::NameIndex::Var(_var)) => None,
     }
 }
 
@@ -28,12 +40,13 @@ fn moddef_components(db: &dyn ItemNamespaceQ, moddef_id: ModDefId) -> VirdantRes
     let mut errors = ErrorReport::new();
 
     let moddef_ast = db.moddef_ast(moddef_id.clone())?;
-    for decl in &moddef_ast.decls {
-         let name = match decl {
-            ast::Decl::Component(component) => Some(&component.name),
-            ast::Decl::Submodule(submodule) => Some(&submodule.name),
-            ast::Decl::Port(port) => Some(&port.name),
-            ast::Decl::Wire(_) => None,
+    // The loops of a parametric moddef are only expanded in its specializations.
+    for decl in moddef_ast.decls.iter().flat_map(|decl| decl.flatten()) {
+         let name = match &decl {
+            ast::Decl::Component(component) => component_name(&component.name, &component.index),
+            ast::Decl::Submodule(submodule) => component_name(&submodule.name, &submodule.index),
+            ast::Decl::Port(port) => component_name(&port.name, &port.index),
+            ast::Decl::Wire(_) | ast::Decl::IndexedWire(_) | ast::Decl::MemWrite(_) | ast::Decl::Connect(_) | ast::Decl::For(_) | ast::Decl::When(_) | ast::Decl::Property(_) => None,
         };
 
         if let Some(component_name) = name {
@@ -58,6 +71,21 @@ fn uniondef_elements(db: &dyn ItemNamespaceQ, uniondef_id: UnionDefId) -> Virdan
     Ok(elements)
 }
 
+fn enumdef_elements(db: &dyn ItemNamespaceQ, enumdef_id: EnumDefId) -> VirdantResult<Vec<ElementId>> {
+    let mut elements = vec![];
+    let mut errors = ErrorReport::new();
+    let enumdef_ast = db.enumdef_ast(enumdef_id.clone())?;
+    for ast::EnumValue(name, _value) in &enumdef_ast.values {
+        let element = ElementId::from_ident(enumdef_id.clone().as_item(), name.clone());
+        if elements.contains(&element) {
+            errors.add(virdant_error!("Duplicate value in {enumdef_id}: {name}"));
+        }
+        elements.push(element);
+    }
+    errors.check()?;
+    Ok(elements)
+}
+
 fn structdef_elements(db: &dyn ItemNamespaceQ, structdef_id: StructDefId) -> VirdantResult<Vec<ElementId>> {
     let mut elements = vec![];
     let structdef_ast = db.structdef_ast(structdef_id.clone())?;
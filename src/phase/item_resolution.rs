@@ -2,7 +2,9 @@ use std::collections::HashSet;
 
 use crate::common::*;
 use crate::ast;
+use crate::ast::Ast;
 use crate::virdant_error;
+use crate::virdant_error_at;
 use super::*;
 
 #[salsa::query_group(ItemResolutionQStorage)]
@@ -17,8 +19,21 @@ pub trait ItemResolutionQ: imports::ImportsQ + item_namespace::ItemNamespaceQ {
 
     fn moddef(&self, moddef: QualIdent, package: PackageId) -> VirdantResult<ModDefId>;
     fn portdef(&self, portdef: QualIdent, package: PackageId) -> VirdantResult<PortDefId>;
+    fn fndef(&self, fndef: QualIdent, package: PackageId) -> VirdantResult<FnDefId>;
+    fn constdef(&self, constdef: QualIdent, package: PackageId) -> VirdantResult<ConstDefId>;
 
     fn resolve_package(&self, package: Ident) -> VirdantResult<PackageId>;
+
+    fn elaborated_moddefs(&self) -> VirdantResult<Vec<ModDefId>>;
+
+    #[salsa::cycle(const_cycle)]
+    fn const_value(&self, constdef: ConstDefId) -> VirdantResult<u64>;
+    #[salsa::cycle(const_cycle)]
+    fn const_width(&self, constdef: ConstDefId) -> VirdantResult<Width>;
+
+    fn moddef_param_values(&self, moddef: ModDefId) -> VirdantResult<Vec<(Ident, u64)>>;
+    fn resolve_nat(&self, nat: ast::Nat, moddef: ModDefId) -> VirdantResult<u64>;
+    fn submodule_moddef(&self, submodule: Ast<ast::Submodule>, moddef: ModDefId) -> VirdantResult<ModDefId>;
 }
 
 fn resolve_package(db: &dyn ItemResolutionQ, package_name: Ident) -> VirdantResult<PackageId> {
@@ -83,7 +98,30 @@ fn package_items(db: &dyn ItemResolutionQ, package_id: PackageId) -> VirdantResu
                     errors.add(virdant_error!("Duplicate item name in package {name}"))
                 }
             },
-
+            ast::Item::FnDef(fndef_ast) => {
+                let name = fndef_ast.name.clone();
+                let fndef = FnDefId::from_ident(package_id.clone(), name.clone());
+                items.push(ItemId::FnDef(fndef));
+                if !item_names.insert(name.clone()) {
+                    errors.add(virdant_error!("Duplicate item name in package {name}"))
+                }
+            },
+            ast::Item::ConstDef(constdef_ast) => {
+                let name = constdef_ast.name.clone();
+                let constdef = ConstDefId::from_ident(package_id.clone(), name.clone());
+                items.push(ItemId::ConstDef(constdef));
+                if !item_names.insert(name.clone()) {
+                    errors.add(virdant_error!("Duplicate item name in package {name}"))
+                }
+            },
+            ast::Item::EnumDef(enumdef_ast) => {
+                let name = enumdef_ast.name.clone();
+                let enumdef = EnumDefId::from_ident(package_id.clone(), name.clone());
+                items.push(ItemId::EnumDef(enumdef));
+                if !item_names.insert(name.clone()) {
+                    errors.add(virdant_error!("Duplicate item name in package {name}"))
+                }
+            },
         }
     }
     Ok(items)
@@ -135,6 +173,250 @@ fn portdef(db: &dyn ItemResolutionQ, portdef: QualIdent, package_id: PackageId)
     if let ItemId::PortDef(portdef_id) = db.item(portdef.clone(), package_id)? {
         Ok(portdef_id)
     } else {
-        Err(virdant_error!("Item {portdef} is not a mod def"))
+        Err(virdant_error!("Item {portdef} is not a port def"))
+    }
+}
+
+fn fndef(db: &dyn ItemResolutionQ, fndef: QualIdent, package_id: PackageId) -> VirdantResult<FnDefId> {
+    if let ItemId::FnDef(fndef_id) = db.item(fndef.clone(), package_id)? {
+        Ok(fndef_id)
+    } else {
+        Err(virdant_error!("Item {fndef} is not a fn def"))
+    }
+}
+
+fn constdef(db: &dyn ItemResolutionQ, constdef: QualIdent, package_id: PackageId) -> VirdantResult<ConstDefId> {
+    if let ItemId::ConstDef(constdef_id) = db.item(constdef.clone(), package_id)? {
+        Ok(constdef_id)
+    } else {
+        Err(virdant_error!("Item {constdef} is not a const def"))
+    }
+}
+
+/// Pairs each parameter of a moddef with the value it was specialized to.
+fn moddef_param_values(db: &dyn ItemResolutionQ, moddef_id: ModDefId) -> VirdantResult<Vec<(Ident, u64)>> {
+    let params = db.moddef_ast(moddef_id.clone())?.params.clone();
+    let args = moddef_id.args();
+    if params.len() != args.len() {
+        let n = params.len();
+        let m = args.len();
+        return Err(virdant_error!("Mod def {moddef_id} takes {n} parameters, but {m} were given"));
+    }
+    Ok(params.into_iter().zip(args).collect())
+}
+
+/// The value of a Nat appearing inside of the given moddef.
+fn resolve_nat(db: &dyn ItemResolutionQ, nat: ast::Nat, moddef_id: ModDefId) -> VirdantResult<u64> {
+    match nat {
+        ast::Nat::Lit(n) => Ok(n),
+        ast::Nat::Param(param) => {
+            for (name, value) in db.moddef_param_values(moddef_id.clone())? {
+                if name == param {
+                    return Ok(value);
+                }
+            }
+            const_nat(db, param.clone().into(), moddef_id.package())
+                .map_err(|err| virdant_error!("Unknown parameter {param} in {moddef_id}").because(err))
+        },
+        ast::Nat::Const(name) => const_nat(db, name, moddef_id.package()),
+    }
+}
+
+/// The value of a constant used as a Nat, as in `Word[WIDTH]`.
+pub(crate) fn const_nat(db: &dyn ItemResolutionQ, name: QualIdent, package_id: PackageId) -> VirdantResult<u64> {
+    let constdef_id = db.constdef(name, package_id)?;
+    db.const_value(constdef_id)
+}
+
+fn const_cycle<T>(_db: &dyn ItemResolutionQ, _cycle: &[String], constdef_id: &ConstDefId) -> VirdantResult<T> {
+    Err(virdant_error!("The definition of constant {constdef_id} refers to itself"))
+}
+
+/// The width of a constant, which must be declared as a Word.
+fn const_width(db: &dyn ItemResolutionQ, constdef_id: ConstDefId) -> VirdantResult<Width> {
+    let constdef_ast = db.constdef_ast(constdef_id.clone())?;
+    match constdef_ast.typ.as_ref() {
+        ast::Type::Word(ast::Nat::Lit(n)) => Ok(*n),
+        ast::Type::Word(ast::Nat::Param(name)) => const_nat(db, name.clone().into(), constdef_id.package()),
+        ast::Type::Word(ast::Nat::Const(name)) => const_nat(db, name.clone(), constdef_id.package()),
+        _ => Err(virdant_error!("Constant {constdef_id} must have a Word type")),
+    }
+}
+
+/// Evaluates a constant at compile time.
+fn const_value(db: &dyn ItemResolutionQ, constdef_id: ConstDefId) -> VirdantResult<u64> {
+    let constdef_ast = db.constdef_ast(constdef_id.clone())?;
+    let width = db.const_width(constdef_id.clone())?;
+    let value = eval_const(db, &constdef_ast.value, constdef_id.package(), width)
+        .map_err(|err| virdant_error!("Could not evaluate constant {constdef_id}").because(err))?;
+    Ok(value)
+}
+
+/// Evaluates an expression whose value is known at compile time.
+/// Arithmetic wraps around at `width` bits.
+fn eval_const(db: &dyn ItemResolutionQ, expr: &Ast<ast::Expr>, package_id: PackageId, width: Width) -> VirdantResult<u64> {
+    let mask = if width >= 64 { u64::MAX } else { (1 << width) - 1 };
+    let eval = |e: &Ast<ast::Expr>| eval_const(db, e, package_id.clone(), width);
+
+    let value = match expr.as_ref() {
+        ast::Expr::Word(lit) => lit.value,
+        ast::Expr::Reference(path) => match path.as_ident() {
+            Some(name) => const_nat(db, name.into(), package_id.clone())?,
+            None => return Err(virdant_error!("Constants may not refer to {path}")),
+        },
+        ast::Expr::QualReference(name) => const_nat(db, name.clone(), package_id.clone())?,
+        ast::Expr::If(c, a, b) => if eval(c)? != 0 { eval(a)? } else { eval(b)? },
+        ast::Expr::MethodCall(subject, method, _width, args) => {
+            let a = eval(subject)?;
+            let b = match args.as_slice() {
+                [] => 0,
+                [arg] => eval(arg)?,
+                _ => return Err(virdant_error!("Wrong number of arguments to {method}")),
+            };
+            match method.as_str() {
+                "add" => a.wrapping_add(b),
+                "sub" => a.wrapping_sub(b),
+                "mul" => a.wrapping_mul(b),
+                "inc" => a.wrapping_add(1),
+                "dec" => a.wrapping_sub(1),
+                "neg" => a.wrapping_neg(),
+                "and" => a & b,
+                "or" => a | b,
+                "xor" => a ^ b,
+                "not" => !a,
+                "sll" => a.checked_shl(b as u32).unwrap_or(0),
+                "srl" => a.checked_shr(b as u32).unwrap_or(0),
+                "eq" => (a == b) as u64,
+                "neq" => (a != b) as u64,
+                "lt" | "ltu" => (a < b) as u64,
+                "lte" | "lteu" => (a <= b) as u64,
+                "gt" | "gtu" => (a > b) as u64,
+                "gte" | "gteu" => (a >= b) as u64,
+                _ => return Err(virdant_error!("Method {method} can't be used in a constant")),
+            }
+        },
+        _ => return Err(virdant_error!("Expression can't be evaluated at compile time")),
+    };
+
+    Ok(value & mask)
+}
+
+/// The (specialized) moddef which a submodule of the given moddef is an instance of.
+fn submodule_moddef(db: &dyn ItemResolutionQ, submodule: Ast<ast::Submodule>, moddef_id: ModDefId) -> VirdantResult<ModDefId> {
+    let generic_moddef_id = db.moddef(submodule.moddef.clone(), moddef_id.package())?;
+    let mut args = vec![];
+    for arg in &submodule.args {
+        args.push(db.resolve_nat(arg.clone(), moddef_id.clone())?);
+    }
+    let submodule_moddef_id = generic_moddef_id.specialize(args);
+    db.moddef_param_values(submodule_moddef_id.clone())?;
+    Ok(submodule_moddef_id)
+}
+
+/// Every moddef without parameters, together with each specialization of a parametric moddef
+/// which is instantiated (directly or indirectly) by one of them.
+fn elaborated_moddefs(db: &dyn ItemResolutionQ) -> VirdantResult<Vec<ModDefId>> {
+    let mut moddefs = vec![];
+    let mut queue = vec![];
+    for package in db.packages() {
+        for moddef_id in db.package_moddefs(package)? {
+            if db.moddef_ast(moddef_id.clone())?.params.is_empty() {
+                queue.push(moddef_id);
+            }
+        }
+    }
+    queue.reverse();
+
+    while let Some(moddef_id) = queue.pop() {
+        if moddefs.contains(&moddef_id) {
+            continue;
+        }
+        let moddef_ast = db.moddef_ast(moddef_id.clone())?;
+        let mut specializations = vec![];
+        for decl in &moddef_ast.decls {
+            if let ast::Decl::Submodule(submodule) = decl {
+                let submodule_moddef_id = db.submodule_moddef(submodule.clone(), moddef_id.clone())?;
+                if !submodule_moddef_id.args().is_empty() {
+                    specializations.push(submodule_moddef_id);
+                }
+            }
+        }
+        moddefs.push(moddef_id);
+        queue.extend(specializations.into_iter().rev());
     }
+
+    let mut acyclic = HashSet::new();
+    for moddef_id in &moddefs {
+        check_no_instantiation_cycle(db, moddef_id.clone(), &mut vec![], &mut acyclic)?;
+    }
+    Ok(moddefs)
+}
+
+/// A moddef which instantiates itself, directly or through other moddefs, would never bottom out.
+/// Specializations with different args are different moddefs here, so `Tree[4]` may contain a `Tree[0]`.
+fn check_no_instantiation_cycle(
+    db: &dyn ItemResolutionQ,
+    moddef_id: ModDefId,
+    stack: &mut Vec<ModDefId>,
+    acyclic: &mut HashSet<ModDefId>,
+) -> VirdantResult<()> {
+    if acyclic.contains(&moddef_id) {
+        return Ok(());
+    }
+
+    stack.push(moddef_id.clone());
+    for decl in &db.moddef_ast(moddef_id.clone())?.decls {
+        if let ast::Decl::Submodule(submodule) = decl {
+            let submodule_moddef_id = db.submodule_moddef(submodule.clone(), moddef_id.clone())?;
+            if let Some(start) = stack.iter().position(|ancestor| *ancestor == submodule_moddef_id) {
+                let cycle: Vec<String> = stack[start..].iter().chain([&submodule_moddef_id]).map(|moddef_id| moddef_id.to_string()).collect();
+                let cycle = cycle.join(" -> ");
+                let span = db.span(submodule.span());
+                return Err(virdant_error_at!("Module instantiation cycle: {cycle}", span));
+            }
+            check_no_instantiation_cycle(db, submodule_moddef_id, stack, acyclic)?;
+        }
+    }
+    stack.pop();
+
+    acyclic.insert(moddef_id);
+    Ok(())
+}
+
+#[test]
+fn elaborated_moddef_params() {
+    let elaborate = |top: &str| {
+        let mut db = Db::new();
+        db.set_source("top", &format!("
+            mod Top {{
+                {top}
+            }}
+
+            mod Delay[W] {{
+                incoming inp : Word[W];
+            }}
+        "));
+        db.elaborated_moddefs().map(|moddef_ids| moddef_ids.iter().map(|moddef_id| moddef_id.to_string()).collect::<Vec<_>>())
+    };
+
+    assert_eq!(elaborate("mod d of Delay[4];").unwrap(), vec!["top::Top", "top::Delay[4]"]);
+    let err = format!("{:?}", elaborate("mod d of Delay;").unwrap_err());
+    assert!(err.contains("Mod def top::Delay takes 1 parameters, but 0 were given"));
+    let err = format!("{:?}", elaborate("mod d of Delay[4, 4];").unwrap_err());
+    assert!(err.contains("Mod def top::Delay[4, 4] takes 1 parameters, but 2 were given"));
+}
+
+#[test]
+fn const_values() {
+    let value = |consts: &str| {
+        let mut db = Db::new();
+        db.set_source("top", consts);
+        let constdef_id = db.constdef(Ident::from("WIDTH").into(), db.resolve_package("top".into())?)?;
+        db.const_value(constdef_id)
+    };
+
+    assert_eq!(value("const WIDTH : Word[8] = 8w8->add(8);").unwrap(), 16);
+    assert_eq!(value("const WIDTH : Word[8] = 255->add(9);").unwrap(), 8);
+    let err = format!("{:?}", value("const WIDTH : Word[8] = WIDTH->inc();").unwrap_err());
+    assert!(err.contains("The definition of constant top::WIDTH refers to itself"));
 }
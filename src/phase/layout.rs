@@ -1,12 +1,18 @@
 use crate::common::*;
 use crate::ast;
-use crate::virdant_error;
+use crate::{virdant_error, virdant_error_at};
 use super::*;
 
 #[salsa::query_group(LayoutQStorage)]
-pub trait LayoutQ: typecheck::TypecheckQ {
+pub trait LayoutQ: type_resolution::TypeResolutionQ {
     fn bitwidth(&self, typ: Type) -> VirdantResult<Width>;
 
+    /// The fields of a struct type with their types and offsets.
+    /// The first field takes the least significant bits.
+    fn struct_fields(&self, typ: Type) -> VirdantResult<Vec<(Ident, Type, Offset)>>;
+    /// The type and offset of the part of a value of type `typ` which is replaced by `->with`.
+    fn update_slot(&self, typ: Type, update: ast::Update) -> VirdantResult<(Type, Offset)>;
+
     fn union_ctor_tag(&self, typ: Type, ctor: Ident) -> VirdantResult<u64>;
     fn union_layout(&self, typ: Type) -> VirdantResult<UnionLayout>;
 }
@@ -16,93 +22,158 @@ fn bitwidth(db: &dyn LayoutQ, typ: Type) -> VirdantResult<Width> {
         Type::Clock => Ok(1),
         Type::Bool => Ok(1),
         Type::Word(n) => Ok(n.into()),
+        Type::Enum(enumdef_id) => db.enumdef_width(enumdef_id),
+        Type::Vec(typ, n) => Ok(db.bitwidth(typ.as_ref().clone())? * n),
         Type::Struct(structdef_id, _typ_args) => {
             let structdef_ast = db.structdef_ast(structdef_id.clone())?;
             let mut width = 0;
 
             for ast::Field(_fieldname, field_typ) in &structdef_ast.fields {
-                let resolved_field_typ = db.resolve_typ(field_typ.clone(), structdef_id.package())?;
+                let resolved_field_typ = db.resolve_typedef_typ(field_typ.clone(), typ.clone())?;
                 width += bitwidth(db, resolved_field_typ)?;
             }
 
             Ok(width)
         },
-        Type::Union(uniondef_id, _typ_args) => {
-            let uniondef_ast = db.uniondef_ast(uniondef_id.clone())?;
-
-            let mut payload_width = 0;
-            for ast::Alt(_ctor, ast_arg_typs) in &uniondef_ast.alts {
-                let mut resolved_arg_typs = vec![];
-                for ast_arg_typ in ast_arg_typs {
-                    let resolved_typ = db.resolve_typ(ast_arg_typ.clone(), uniondef_id.package())?;
-                    resolved_arg_typs.push(resolved_typ);
-                }
+        Type::Union(_uniondef_id, _typ_args) => Ok(db.union_layout(typ)?.width()),
+    }
+}
 
-                let mut width = 0;
-                for resolved_arg_typ in &resolved_arg_typs {
-                    width += db.bitwidth(resolved_arg_typ.clone())?;
-                }
+fn struct_fields(db: &dyn LayoutQ, typ: Type) -> VirdantResult<Vec<(Ident, Type, Offset)>> {
+    let Type::Struct(structdef_id, _typ_args) = &typ else {
+        return Err(virdant_error!("Type {typ} is not a struct type"));
+    };
+    let structdef_ast = db.structdef_ast(structdef_id.clone())?;
 
-                if width > payload_width {
-                    payload_width = width;
-                }
+    let mut fields = vec![];
+    let mut offset = 0;
+    for ast::Field(fieldname, field_typ) in &structdef_ast.fields {
+        let resolved_field_typ = db.resolve_typedef_typ(field_typ.clone(), typ.clone())?;
+        let width = db.bitwidth(resolved_field_typ.clone())?;
+        fields.push((fieldname.clone(), resolved_field_typ, offset));
+        offset += width;
+    }
+    Ok(fields)
+}
+
+fn update_slot(db: &dyn LayoutQ, typ: Type, update: ast::Update) -> VirdantResult<(Type, Offset)> {
+    match (&typ, &update) {
+        (Type::Word(n), ast::Update::Idx(i)) if i < n => Ok((Type::Word(1), *i)),
+        (Type::Word(n), ast::Update::IdxRange(j, i)) if i < j && j <= n => Ok((Type::Word(j - i), *i)),
+        (Type::Vec(elem_typ, n), ast::Update::Idx(i)) if i < n => {
+            let elem_typ = elem_typ.as_ref().clone();
+            let offset = i * db.bitwidth(elem_typ.clone())?;
+            Ok((elem_typ, offset))
+        },
+        (Type::Struct(_, _), ast::Update::Field(field)) => {
+            match db.struct_fields(typ.clone())?.into_iter().find(|(fieldname, _typ, _offset)| fieldname == field) {
+                Some((_fieldname, field_typ, offset)) => Ok((field_typ, offset)),
+                None => Err(virdant_error!("Type {typ} has no field {field}")),
             }
-            //let tag_width = db.alttypedef_tag_bitwidth(typ.clone())?;
-            let layout = db.union_layout(typ.clone())?;
-            let width = layout.tag_width + payload_width;
-            Ok(width)
         },
+        (Type::Word(_) | Type::Vec(_, _), ast::Update::Idx(i)) => Err(virdant_error!("Index [{i}] is out of bounds for {typ}")),
+        (Type::Word(_), ast::Update::IdxRange(j, i)) => Err(virdant_error!("Range [{j}..{i}] is out of bounds for {typ}")),
+        _ => Err(virdant_error!("Values of type {typ} can't be updated with {update:?}")),
     }
 }
 
+/// Unions are laid out with the tag in the least significant bits,
+/// followed by the arguments of the ctor, with the first argument in the lower bits.
+/// The bits above the arguments of a ctor which has fewer bits than the widest ctor are padding.
+///
+/// The layout may be chosen with a `#[layout(...)]` attribute on the union type:
+///
+/// * `padding = "zero"` - padding is filled with zeros. This is the default.
+/// * `padding = "x"` - padding is left as `x`.
+/// * `tag = "binary"` - the ctors are numbered in order, starting from 0. This is the default.
+/// * `tag = "onehot"` - the tag of the `i`th ctor has only bit `i` set.
+///
+/// The tag is always at least one bit wide, even for a union with a single ctor.
 fn union_layout(db: &dyn LayoutQ, typ: Type) -> VirdantResult<UnionLayout> {
-    let uniondef = if let Type::Union(uniondef, _typ_args) = typ {
-        uniondef
-    } else {
-        todo!()
+    let Type::Union(uniondef, _typ_args) = &typ else {
+        return Err(virdant_error!("Type {typ} is not a union type"));
+    };
+    let uniondef_ast = db.uniondef_ast(uniondef.clone())?;
+
+    let mut padding = UnionPadding::Zero;
+    let mut tags = UnionTags::Binary;
+    let mut errors = ErrorReport::new();
+    for attr in &uniondef_ast.attrs {
+        let span = db.span(attr.span());
+        let ast::Attr(attr_name, args) = attr.as_ref();
+        if attr_name.as_str() != "layout" {
+            errors.add(virdant_error_at!("Unknown attribute #[{attr_name}] on {uniondef}", span));
+            continue;
+        }
+        for arg in args {
+            match arg {
+                ast::AttrArg::Named(name, ast::AttrValue::Str(value)) => match (name.as_str(), value.as_str()) {
+                    ("padding", "zero") => padding = UnionPadding::Zero,
+                    ("padding", "x") => padding = UnionPadding::X,
+                    ("tag", "binary") => tags = UnionTags::Binary,
+                    ("tag", "onehot") => tags = UnionTags::OneHot,
+                    _ => errors.add(virdant_error_at!("Unknown layout {name} = \"{value}\" on {uniondef}", span.clone())),
+                },
+                _ => errors.add(virdant_error_at!("Layouts must be given as NAME = \"value\"", span.clone())),
+            }
+        }
+    }
+    errors.check()?;
+
+    let ctors = uniondef_ast.alts.len() as u64;
+    let tag_width = match tags {
+        UnionTags::Binary => clog2(ctors).max(1),
+        UnionTags::OneHot => ctors.max(1),
     };
-    let alttypedef_ast = db.uniondef_ast(uniondef.clone())?;
-    let tag_width = clog2(alttypedef_ast.alts.len() as u64);
 
     let mut slots_by_ctor: Vec<(Ident, CtorSlots)> = vec![];
-    for ast::Alt(ctor, arg_typs) in &alttypedef_ast.alts {
+    for ast::Alt(ctor, arg_typs) in &uniondef_ast.alts {
         let mut slots = CtorSlots::default();
         for arg_typ in arg_typs {
-            let resolved_arg_typ = db.resolve_typ(arg_typ.clone(), uniondef.package())?;
+            let resolved_arg_typ = db.resolve_typedef_typ(arg_typ.clone(), typ.clone())?;
             let arg_typ_bitwidth = db.bitwidth(resolved_arg_typ)?;
             slots.add(arg_typ_bitwidth);
         }
         slots_by_ctor.push((ctor.clone(), slots))
     }
 
-    let slots_by_ctor = slots_by_ctor.into_iter().collect();
-
     let layout = UnionLayout {
         tag_width,
+        tags,
+        padding,
         slots: slots_by_ctor,
     };
     Ok(layout)
 }
 
 fn union_ctor_tag(db: &dyn LayoutQ, typ: Type, ctor: Ident) -> VirdantResult<u64> {
-    let uniondef = if let Type::Union(uniondef, _typ_args) = typ {
-        uniondef
+    let layout = db.union_layout(typ.clone())?;
+    if layout.slots.iter().any(|(ctor_name, _slots)| ctor_name == &ctor) {
+        Ok(layout.tag_for(ctor))
     } else {
-        todo!()
-    };
-
-    let alttypedef_ast = db.uniondef_ast(uniondef)?;
-    for (tag, ast::Alt(ctor_name, _)) in alttypedef_ast.alts.iter().enumerate() {
-        if ctor_name == &ctor {
-            return Ok(tag.try_into().unwrap());
-        }
+        Err(virdant_error!("Unknown ctor: {ctor} on type {typ}"))
     }
-    Err(virdant_error!("Unknown ctor: {ctor}"))
+}
+
+/// How the tags of a union are encoded.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+pub enum UnionTags {
+    Binary,
+    OneHot,
+}
+
+/// What fills the bits of a union value which aren't used by its ctor.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+pub enum UnionPadding {
+    Zero,
+    X,
 }
 
 #[derive(Clone, Eq, PartialEq, Hash, Debug)]
 pub struct UnionLayout {
     tag_width: Width,
+    tags: UnionTags,
+    padding: UnionPadding,
     slots: Vec<(Ident, CtorSlots)>,
 }
 
@@ -112,24 +183,24 @@ impl UnionLayout {
     }
 
     pub fn payload_width(&self) -> Width {
-        let mut payload_width = 0;
-        for ctor in self.slots.iter().map(|(ctor, _)| ctor) {
-            let ctor_payload_width = self.ctor_payload_width(ctor.clone());
-            if ctor_payload_width > payload_width {
-                payload_width = ctor_payload_width;
-            }
-        }
-        payload_width
+        self.slots.iter().map(|(_ctor, slots)| slots.width()).max().unwrap_or(0)
     }
 
     pub fn tag_width(&self) -> Width {
         self.tag_width
     }
 
+    pub fn padding(&self) -> UnionPadding {
+        self.padding
+    }
+
     pub fn tag_for(&self, ctor: Ident) -> Tag {
-        for (tag, (ctor_name, _slots)) in self.slots.iter().enumerate() {
+        for (i, (ctor_name, _slots)) in self.slots.iter().enumerate() {
             if ctor_name == &ctor {
-                return tag as Tag;
+                return match self.tags {
+                    UnionTags::Binary => i as Tag,
+                    UnionTags::OneHot => 1 << i,
+                };
             }
         }
 
@@ -137,12 +208,10 @@ impl UnionLayout {
     }
 
     pub fn ctor_slots(&self, ctor: Ident) -> Vec<(Offset, Width)> {
-        for (_ctor_name, slots) in &self.slots {
-            let mut results = vec![];
-            for i in 0..slots.0.len() {
-                results.push(self.ctor_slot(ctor.clone(), i));
+        for (ctor_name, slots) in &self.slots {
+            if ctor_name == &ctor {
+                return (0..slots.0.len()).map(|i| self.ctor_slot(ctor.clone(), i)).collect();
             }
-        return results;
         }
         panic!("No ctor found: {ctor}")
     }
@@ -170,12 +239,7 @@ impl UnionLayout {
     }
 
     pub fn ctor_width(&self, ctor: Ident) -> Width {
-        for (ctor_name, slots) in &self.slots {
-            if ctor_name == &ctor {
-                return self.tag_width + slots.width();
-            }
-        }
-        panic!("No ctor found: {ctor}")
+        self.tag_width + self.ctor_payload_width(ctor)
     }
 }
 
@@ -191,3 +255,56 @@ impl CtorSlots {
         self.0.iter().sum()
     }
 }
+
+#[test]
+fn union_layouts() {
+    use super::item_resolution::ItemResolutionQ;
+    use super::resolve::ResolveQ;
+    use super::type_resolution::TypeResolutionQ;
+
+    let layout_for = |attrs: &str, union: &str| {
+        let mut db = Db::new();
+        db.set_source("top", &format!("
+            {attrs}
+            union type Op {{
+                Nop();
+                Load(Word[4], Word[2]);
+                Jump(Word[3]);
+            }}
+
+            union type Only {{
+                Only(Word[4]);
+            }}
+
+            mod Top {{
+                incoming op : Op;
+                incoming only : Only;
+            }}
+        "));
+        let moddef_id = db.moddef(Ident::from("Top").into(), db.resolve_package("top".into())?)?;
+        let component_id = db.resolve_component_by_path(moddef_id, union.into())?;
+        db.union_layout(db.component_typ(component_id)?)
+    };
+
+    let layout = layout_for("", "op").unwrap();
+    assert_eq!((layout.tag_width(), layout.width()), (2, 8));
+    assert_eq!(layout.tag_for("Jump".into()), 2);
+    assert_eq!(layout.ctor_slots("Load".into()), vec![(2, 4), (6, 2)]);
+    assert_eq!(layout.ctor_slots("Jump".into()), vec![(2, 3)]);
+    assert_eq!(layout.padding(), UnionPadding::Zero);
+
+    // A union with a single ctor still has a tag.
+    let layout = layout_for("", "only").unwrap();
+    assert_eq!((layout.tag_width(), layout.width()), (1, 5));
+    assert_eq!(layout.ctor_slots("Only".into()), vec![(1, 4)]);
+
+    let layout = layout_for("#[layout(tag = \"onehot\", padding = \"x\")]", "op").unwrap();
+    assert_eq!((layout.tag_width(), layout.width()), (3, 9));
+    assert_eq!(layout.tag_for("Jump".into()), 0b100);
+    assert_eq!(layout.ctor_slots("Jump".into()), vec![(3, 3)]);
+    assert_eq!(layout.padding(), UnionPadding::X);
+
+    let err = |attrs: &str| format!("{:?}", layout_for(attrs, "op").unwrap_err());
+    assert!(err("#[layout(tag = \"gray\")]").contains("Unknown layout tag = \\\"gray\\\" on top::Op"));
+    assert!(err("#[packed()]").contains("Unknown attribute #[packed] on top::Op"));
+}
@@ -1,4 +1,5 @@
-use crate::{ast, common::*, virdant_error};
+use crate::{ast, common::*, virdant_error, virdant_error_at};
+use crate::ast::Ast;
 use super::*;
 
 #[salsa::query_group(ResolveQStorage)]
@@ -9,6 +10,97 @@ pub trait ResolveQ: item_resolution::ItemResolutionQ {
     fn element_ofness(&self, element_id: ElementId) -> VirdantResult<Option<ItemId>>;
 
     fn resolve_component_by_path(&self, moddef_id: ModDefId, path: Path) -> VirdantResult<ElementId>;
+
+    fn port_role(&self, moddef_id: ModDefId, path: Path) -> VirdantResult<(PortDefId, ast::PortRole)>;
+    fn port_channel_driven(&self, moddef_id: ModDefId, path: Path) -> VirdantResult<Option<bool>>;
+    fn moddef_wires(&self, moddef_id: ModDefId) -> VirdantResult<Vec<Ast<ast::Wire>>>;
+}
+
+/// The portdef of a port instance, and the role it plays as seen from the given moddef.
+/// The path is either a port `p` of the moddef, or a port `s.p` of one of its submodules.
+fn port_role(db: &dyn ResolveQ, moddef_id: ModDefId, path: Path) -> VirdantResult<(PortDefId, ast::PortRole)> {
+    let (moddef_id, name, flipped) = match path.parts().as_slice() {
+        [name] => (moddef_id, name.clone(), false),
+        [submodule_name, name] => {
+            let moddef_ast = db.moddef_ast(moddef_id.clone())?;
+            let submodule = moddef_ast.decls.iter().find_map(|decl| match decl {
+                ast::Decl::Submodule(submodule) if &submodule.name == submodule_name => Some(submodule.clone()),
+                _ => None,
+            }).ok_or_else(|| virdant_error!("No submodule {submodule_name} in {moddef_id}"))?;
+            (db.submodule_moddef(submodule, moddef_id)?, name.clone(), true)
+        },
+        _ => return Err(virdant_error!("Not a port: {path}")),
+    };
+
+    let moddef_ast = db.moddef_ast(moddef_id.clone())?;
+    for decl in &moddef_ast.decls {
+        if let ast::Decl::Port(port) = decl {
+            if port.name == name {
+                let portdef_id = db.portdef(port.portdef.clone(), moddef_id.package())?;
+                let role = if flipped { port.role.flip() } else { port.role };
+                return Ok((portdef_id, role));
+            }
+        }
+    }
+    Err(virdant_error!("Not a port: {path}"))
+}
+
+/// For a channel of a port instance, as in `p.c` or `s.p.c`, whether the given moddef drives it.
+/// Returns `None` when the path isn't a port channel.
+fn port_channel_driven(db: &dyn ResolveQ, moddef_id: ModDefId, path: Path) -> VirdantResult<Option<bool>> {
+    if path.is_local() {
+        return Ok(None);
+    }
+    let Ok((portdef_id, role)) = db.port_role(moddef_id, path.parent()) else {
+        return Ok(None);
+    };
+    let channel_name = path.name();
+    let portdef_ast = db.portdef_ast(portdef_id.clone())?;
+    for ast::Channel(dir, name, _typ) in &portdef_ast.channels {
+        if name == &channel_name {
+            return Ok(Some(role.drives(*dir)));
+        }
+    }
+    Err(virdant_error!("No channel {channel_name} in {portdef_id}"))
+}
+
+/// The wires of a moddef, with each connect `a :=: b` expanded into one wire for each channel.
+fn moddef_wires(db: &dyn ResolveQ, moddef_id: ModDefId) -> VirdantResult<Vec<Ast<ast::Wire>>> {
+    let mut errors = ErrorReport::new();
+    let mut wires = vec![];
+    let moddef_ast = db.moddef_ast(moddef_id.clone())?;
+
+    for decl in &moddef_ast.decls {
+        match decl {
+            ast::Decl::Wire(wire) => wires.push(wire.clone()),
+            ast::Decl::Connect(connect) => {
+                let span = db.span(connect.span());
+                let ast::Connect(a, b) = connect.as_ref();
+                let (a_portdef_id, a_role) = db.port_role(moddef_id.clone(), a.clone())?;
+                let (b_portdef_id, b_role) = db.port_role(moddef_id.clone(), b.clone())?;
+
+                if a_portdef_id != b_portdef_id {
+                    errors.add(virdant_error_at!("Can't connect {a} of {a_portdef_id} to {b} of {b_portdef_id}", span));
+                    continue;
+                } else if a_role == b_role {
+                    let roles = if a_role == ast::PortRole::Master { "masters" } else { "slaves" };
+                    errors.add(virdant_error_at!("Can't connect {a} to {b}, since they are both {roles}", span));
+                    continue;
+                }
+
+                let portdef_ast = db.portdef_ast(a_portdef_id)?;
+                for ast::Channel(dir, channel, _typ) in &portdef_ast.channels {
+                    let (target, source) = if a_role.drives(*dir) { (a, b) } else { (b, a) };
+                    let source = connect.derive(ast::Expr::Reference(source.join(&channel.as_path())));
+                    wires.push(connect.derive(ast::Wire(target.join(&channel.as_path()), ast::WireType::Continuous, source)));
+                }
+            },
+            _ => (),
+        }
+    }
+
+    errors.check()?;
+    Ok(wires)
 }
 
 fn resolve_component_by_path(db: &dyn ResolveQ, moddef_id: ModDefId, path: Path) -> VirdantResult<ElementId> {
@@ -36,7 +128,8 @@ fn element_ofness(db: &dyn ResolveQ, element_id: ElementId) -> VirdantResult<Opt
         for decl in &moddef_ast.decls {
             match decl {
                 ast::Decl::Submodule(submodule) if submodule.name == element_id.name() => {
-                    return Ok(Some(db.item(submodule.moddef.clone(), package_id)?))
+                    let ItemId::ModDef(moddef_id) = element_id.item() else { unreachable!() };
+                    return Ok(Some(db.submodule_moddef(submodule.clone(), moddef_id)?.as_item()))
                 },
                 ast::Decl::Port(port) if port.name == element_id.name() => {
                     return Ok(Some(db.item(port.portdef.clone(), package_id)?))
@@ -87,3 +180,36 @@ fn resolve_element(db: &dyn ResolveQ, item_id: ItemId, name: Ident) -> VirdantRe
 
     Err(virdant_error!("TODO resolve_element: Could not resolve {name} in {item_id}"))
 }
+
+#[test]
+fn connect_wires() {
+    use super::item_resolution::ItemResolutionQ;
+
+    let wires_for = |top: &str| -> VirdantResult<Vec<String>> {
+        let mut db = Db::new();
+        db.set_source("top", &format!("
+            port Inc {{
+                mosi inp : Word[8];
+                miso out : Word[8];
+            }}
+
+            mod Top {{
+                slave port inc of Inc;
+                mod incrementer of Incrementer;
+                {top}
+            }}
+
+            mod Incrementer {{
+                slave port inc of Inc;
+                inc.out := inc.inp->inc();
+            }}
+        "));
+        let moddef_id = db.moddef(Ident::from("Top").into(), db.resolve_package("top".into())?)?;
+        let wires = db.moddef_wires(moddef_id)?;
+        Ok(wires.iter().map(|wire| wire.0.to_string()).collect())
+    };
+
+    assert_eq!(wires_for("incrementer.inc :=: inc;").unwrap(), vec!["incrementer.inc.inp", "inc.out"]);
+    let err = format!("{:?}", wires_for("inc :=: inc;").unwrap_err());
+    assert!(err.contains("Can't connect inc to inc, since they are both slaves"));
+}
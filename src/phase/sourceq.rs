@@ -8,6 +8,9 @@ pub trait SourceQ: salsa::Database {
     #[salsa::input]
     fn sources(&self) -> HashMap<String, Arc<String>>;
 
+    #[salsa::input]
+    fn source_dirs(&self) -> HashMap<String, std::path::PathBuf>;
+
     fn linelens(&self, package_id: PackageId) -> LineLens;
     fn pos(&self, pos: PosIdx) -> Pos;
     fn span(&self, span: SpanIdx) -> Span;
@@ -1,9 +1,11 @@
 use crate::ast::ComponentKind;
 use crate::context::Context;
 use crate::{ast, common::*};
+use crate::virdant_error_at;
 use super::*;
 
 use super::typecheck::TypedExpr;
+use super::sourceq::Span;
 
 #[salsa::query_group(StructureQStorage)]
 pub trait StructureQ: check::CheckQ {
@@ -16,7 +18,25 @@ pub struct ModDef {
     components: Vec<Component>,
     submodules: Vec<Submodule>,
     ports: Vec<Port>,
-    ext: bool,
+    properties: Vec<Property>,
+    ext: Option<ExtBinding>,
+    out_of_range: OutOfRange,
+}
+
+/// What a dynamic `get` or `slice` reads past the end of its subject.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum OutOfRange {
+    #[default]
+    Zero,
+    X,
+}
+
+/// Where the implementation of an `ext mod` lives.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ExtBinding {
+    source: std::path::PathBuf,
+    verilog_name: String,
+    params: Vec<(Ident, ast::AttrValue)>,
 }
 
 #[derive(Clone, PartialEq, Eq, Debug)]
@@ -26,6 +46,27 @@ pub struct Component {
     kind: ast::ComponentKind,
     driver: Option<Arc<TypedExpr>>,
     clock: Option<Arc<TypedExpr>>,
+    /// The write ports of a mem.
+    writes: Vec<MemWritePort>,
+    /// The hex file a mem is initialized from.
+    init: Option<std::path::PathBuf>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct MemWritePort {
+    addr: Arc<TypedExpr>,
+    data: Arc<TypedExpr>,
+    enable: Option<Arc<TypedExpr>>,
+}
+
+/// An `assert` or a `cover`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Property {
+    kind: ast::PropertyKind,
+    cond: Arc<TypedExpr>,
+    clock: Arc<TypedExpr>,
+    enable: Option<Arc<TypedExpr>>,
+    span: Span,
 }
 
 #[derive(Clone, PartialEq, Eq, Debug)]
@@ -39,6 +80,7 @@ pub struct Submodule {
 pub struct Port {
     id: ElementId,
     portdef_id: PortDefId,
+    role: ast::PortRole,
     drivers: HashMap<Path, Arc<TypedExpr>>,
 }
 
@@ -59,8 +101,41 @@ impl ModDef {
         self.submodules.clone()
     }
 
+    pub fn port_instances(&self) -> Vec<Port> {
+        self.ports.clone()
+    }
+
+    pub fn properties(&self) -> Vec<Property> {
+        self.properties.clone()
+    }
+
     pub fn is_ext(&self) -> bool {
-        self.ext
+        self.ext.is_some()
+    }
+
+    pub fn ext_binding(&self) -> Option<ExtBinding> {
+        self.ext.clone()
+    }
+
+    pub fn out_of_range(&self) -> OutOfRange {
+        self.out_of_range
+    }
+}
+
+impl ExtBinding {
+    /// The Verilog file which defines the module.
+    pub fn source(&self) -> std::path::PathBuf {
+        self.source.clone()
+    }
+
+    /// The name of the module in the Verilog source.
+    pub fn verilog_name(&self) -> String {
+        self.verilog_name.clone()
+    }
+
+    /// The values of Verilog parameters to instantiate the module with.
+    pub fn params(&self) -> Vec<(Ident, ast::AttrValue)> {
+        self.params.clone()
     }
 }
 
@@ -93,6 +168,10 @@ impl Component {
         self.kind == ComponentKind::Node
     }
 
+    pub fn is_mem(&self) -> bool {
+        self.kind == ComponentKind::Mem
+    }
+
     pub fn driver(&self) -> Option<Arc<TypedExpr>> {
         self.driver.clone()
     }
@@ -100,6 +179,57 @@ impl Component {
     pub fn clock(&self) -> Option<Arc<TypedExpr>> {
         self.clock.clone()
     }
+
+    pub fn writes(&self) -> Vec<MemWritePort> {
+        self.writes.clone()
+    }
+
+    pub fn init(&self) -> Option<std::path::PathBuf> {
+        self.init.clone()
+    }
+}
+
+impl MemWritePort {
+    pub fn addr(&self) -> Arc<TypedExpr> {
+        self.addr.clone()
+    }
+
+    pub fn data(&self) -> Arc<TypedExpr> {
+        self.data.clone()
+    }
+
+    /// The write happens on every clock cycle when there is no enable.
+    pub fn enable(&self) -> Option<Arc<TypedExpr>> {
+        self.enable.clone()
+    }
+}
+
+impl Property {
+    pub fn is_assert(&self) -> bool {
+        self.kind == ast::PropertyKind::Assert
+    }
+
+    pub fn is_cover(&self) -> bool {
+        self.kind == ast::PropertyKind::Cover
+    }
+
+    pub fn cond(&self) -> Arc<TypedExpr> {
+        self.cond.clone()
+    }
+
+    pub fn clock(&self) -> Arc<TypedExpr> {
+        self.clock.clone()
+    }
+
+    /// The property is checked on every clock cycle when there is no enable.
+    pub fn enable(&self) -> Option<Arc<TypedExpr>> {
+        self.enable.clone()
+    }
+
+    /// Where the property is declared, for reporting failures.
+    pub fn span(&self) -> Span {
+        self.span.clone()
+    }
 }
 
 impl Submodule {
@@ -111,6 +241,25 @@ impl Submodule {
         self.moddef_id.clone()
     }
 
+    pub fn driver_for(&self, path_id: Path) -> Arc<TypedExpr> {
+        self.drivers.get(&path_id).unwrap_or_else(|| panic!("No driver for {path_id}")).clone()
+    }
+}
+
+impl Port {
+    pub fn id(&self) -> ElementId {
+        self.id.clone()
+    }
+
+    pub fn portdef(&self) -> PortDefId {
+        self.portdef_id.clone()
+    }
+
+    pub fn role(&self) -> ast::PortRole {
+        self.role
+    }
+
+    /// The driver of one of the channels this module drives, as in `inc.out`.
     pub fn driver_for(&self, path_id: Path) -> Arc<TypedExpr> {
         self.drivers.get(&path_id).expect(&format!("No driver for {path_id}")).clone()
     }
@@ -122,13 +271,14 @@ fn structure_moddef(db: &dyn StructureQ, moddef_id: ModDefId) -> VirdantResult<M
     let mut components = vec![];
     let mut submodules = vec![];
     let mut ports = vec![];
+    let mut properties = vec![];
 
     let moddef_ast = db.moddef_ast(moddef_id.clone())?;
 
     for decl in &moddef_ast.decls {
         match decl {
             ast::Decl::Component(component) => {
-                let typ = db.resolve_typ(component.typ.clone(), moddef_id.package())?;
+                let typ = db.resolve_moddef_typ(component.typ.clone(), moddef_id.clone())?;
 
                 let driver = if !moddef_ast.ext {
                     let wire = db.wire_ast(moddef_id.clone(), component.name.as_path())?;
@@ -136,7 +286,7 @@ fn structure_moddef(db: &dyn StructureQ, moddef_id: ModDefId) -> VirdantResult<M
                     match wire {
                         Some(w) => {
                             let ast::Wire(_target, _wire_type, expr) = w.as_ref();
-                            Some(db.typecheck_expr(moddef_id.clone(), expr.clone(), typ.clone(), Context::empty())?)
+                            Some(db.typecheck_expr(moddef_id.as_item(), expr.clone(), typ.clone(), Context::empty())?)
                         },
                         None => None,
                     }
@@ -144,14 +294,35 @@ fn structure_moddef(db: &dyn StructureQ, moddef_id: ModDefId) -> VirdantResult<M
                     None
                 };
 
-                let clock = if component.kind == ComponentKind::Reg {
+                let clock = if component.kind == ComponentKind::Reg || component.kind == ComponentKind::Mem {
                     let clock_expr = component.clock.clone().unwrap();
-                    let typed_expr = db.typecheck_expr(moddef_id.clone(), clock_expr, Type::Clock, Context::empty())?;
+                    let typed_expr = db.typecheck_expr(moddef_id.as_item(), clock_expr, Type::Clock, Context::empty())?;
                     Some(typed_expr)
                 } else {
                     None
                 };
 
+                let mut writes = vec![];
+                if let Type::Vec(elem_typ, depth) = &typ {
+                    for decl in &moddef_ast.decls {
+                        if let ast::Decl::MemWrite(mem_write) = decl {
+                            let ast::MemWrite { mem, addr, data, enable } = mem_write.as_ref();
+                            if mem == &component.name {
+                                let typecheck = |expr: &ast::Ast<ast::Expr>, typ: Type| {
+                                    db.typecheck_expr(moddef_id.as_item(), expr.clone(), typ, Context::empty())
+                                };
+                                writes.push(MemWritePort {
+                                    addr: typecheck(addr, Type::Word(clog2(*depth)))?,
+                                    data: typecheck(data, elem_typ.as_ref().clone())?,
+                                    enable: enable.as_ref().map(|enable| typecheck(enable, Type::Word(1))).transpose()?,
+                                });
+                            }
+                        }
+                    }
+                }
+
+                let init = component.init.clone().map(|init| package_path(db, moddef_id.package(), init));
+
                 components.push(
                     Component {
                         id: ComponentId::from_ident(moddef_id.clone(), component.name.clone()),
@@ -159,13 +330,13 @@ fn structure_moddef(db: &dyn StructureQ, moddef_id: ModDefId) -> VirdantResult<M
                         kind: component.kind.clone(),
                         driver,
                         clock,
+                        writes,
+                        init,
                     }
                 )
             },
             ast::Decl::Submodule(submodule) => {
-                eprintln!("--------------------------------------------------------------------------------");
-                eprintln!("Submodule in Structure of module {moddef_id}: {submodule:?}");
-                let submodule_moddef_id = db.moddef(submodule.moddef.clone(), moddef_id.package())?;
+                let submodule_moddef_id = db.submodule_moddef(submodule.clone(), moddef_id.clone())?;
 
                 let mut drivers = HashMap::new();
 
@@ -177,28 +348,24 @@ fn structure_moddef(db: &dyn StructureQ, moddef_id: ModDefId) -> VirdantResult<M
                         ast::Decl::Component(component) if component.kind == ComponentKind::Incoming => {
                             let target_path = submodule.name.as_path().join(&component.name.as_path());
                             let component_id = db.resolve_component_by_path(moddef_id.clone(), target_path.clone())?;
-                            eprintln!("component_id = {component_id}");
                             incomings.insert(target_path, component_id);
                         },
                         _ => (),
                     }
                 }
 
-                for decl in &moddef_ast.decls {
-                    if let ast::Decl::Wire(wire) = decl {
-                        let ast::Wire(target, _wire_type, expr) = wire.as_ref();
-                        eprintln!("looking at wire targeting: {target}");
-                        if incomings.contains_key(target) {
-                            eprintln!("target = {target}");
-                            let component_id = db.resolve_component_by_path(moddef_id.clone(), target.clone())?;
-                            eprintln!("component_id = {component_id}");
-                            let typ = db.component_typ(component_id)?;
-                            let typed_expr = db.typecheck_expr(moddef_id.clone(), expr.clone(), typ.clone(), Context::empty())?;
-                            drivers.insert(target.clone(), typed_expr);
-                        }
+                for wire in db.moddef_wires(moddef_id.clone())? {
+                    let ast::Wire(target, _wire_type, expr) = wire.as_ref();
+                    // The channels of the submodule's ports which flow into it are driven from here, too.
+                    let driven_channel = target.head() == submodule.name &&
+                        db.port_channel_driven(moddef_id.clone(), target.clone())? == Some(true);
+                    if incomings.contains_key(target) || driven_channel {
+                        let component_id = db.resolve_component_by_path(moddef_id.clone(), target.clone())?;
+                        let typ = db.component_typ(component_id)?;
+                        let typed_expr = db.typecheck_expr(moddef_id.as_item(), expr.clone(), typ.clone(), Context::empty())?;
+                        drivers.insert(target.clone(), typed_expr);
                     }
                 }
-                dbg!(&drivers);
                 submodules.push(
                     Submodule {
                         id: ElementId::from_ident(moddef_id.as_item(), submodule.name.clone()),
@@ -206,48 +373,160 @@ fn structure_moddef(db: &dyn StructureQ, moddef_id: ModDefId) -> VirdantResult<M
                         drivers,
                     }
                 );
-                eprintln!("DONE");
-                eprintln!("--------------------------------------------------------------------------------");
             },
             ast::Decl::Port(port) => {
                 let portdef_id = db.portdef(port.portdef.clone(), moddef_id.package())?;
-                let drivers: HashMap<Path, Arc<TypedExpr>> = HashMap::new();
-
-                /*
-                for decl in &moddef_ast.decls {
-                    if let ast::Decl::Wire(wire) = decl {
-                        let ast::Wire(target, _wire_type, expr) = wire.as_ref();
-                        eprintln!("looking at wire targeting: {target}");
-                        if incomings.contains_key(target) {
-                            eprintln!("target = {target}");
-                            let component_id = db.resolve_component_by_path(moddef_id.clone(), target.clone())?;
-                            eprintln!("component_id = {component_id}");
-                            let typ = db.component_typ(component_id)?;
-                            let typed_expr = db.typecheck_expr(moddef_id.clone(), expr.clone(), typ.clone(), Context::empty())?;
-                            drivers.insert(target.clone(), typed_expr);
-                        }
+                let mut drivers: HashMap<Path, Arc<TypedExpr>> = HashMap::new();
+
+                for wire in db.moddef_wires(moddef_id.clone())? {
+                    let ast::Wire(target, _wire_type, expr) = wire.as_ref();
+                    if target.is_nonlocal() && target.head() == port.name {
+                        let component_id = db.resolve_component_by_path(moddef_id.clone(), target.clone())?;
+                        let typ = db.component_typ(component_id)?;
+                        let typed_expr = db.typecheck_expr(moddef_id.as_item(), expr.clone(), typ.clone(), Context::empty())?;
+                        drivers.insert(target.clone(), typed_expr);
                     }
                 }
-*/
 
                 ports.push(
                     Port {
                         id: ElementId::from_ident(moddef_id.as_item(), port.name.clone()),
                         portdef_id,
+                        role: port.role,
                         drivers,
                     }
                 );
             },
+            ast::Decl::Property(property) => {
+                let ast::Property { kind, cond, clock, enable } = property.as_ref();
+                let typecheck = |expr: &ast::Ast<ast::Expr>, typ: Type| {
+                    db.typecheck_expr(moddef_id.as_item(), expr.clone(), typ, Context::empty())
+                };
+                properties.push(Property {
+                    kind: *kind,
+                    cond: typecheck(cond, Type::Word(1))?,
+                    clock: typecheck(clock, Type::Clock)?,
+                    enable: enable.as_ref().map(|enable| typecheck(enable, Type::Word(1))).transpose()?,
+                    span: db.span(property.span()),
+                });
+            },
             ast::Decl::Wire(_wire) => (),
+            ast::Decl::IndexedWire(_wire) => (),
+            ast::Decl::MemWrite(_mem_write) => (),
+            ast::Decl::Connect(_connect) => (),
+            // Loops and whens are expanded by moddef_ast.
+            ast::Decl::For(_for_decl) => (),
+            ast::Decl::When(_when) => (),
         }
     }
 
+    let (ext, out_of_range) = if moddef_ast.ext {
+        (Some(structure_ext_binding(db, moddef_id.clone(), &moddef_ast)?), OutOfRange::default())
+    } else {
+        (None, structure_out_of_range(db, moddef_id.clone(), &moddef_ast)?)
+    };
+
     let moddef = ModDef {
         id: moddef_id,
         components,
         submodules,
         ports,
-        ext: moddef_ast.ext,
+        properties,
+        ext,
+        out_of_range,
     };
     Ok(moddef)
 }
+
+/// Reads the attributes of a mod which isn't ext.
+/// The only one is `#[out_of_range("zero")]` or `#[out_of_range("x")]`, which defaults to zero.
+fn structure_out_of_range(db: &dyn StructureQ, moddef_id: ModDefId, moddef_ast: &ast::ModDef) -> VirdantResult<OutOfRange> {
+    let mut errors = ErrorReport::new();
+    let mut out_of_range = None;
+    for attr in &moddef_ast.attrs {
+        let span = db.span(attr.span());
+        let ast::Attr(attr_name, args) = attr.as_ref();
+        match (attr_name.as_str(), args.as_slice()) {
+            ("out_of_range", [ast::AttrArg::Positional(ast::AttrValue::Str(policy))]) if out_of_range.is_none() => {
+                match policy.as_str() {
+                    "zero" => out_of_range = Some(OutOfRange::Zero),
+                    "x" => out_of_range = Some(OutOfRange::X),
+                    _ => errors.add(virdant_error_at!("Unknown out_of_range \"{policy}\" on {moddef_id}. Expected \"zero\" or \"x\"", span)),
+                }
+            },
+            ("out_of_range", _) => {
+                errors.add(virdant_error_at!("Malformed or duplicate attribute #[{attr_name}] on {moddef_id}", span));
+            },
+            _ => errors.add(virdant_error_at!("Unknown attribute #[{attr_name}] on {moddef_id}", span)),
+        }
+    }
+    errors.check()?;
+    Ok(out_of_range.unwrap_or_default())
+}
+
+/// Reads the attributes of an `ext mod`:
+///
+/// * `#[source("path/to/File.v")]` - the Verilog source, relative to the package's directory.
+///   Defaults to `ext/{Name}.v`.
+/// * `#[module("VerilogName")]` - the name of the Verilog module. Defaults to the name of the moddef.
+/// * `#[param(NAME = value, ...)]` - Verilog parameters to instantiate the module with.
+fn structure_ext_binding(db: &dyn StructureQ, moddef_id: ModDefId, moddef_ast: &ast::ModDef) -> VirdantResult<ExtBinding> {
+    let mut errors = ErrorReport::new();
+    let moddef_name = moddef_id.name();
+
+    let mut source: Option<String> = None;
+    let mut verilog_name: Option<String> = None;
+    let mut params: Vec<(Ident, ast::AttrValue)> = vec![];
+
+    for attr in &moddef_ast.attrs {
+        let span = db.span(attr.span());
+        let ast::Attr(attr_name, args) = attr.as_ref();
+        match (attr_name.as_str(), args.as_slice()) {
+            ("source", [ast::AttrArg::Positional(ast::AttrValue::Str(path))]) if source.is_none() => {
+                source = Some(path.clone());
+            },
+            ("module", [ast::AttrArg::Positional(ast::AttrValue::Str(name))]) if verilog_name.is_none() => {
+                verilog_name = Some(name.clone());
+            },
+            ("param", args) => {
+                for arg in args {
+                    match arg {
+                        ast::AttrArg::Named(param, value) => {
+                            if params.iter().any(|(name, _value)| name == param) {
+                                errors.add(virdant_error_at!("Duplicate param {param} on {moddef_name}", span.clone()));
+                            }
+                            params.push((param.clone(), value.clone()));
+                        },
+                        ast::AttrArg::Positional(_value) => {
+                            errors.add(virdant_error_at!("Params must be given as NAME = value", span.clone()));
+                        },
+                    }
+                }
+            },
+            ("source", _) | ("module", _) => {
+                errors.add(virdant_error_at!("Malformed or duplicate attribute #[{attr_name}] on {moddef_name}", span));
+            },
+            _ => {
+                errors.add(virdant_error_at!("Unknown attribute #[{attr_name}] on {moddef_name}", span));
+            },
+        }
+    }
+    errors.check()?;
+
+    let source = source.unwrap_or_else(|| format!("ext/{moddef_name}.v"));
+    let source = package_path(db, moddef_id.package(), source);
+
+    Ok(ExtBinding {
+        source,
+        verilog_name: verilog_name.unwrap_or_else(|| moddef_name.to_string()),
+        params,
+    })
+}
+
+/// Paths in a package are relative to the package's directory.
+fn package_path(db: &dyn StructureQ, package_id: PackageId, path: String) -> std::path::PathBuf {
+    match db.source_dirs().get(package_id.name().as_str()) {
+        Some(dir) => dir.join(path),
+        None => path.into(),
+    }
+}
@@ -3,36 +3,169 @@ use crate::ast::Ast;
 use crate::ast;
 use crate::virdant_error;
 use super::*;
+use super::item_resolution::const_nat;
 
 #[salsa::query_group(TypeResolutionQStorage)]
 pub trait TypeResolutionQ: item_dependency::ItemDependencyQ {
     fn resolve_typ(&self, typ: Ast<ast::Type>, from: PackageId) -> VirdantResult<Type>;
+    fn resolve_moddef_typ(&self, typ: Ast<ast::Type>, moddef: ModDefId) -> VirdantResult<Type>;
+    fn resolve_typedef_typ(&self, typ: Ast<ast::Type>, within: Type) -> VirdantResult<Type>;
 
-    fn method_sig(&self, typ: Type, method: Ident) -> VirdantResult<MethodSig>;
+    fn method_sig(&self, typ: Type, method: Ident, width: Option<Width>) -> VirdantResult<MethodSig>;
     fn ctor_sig(&self, typ: Type, ctor: Ident) -> VirdantResult<CtorSig>;
+    fn fndef_sig(&self, fndef: FnDefId) -> VirdantResult<FnSig>;
+    fn constdef_typ(&self, constdef: ConstDefId) -> VirdantResult<Type>;
+    fn enumdef_width(&self, enumdef: EnumDefId) -> VirdantResult<Width>;
+    fn enumdef_values(&self, enumdef: EnumDefId) -> VirdantResult<Vec<(Ident, u64)>>;
 
     fn component_typ(&self, element_id: ElementId) -> VirdantResult<Type>;
 }
 
 fn resolve_typ(db: &dyn TypeResolutionQ, typ: Ast<ast::Type>, from: PackageId) -> VirdantResult<Type> {
+    resolve_typ_in(db, typ, from, &[])
+}
+
+/// Resolves a type which appears inside of a moddef, where it may mention the moddef's parameters.
+fn resolve_moddef_typ(db: &dyn TypeResolutionQ, typ: Ast<ast::Type>, moddef_id: ModDefId) -> VirdantResult<Type> {
+    let params: Vec<(Ident, TypeArg)> = db.moddef_param_values(moddef_id.clone())?
+        .into_iter()
+        .map(|(param, value)| (param, TypeArg::Nat(value)))
+        .collect();
+    resolve_typ_in(db, typ, moddef_id.package(), &params)
+}
+
+/// Resolves a type which appears in the definition of a struct or union type,
+/// where it may mention the type parameters, which take their values from the arguments of `within`.
+fn resolve_typedef_typ(db: &dyn TypeResolutionQ, typ: Ast<ast::Type>, within: Type) -> VirdantResult<Type> {
+    let (item_id, params, args) = match &within {
+        Type::Struct(structdef_id, args) => {
+            (structdef_id.as_item(), db.structdef_ast(structdef_id.clone())?.params.clone(), args.clone())
+        },
+        Type::Union(uniondef_id, args) => {
+            (uniondef_id.as_item(), db.uniondef_ast(uniondef_id.clone())?.params.clone(), args.clone())
+        },
+        _ => return Err(virdant_error!("Type {within} is not a struct or union type")),
+    };
+    if params.len() != args.len() {
+        let n = params.len();
+        let m = args.len();
+        return Err(virdant_error!("Type {item_id} takes {n} arguments, but {within} gives {m}"));
+    }
+    let params: Vec<(Ident, TypeArg)> = params.into_iter().zip(args).collect();
+    resolve_typ_in(db, typ, item_id.package(), &params)
+}
+
+fn resolve_typ_in(db: &dyn TypeResolutionQ, typ: Ast<ast::Type>, from: PackageId, params: &[(Ident, TypeArg)]) -> VirdantResult<Type> {
     Ok(match typ.as_ref() {
         ast::Type::Clock => Type::Clock,
-        ast::Type::Word(w) => Type::Word(*w),
-        ast::Type::Vec(_, _) => todo!(),
-        ast::Type::TypeRef(path) => {
-            let typ_args = vec![];
+        ast::Type::Word(nat) => Type::Word(resolve_nat_in(db, nat, from.clone(), params)?),
+        ast::Type::Vec(typ, n) => {
+            let elem_typ = resolve_typ_in(db, typ.clone(), from.clone(), params)?;
+            Type::Vec(Arc::new(elem_typ), resolve_nat_in(db, n, from, params)?)
+        },
+        ast::Type::TypeRef(path, args) => {
+            if let (None, true) = (path.namespace(), args.is_empty()) {
+                match lookup_param(&path.name(), params) {
+                    Some(TypeArg::Type(typ)) => return Ok(typ.as_ref().clone()),
+                    Some(TypeArg::Nat(_n)) => return Err(virdant_error!("Parameter {path} is a Nat, not a type")),
+                    None => (),
+                }
+            }
+
+            let mut typ_args = vec![];
+            for arg in args {
+                typ_args.push(resolve_typ_arg(db, arg, from.clone(), params)?);
+            }
+
+            let (item_id, typ_params) = match db.item(path.clone(), from)? {
+                ItemId::UnionDef(uniondef) => {
+                    let typ_params = db.uniondef_ast(uniondef.clone())?.params.clone();
+                    (uniondef.as_item(), typ_params)
+                },
+                ItemId::StructDef(structdef) => {
+                    let typ_params = db.structdef_ast(structdef.clone())?.params.clone();
+                    (structdef.as_item(), typ_params)
+                },
+                ItemId::EnumDef(enumdef) => (enumdef.as_item(), vec![]),
+                item_id => return Err(virdant_error!("Item {item_id} is not a type")),
+            };
+
+            if typ_params.len() != typ_args.len() {
+                let n = typ_params.len();
+                let m = typ_args.len();
+                return Err(virdant_error!("Type {item_id} takes {n} arguments, but {m} were given"));
+            }
 
-            match db.item(path.clone(), from)? {
-                ItemId::ModDef(_) => todo!(),
+            match item_id {
                 ItemId::UnionDef(uniondef) => Type::Union(uniondef, typ_args),
                 ItemId::StructDef(structdef) => Type::Struct(structdef, typ_args),
-                ItemId::PortDef(_) => todo!(),
+                ItemId::EnumDef(enumdef) => Type::Enum(enumdef),
+                _ => unreachable!(),
             }
         },
     })
 }
 
-fn method_sig(_db: &dyn TypeResolutionQ, typ: Type, method: Ident) -> VirdantResult<MethodSig> {
+fn resolve_typ_arg(db: &dyn TypeResolutionQ, arg: &ast::TypeArg, from: PackageId, params: &[(Ident, TypeArg)]) -> VirdantResult<TypeArg> {
+    match arg {
+        ast::TypeArg::Nat(nat) => Ok(TypeArg::Nat(resolve_nat_in(db, nat, from.clone(), params)?)),
+        ast::TypeArg::Type(typ) => {
+            // A bare name may be a Nat parameter or a constant, as in `Buffer[W]`.
+            if let ast::Type::TypeRef(path, args) = typ.as_ref() {
+                if args.is_empty() {
+                    if path.namespace().is_none() {
+                        if let Some(TypeArg::Nat(n)) = lookup_param(&path.name(), params) {
+                            return Ok(TypeArg::Nat(n));
+                        }
+                    }
+                    if let Ok(ItemId::ConstDef(constdef_id)) = db.item(path.clone(), from.clone()) {
+                        return Ok(TypeArg::Nat(db.const_value(constdef_id)?));
+                    }
+                }
+            }
+            Ok(TypeArg::Type(Arc::new(resolve_typ_in(db, typ.clone(), from, params)?)))
+        },
+    }
+}
+
+fn resolve_nat_in(db: &dyn TypeResolutionQ, nat: &ast::Nat, from: PackageId, params: &[(Ident, TypeArg)]) -> VirdantResult<u64> {
+    match nat {
+        ast::Nat::Lit(n) => Ok(*n),
+        ast::Nat::Param(param) => match lookup_param(param, params) {
+            Some(TypeArg::Nat(n)) => Ok(n),
+            Some(TypeArg::Type(_typ)) => Err(virdant_error!("Parameter {param} is a type, not a Nat")),
+            None => const_nat(db, param.clone().into(), from)
+                .map_err(|err| virdant_error!("Unknown parameter {param}").because(err)),
+        },
+        ast::Nat::Const(name) => const_nat(db, name.clone(), from),
+    }
+}
+
+fn lookup_param(name: &Ident, params: &[(Ident, TypeArg)]) -> Option<TypeArg> {
+    params.iter().find(|(param, _arg)| param == name).map(|(_param, arg)| arg.clone())
+}
+
+/// Methods which take a width, written `x->zext[16]()`.
+pub(crate) fn is_width_method(method: &Ident) -> bool {
+    ["zext", "sext", "trunc", "slice"].contains(&method.as_str())
+}
+
+/// The width of a dynamic index into `n` bits or elements, as in `x->get(i)`.
+pub(crate) fn index_width(n: u64) -> Width {
+    clog2(n).max(1)
+}
+
+fn method_sig(db: &dyn TypeResolutionQ, typ: Type, method: Ident, width: Option<Width>) -> VirdantResult<MethodSig> {
+    match (&typ, width) {
+        (Type::Word(_n), Some(_m)) if !is_width_method(&method) => {
+            return Err(virdant_error!("Method {method} does not take a width"));
+        },
+        (Type::Word(_n), None) if is_width_method(&method) => {
+            return Err(virdant_error!("Method {method} needs a width, as in {method}[8]"));
+        },
+        _ => (),
+    }
+
     match typ {
         Type::Word(n) => {
             if method == "add".into() {
@@ -45,31 +178,59 @@ fn method_sig(_db: &dyn TypeResolutionQ, typ: Type, method: Ident) -> VirdantRes
                 Ok(MethodSig(vec![typ.clone()], typ.clone()))
             } else if method == "srl".into() {
                 Ok(MethodSig(vec![typ.clone()], typ.clone()))
+            } else if method == "sra".into() {
+                Ok(MethodSig(vec![typ.clone()], typ.clone()))
             } else if method == "sub".into() {
                 Ok(MethodSig(vec![typ.clone()], typ.clone()))
+            } else if method == "mul".into() || method == "muls".into() {
+                Ok(MethodSig(vec![typ.clone()], Type::Word(2 * n)))
             } else if method == "and".into() {
                 Ok(MethodSig(vec![typ.clone()], typ.clone()))
             } else if method == "or".into() {
                 Ok(MethodSig(vec![typ.clone()], typ.clone()))
             } else if method == "xor".into() {
                 Ok(MethodSig(vec![typ.clone()], typ.clone()))
-            } else if method == "lt".into() {
-                Ok(MethodSig(vec![typ.clone()], Type::Word(1)))
-            } else if method == "lte".into() {
-                Ok(MethodSig(vec![typ.clone()], Type::Word(1)))
-            } else if method == "gt".into() {
-                Ok(MethodSig(vec![typ.clone()], Type::Word(1)))
-            } else if method == "gte".into() {
+            } else if [
+                "lt", "lte", "gt", "gte",
+                "ltu", "lteu", "gtu", "gteu",
+                "lts", "ltes", "gts", "gtes",
+            ].contains(&method.as_str()) {
                 Ok(MethodSig(vec![typ.clone()], Type::Word(1)))
             } else if method == "eq".into() {
                 Ok(MethodSig(vec![typ.clone()], Type::Word(1)))
             } else if method == "neq".into() {
                 Ok(MethodSig(vec![typ.clone()], Type::Word(1)))
-            } else if method == "not".into() {
+            } else if method == "not".into() || method == "neg".into() {
                 Ok(MethodSig(vec![], typ.clone()))
-            } else if is_pow2(n) && method == "get".into() {
-                let argtyp = Type::Word(clog2(n));
-                Ok(MethodSig(vec![argtyp.clone()], Type::Word(1)))
+            } else if method == "zext".into() || method == "sext".into() {
+                let m = width.unwrap();
+                if m < n {
+                    return Err(virdant_error!("Can't {method} a Word[{n}] to the smaller Word[{m}]"));
+                }
+                Ok(MethodSig(vec![], Type::Word(m)))
+            } else if method == "trunc".into() {
+                let m = width.unwrap();
+                if m > n || m == 0 {
+                    return Err(virdant_error!("Can't trunc a Word[{n}] to Word[{m}]"));
+                }
+                Ok(MethodSig(vec![], Type::Word(m)))
+            } else if method == "get".into() {
+                Ok(MethodSig(vec![Type::Word(index_width(n))], Type::Word(1)))
+            } else if method == "slice".into() {
+                let m = width.unwrap();
+                if m > n || m == 0 {
+                    return Err(virdant_error!("Can't slice a Word[{m}] out of a Word[{n}]"));
+                }
+                Ok(MethodSig(vec![Type::Word(index_width(n))], Type::Word(m)))
+            } else {
+                Err(virdant_error!("No such method {method} for type {typ}"))
+            }
+        },
+        Type::Enum(ref enumdef) => {
+            if method == "eq".into() || method == "neq".into() {
+                Ok(MethodSig(vec![typ.clone()], Type::Word(1)))
+            } else if method == "bits".into() {
+                Ok(MethodSig(vec![], Type::Word(db.enumdef_width(enumdef.clone())?)))
             } else {
                 Err(virdant_error!("No such method {method} for type {typ}"))
             }
@@ -79,6 +240,15 @@ fn method_sig(_db: &dyn TypeResolutionQ, typ: Type, method: Ident) -> VirdantRes
 }
 
 fn ctor_sig(db: &dyn TypeResolutionQ, typ: Type, ctor: Ident) -> VirdantResult<CtorSig> {
+    // The values of an enum are ctors which take no arguments.
+    if let Type::Enum(enumdef) = &typ {
+        if db.enumdef_values(enumdef.clone())?.iter().any(|(name, _value)| name == &ctor) {
+            return Ok(CtorSig(vec![], typ));
+        } else {
+            return Err(virdant_error!("Unknown value: {ctor} on type {typ}"));
+        }
+    }
+
     let uniondef = if let Type::Union(uniondef, _args) = &typ {
         uniondef
     } else {
@@ -91,7 +261,7 @@ fn ctor_sig(db: &dyn TypeResolutionQ, typ: Type, ctor: Ident) -> VirdantResult<C
         if ctor_name == &ctor {
             let mut arg_typs = vec![];
             for ast_arg_typ in ast_arg_typs {
-                let resolved_arg_typ = db.resolve_typ(ast_arg_typ.clone(), uniondef.package())?;
+                let resolved_arg_typ = db.resolve_typedef_typ(ast_arg_typ.clone(), typ.clone())?;
                 arg_typs.push(resolved_arg_typ);
             }
             return Ok(CtorSig(arg_typs, typ));
@@ -101,6 +271,51 @@ fn ctor_sig(db: &dyn TypeResolutionQ, typ: Type, ctor: Ident) -> VirdantResult<C
     Err(virdant_error!("Unknown ctor: {ctor} on type {typ}"))
 }
 
+fn fndef_sig(db: &dyn TypeResolutionQ, fndef_id: FnDefId) -> VirdantResult<FnSig> {
+    let fndef_ast = db.fndef_ast(fndef_id.clone())?;
+    let mut arg_typs = vec![];
+    for (_arg, arg_typ) in &fndef_ast.args {
+        arg_typs.push(db.resolve_typ(arg_typ.clone(), fndef_id.package())?);
+    }
+    let ret_typ = db.resolve_typ(fndef_ast.ret.clone(), fndef_id.package())?;
+    Ok(FnSig(arg_typs, ret_typ))
+}
+
+fn constdef_typ(db: &dyn TypeResolutionQ, constdef_id: ConstDefId) -> VirdantResult<Type> {
+    let constdef_ast = db.constdef_ast(constdef_id.clone())?;
+    db.resolve_typ(constdef_ast.typ.clone(), constdef_id.package())
+}
+
+fn enumdef_width(db: &dyn TypeResolutionQ, enumdef_id: EnumDefId) -> VirdantResult<Width> {
+    let enumdef_ast = db.enumdef_ast(enumdef_id.clone())?;
+    match db.resolve_typ(enumdef_ast.typ.clone(), enumdef_id.package())? {
+        Type::Word(n) => Ok(n),
+        typ => Err(virdant_error!("Enum {enumdef_id} must be encoded as a Word, not {typ}")),
+    }
+}
+
+/// The encodings of the values of an enum, in the order they are declared.
+fn enumdef_values(db: &dyn TypeResolutionQ, enumdef_id: EnumDefId) -> VirdantResult<Vec<(Ident, u64)>> {
+    let enumdef_ast = db.enumdef_ast(enumdef_id.clone())?;
+    let width = db.enumdef_width(enumdef_id.clone())?;
+    let mut errors = ErrorReport::new();
+    let mut values: Vec<(Ident, u64)> = vec![];
+
+    for ast::EnumValue(name, nat) in &enumdef_ast.values {
+        let value = resolve_nat_in(db, nat, enumdef_id.package(), &[])?;
+        if width < 64 && value >> width != 0 {
+            errors.add(virdant_error!("Value {name} of {enumdef_id} does not fit in Word[{width}]: {value}"));
+        }
+        if let Some((other, _value)) = values.iter().find(|(_other, other_value)| *other_value == value) {
+            errors.add(virdant_error!("Values {other} and {name} of {enumdef_id} have the same encoding: {value}"));
+        }
+        values.push((name.clone(), value));
+    }
+
+    errors.check()?;
+    Ok(values)
+}
+
 fn component_typ(db: &dyn TypeResolutionQ, element_id: ElementId) -> VirdantResult<Type> {
     let item_ast = db.item_ast(element_id.item()).unwrap();
 
@@ -108,14 +323,17 @@ fn component_typ(db: &dyn TypeResolutionQ, element_id: ElementId) -> VirdantResu
         for decl in &moddef_ast.decls {
             if let ast::Decl::Component(simplecomponent) = decl {
                 if simplecomponent.name == element_id.name() {
-                    let typ = db.resolve_typ(simplecomponent.typ.clone(), element_id.item().package())?;
+                    let typ = match element_id.item() {
+                        ItemId::ModDef(moddef_id) => db.resolve_moddef_typ(simplecomponent.typ.clone(), moddef_id)?,
+                        item_id => db.resolve_typ(simplecomponent.typ.clone(), item_id.package())?,
+                    };
                     return Ok(typ);
                 }
             }
         }
     } else if let ast::Item::PortDef(portdef_ast) = item_ast {
         for channel in &portdef_ast.channels {
-            let ast::Channel(dir, channel_name, typ) = channel;
+            let ast::Channel(_dir, channel_name, typ) = channel;
             if channel_name == &element_id.name() {
                 let typ = db.resolve_typ(typ.clone(), element_id.item().package())?;
                 return Ok(typ);
@@ -125,3 +343,111 @@ fn component_typ(db: &dyn TypeResolutionQ, element_id: ElementId) -> VirdantResu
 
     Err(virdant_error!("Unknown component: {element_id} could not resolve type"))
 }
+
+#[test]
+fn width_methods() {
+    use super::check::CheckQ;
+
+    let check = |expr: &str| {
+        let mut db = Db::new();
+        db.set_source("top", &format!("
+            mod Top {{
+                incoming a : Word[8];
+                outgoing out : Word[16];
+                out := {expr};
+            }}
+        "));
+        db.check()
+    };
+
+    assert!(check("a->zext[16]()").is_ok());
+    assert!(check("a->sext()").is_ok());
+    assert!(check("a->zext[16]()->trunc[4]()->zext()").is_ok());
+    assert!(check("a->mul(a)").is_ok());
+    assert!(check("a->trunc[16]()").is_err());
+    assert!(check("a->zext[4]()->zext()").is_err());
+    assert!(check("a->add[16](a)").is_err());
+}
+
+#[test]
+fn generic_types() {
+    use super::check::CheckQ;
+    use super::layout::LayoutQ;
+    use super::item_resolution::ItemResolutionQ;
+    use super::resolve::ResolveQ;
+
+    let db_for = |typ: &str| {
+        let mut db = Db::new();
+        db.set_source("top", &format!("
+            union type Valid[T] {{
+                Invalid();
+                Valid(T);
+            }}
+
+            struct type Pair[T, N] {{
+                fst : T;
+                snd : Word[N];
+            }}
+
+            mod Top {{
+                incoming a : {typ};
+                outgoing out : {typ};
+                out := a;
+            }}
+        "));
+        db
+    };
+
+    let bitwidth = |typ: &str| {
+        let db = db_for(typ);
+        db.check()?;
+        let moddef_id = db.moddef(Ident::from("Top").into(), db.resolve_package("top".into())?)?;
+        let component_id = db.resolve_component_by_path(moddef_id, "a".into())?;
+        db.bitwidth(db.component_typ(component_id)?)
+    };
+
+    assert_eq!(bitwidth("Valid[Word[8]]").unwrap(), 9);
+    assert_eq!(bitwidth("Valid[Valid[Word[3]]]").unwrap(), 5);
+    assert_eq!(bitwidth("Pair[Valid[Word[2]], 4]").unwrap(), 7);
+    assert!(bitwidth("Valid").is_err());
+    assert!(bitwidth("Valid[Word[8], 8]").is_err());
+    assert!(bitwidth("Pair[4, Word[4]]").is_err());
+}
+
+#[test]
+fn enum_values() {
+    use super::item_resolution::ItemResolutionQ;
+    use super::resolve::ResolveQ;
+
+    let typ_for = |values: &str| -> VirdantResult<(Db, Type)> {
+        let mut db = Db::new();
+        db.set_source("top", &format!("
+            enum type Op : Word[2] {{
+                {values}
+            }}
+
+            mod Top {{
+                incoming op : Op;
+            }}
+        "));
+        let moddef_id = db.moddef(Ident::from("Top").into(), db.resolve_package("top".into())?)?;
+        let typ = db.component_typ(db.resolve_component_by_path(moddef_id, "op".into())?)?;
+        Ok((db, typ))
+    };
+    let values = |values: &str| -> VirdantResult<Vec<(Ident, u64)>> {
+        let (db, typ) = typ_for(values)?;
+        let Type::Enum(enumdef_id) = typ else { unreachable!() };
+        db.enumdef_values(enumdef_id)
+    };
+
+    assert_eq!(values("ADD = 0; SUB = 3;").unwrap(), vec![("ADD".into(), 0), ("SUB".into(), 3)]);
+    let err = format!("{:?}", values("ADD = 0; SUB = 0;").unwrap_err());
+    assert!(err.contains("Values ADD and SUB of top::Op have the same encoding: 0"));
+    let err = format!("{:?}", values("ADD = 0; SUB = 4;").unwrap_err());
+    assert!(err.contains("Value SUB of top::Op does not fit in Word[2]: 4"));
+
+    let (db, typ) = typ_for("ADD = 0; SUB = 3;").unwrap();
+    assert!(db.ctor_sig(typ.clone(), "SUB".into()).is_ok());
+    let err = format!("{:?}", db.ctor_sig(typ, "MUL".into()).unwrap_err());
+    assert!(err.contains("Unknown value: MUL on type top::Op"));
+}
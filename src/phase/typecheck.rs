@@ -5,14 +5,20 @@ use crate::ast;
 use crate::virdant_error;
 use crate::virdant_error_at;
 use super::*;
+use super::type_resolution::{is_width_method, index_width};
+use super::sourceq::Span;
+use std::collections::{HashMap, HashSet};
 
 #[salsa::query_group(TypecheckQStorage)]
-pub trait TypecheckQ: type_resolution::TypeResolutionQ {
-    fn typecheck_expr(&self, moddef: ModDefId, expr: Ast<ast::Expr>, typ: Type, ctx: Context<Ident, Type>) -> VirdantResult<Arc<TypedExpr>>;
-    fn typeinfer_expr(&self, moddef: ModDefId, expr: Ast<ast::Expr>, ctx: Context<Ident, Type>) -> VirdantResult<Arc<TypedExpr>>;
+pub trait TypecheckQ: layout::LayoutQ {
+    /// Typechecks an expression in the body of a moddef or fn def.
+    fn typecheck_expr(&self, item: ItemId, expr: Ast<ast::Expr>, typ: Type, ctx: Context<Ident, Type>) -> VirdantResult<Arc<TypedExpr>>;
+    fn typeinfer_expr(&self, item: ItemId, expr: Ast<ast::Expr>, ctx: Context<Ident, Type>) -> VirdantResult<Arc<TypedExpr>>;
 
     fn moddef_reference_type(&self, moddef: ModDefId, target: Path) -> VirdantResult<Type>;
 
+    fn fndef_body(&self, fndef: FnDefId) -> VirdantResult<Arc<TypedExpr>>;
+
     fn typecheck_moddef(&self, moddef: ModDefId) -> VirdantResult<()>;
     fn typecheck(&self, moddef: ModDefId) -> VirdantResult<()>;
 }
@@ -25,10 +31,17 @@ pub enum TypedExpr {
     Struct(Type, Option<QualIdent>, Vec<(Ident, Arc<TypedExpr>)>),
     MethodCall(Type, Arc<TypedExpr>, Ident, Vec<Arc<TypedExpr>>),
     Ctor(Type, Ident, Vec<Arc<TypedExpr>>),
+    FnCall(Type, FnDefId, Vec<Arc<TypedExpr>>),
+    /// A conversion from a word to a type of the same width, as in `Op::from_bits(w)`.
+    FromBits(Type, Arc<TypedExpr>),
+    /// A read from a `mem`, written `m->read(addr)`,
+    /// or `m->read_sync(addr)` for a read whose data arrives on the next clock cycle.
+    MemRead(Type, ComponentId, Arc<TypedExpr>, bool),
     As(Type, Arc<TypedExpr>, Ast<ast::Type>),
     Idx(Type, Arc<TypedExpr>, StaticIndex),
     IdxRange(Type, Arc<TypedExpr>, StaticIndex, StaticIndex),
     Cat(Type, Vec<Arc<TypedExpr>>),
+    With(Type, Arc<TypedExpr>, Vec<(ast::Update, Arc<TypedExpr>)>),
     If(Type, Arc<TypedExpr>, Arc<TypedExpr>, Arc<TypedExpr>),
     Let(Type, Ident, Option<Ast<ast::Type>>, Arc<TypedExpr>, Arc<TypedExpr>),
     Match(Type, Arc<TypedExpr>, Option<Arc<Type>>, Vec<TypedMatchArm>),
@@ -39,6 +52,9 @@ pub enum Referent {
     Local(Ident),
     LocalComponent(ElementId),
     NonLocalComponent(ElementId, ElementId),
+    /// A channel of a port, as in `inc.out`, or of a port of a submodule, as in `incrementer.inc.out`.
+    PortChannel(Path),
+    Const(ConstDefId),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -92,10 +108,14 @@ impl TypedExpr {
             TypedExpr::Struct(typ, _, _) => typ.clone(),
             TypedExpr::MethodCall(typ, _, _, _) => typ.clone(),
             TypedExpr::Ctor(typ, _, _) => typ.clone(),
+            TypedExpr::FnCall(typ, _, _) => typ.clone(),
+            TypedExpr::FromBits(typ, _) => typ.clone(),
+            TypedExpr::MemRead(typ, _, _, _) => typ.clone(),
             TypedExpr::As(typ, _, _) => typ.clone(),
             TypedExpr::Idx(typ, _, _) => typ.clone(),
             TypedExpr::IdxRange(typ, _, _, _) => typ.clone(),
             TypedExpr::Cat(typ, _) => typ.clone(),
+            TypedExpr::With(typ, _, _) => typ.clone(),
             TypedExpr::If(typ, _, _, _) => typ.clone(),
             TypedExpr::Let(typ, _x, _ascription, _e, _b) => typ.clone(),
             TypedExpr::Match(typ, _subject, _ascription, _arms) => typ.clone(),
@@ -113,15 +133,16 @@ fn pow(n: u64, k: u64) -> u64 {
 
 fn typecheck_expr(
     db: &dyn TypecheckQ,
-    moddef_id: ModDefId,
+    item_id: ItemId,
     expr: Ast<ast::Expr>,
     typ: Type,
     ctx: Context<Ident, Type>,
 ) -> VirdantResult<Arc<TypedExpr>> {
     let span = db.span(expr.span());
+    let expr = infer_widths(db, &item_id, expr, &typ, &ctx)?;
     match expr.as_ref() {
         ast::Expr::Reference(path) => {
-            let expr = db.typeinfer_expr(moddef_id, expr.clone(), ctx)?;
+            let expr = db.typeinfer_expr(item_id, expr.clone(), ctx)?;
             let actual_typ = expr.typ();
             if typ != actual_typ {
                 Err(virdant_error_at!("Wrong types: {path} is {typ} vs {actual_typ}", span))
@@ -130,6 +151,15 @@ fn typecheck_expr(
             }
 
         },
+        ast::Expr::QualReference(name) => {
+            let expr = db.typeinfer_expr(item_id, expr.clone(), ctx)?;
+            let actual_typ = expr.typ();
+            if typ != actual_typ {
+                Err(virdant_error_at!("Wrong types: {name} is {typ} vs {actual_typ}", span))
+            } else {
+                Ok(expr)
+            }
+        },
         ast::Expr::Word(lit) => {
             match (typ.clone(), lit.width) {
                 (Type::Word(n), Some(m)) if n == m => Ok(TypedExpr::Word(typ, lit.clone()).into()),
@@ -148,18 +178,33 @@ fn typecheck_expr(
         ast::Expr::Struct(structname, fields) => {
             let mut typed_fields = vec![];
             for (fieldname, expr) in fields {
-                let typed_expr = db.typeinfer_expr(moddef_id.clone(), expr.clone(), ctx.clone())?;
+                let typed_expr = db.typeinfer_expr(item_id.clone(), expr.clone(), ctx.clone())?;
                 typed_fields.push((fieldname.clone(), typed_expr));
             }
             Ok(TypedExpr::Struct(typ, structname.clone(), typed_fields).into())
         },
-        ast::Expr::MethodCall(subject, method, args) => {
-            let typed_subject = db.typeinfer_expr(moddef_id.clone(), subject.clone(), ctx.clone())?;
+        ast::Expr::MethodCall(subject, _method, _width, _args) if mem_subject(db, &item_id, subject, &ctx)?.is_some() => {
+            let typed_expr = db.typeinfer_expr(item_id, expr.clone(), ctx)?;
+            let actual_typ = typed_expr.typ();
+            if typ != actual_typ {
+                return Err(virdant_error_at!("Wrong types: memory read gives {actual_typ}, not {typ}", span));
+            }
+            Ok(typed_expr)
+        },
+        ast::Expr::MethodCall(subject, method, width, args) => {
+            let typed_subject = db.typeinfer_expr(item_id.clone(), subject.clone(), ctx.clone())?;
             let subject_typ = typed_subject.typ();
 
-            let sig = match db.method_sig(typed_subject.typ(), method.clone()) {
+            // Methods like zext[m] may leave their width to be taken from the expected type.
+            let width = match (width, &typ) {
+                (None, Type::Word(m)) if is_width_method(method) => Some(*m),
+                (None, Type::Vec(_, m)) if method.as_str() == "slice" => Some(*m),
+                _ => *width,
+            };
+
+            let sig = match method_sig(db, typed_subject.typ(), method.clone(), width) {
                 Ok(sig) => sig,
-                Err(_e) => return Err(virdant_error_at!("No such method `{method}` on type {subject_typ}", span)),
+                Err(e) => return Err(virdant_error_at!("No such method `{method}` on type {subject_typ}", span).because(e)),
             };
 
             let MethodSig(arg_types, ret_type) = sig;
@@ -174,7 +219,7 @@ fn typecheck_expr(
 
             let mut typed_args = vec![];
             for (arg, arg_type) in args.iter().zip(arg_types) {
-                let typed_arg = db.typecheck_expr(moddef_id.clone(), arg.clone(), arg_type, ctx.clone())?;
+                let typed_arg = db.typecheck_expr(item_id.clone(), arg.clone(), arg_type, ctx.clone())?;
                 typed_args.push(typed_arg);
             }
 
@@ -187,22 +232,34 @@ fn typecheck_expr(
             }
             let mut typed_args = vec![];
             for (arg, arg_typ) in args.iter().zip(arg_types) {
-                let typed_arg = db.typecheck_expr(moddef_id.clone(), arg.clone(), arg_typ.clone(), ctx.clone())?;
+                let typed_arg = db.typecheck_expr(item_id.clone(), arg.clone(), arg_typ.clone(), ctx.clone())?;
                 typed_args.push(typed_arg);
             }
             Ok(TypedExpr::Ctor(typ, ctor.clone(), typed_args).into())
         },
+        ast::Expr::FnCall(name, args) => {
+            let typed_expr = match from_bits_typ(db, &item_id, name, Some(&typ), span.clone())? {
+                Some(from_bits_typ) => typecheck_from_bits(db, item_id.clone(), span.clone(), name, from_bits_typ, args, ctx)?,
+                None => db.typeinfer_expr(item_id.clone(), expr.clone(), ctx)?,
+            };
+            let actual_typ = typed_expr.typ();
+            if typ != actual_typ {
+                return Err(virdant_error_at!("Wrong types: {name}(...) returns {actual_typ}, not {typ}", span));
+            }
+            Ok(typed_expr)
+        },
         ast::Expr::As(subject, expected_typ) => {
-            let expected_type_resolved = db.resolve_typ(expected_typ.clone(), moddef_id.package())?;
-            let typed_subject = db.typecheck_expr(moddef_id.clone(), subject.clone(), expected_type_resolved.clone(), ctx)?;
+            let expected_type_resolved = resolve_item_typ(db, expected_typ.clone(), item_id.clone())?;
+            let typed_subject = db.typecheck_expr(item_id.clone(), subject.clone(), expected_type_resolved.clone(), ctx)?;
             if expected_type_resolved != typ {
                 return Err(virdant_error_at!("Ascription failed: {expected_type_resolved} is not the same as {typ}", span));
             } else {
                 Ok(TypedExpr::As(expected_type_resolved, typed_subject.clone(), expected_typ.clone()).into())
             }
         },
+        ast::Expr::IndexedReference(_name, _rest) => db.typeinfer_expr(item_id, expr.clone(), ctx),
         ast::Expr::Idx(subject, i) => {
-            let typed_expr = db.typeinfer_expr(moddef_id, expr.clone(), ctx)?;
+            let typed_expr = db.typeinfer_expr(item_id, expr.clone(), ctx)?;
             if typed_expr.typ() != typ {
                 Err(virdant_error_at!("UH OH: Idx: {subject:?}[{i}]", span))
             } else {
@@ -210,15 +267,15 @@ fn typecheck_expr(
             }
         },
         ast::Expr::IdxRange(subject, j, i) => {
-            let typed_expr = db.typeinfer_expr(moddef_id, expr.clone(), ctx)?;
+            let typed_expr = db.typeinfer_expr(item_id, expr.clone(), ctx)?;
             if typed_expr.typ() != typ {
                 Err(virdant_error_at!("UH OH: IdxRange: {subject:?}[{j}..{i}]", span))
             } else {
                 Ok(typed_expr)
             }
         },
-        ast::Expr::Cat(_) => {
-            let typed_expr = db.typeinfer_expr(moddef_id, expr.clone(), ctx)?;
+        ast::Expr::Cat(_) | ast::Expr::With(_, _) => {
+            let typed_expr = db.typeinfer_expr(item_id, expr.clone(), ctx)?;
             let actual_typ = typed_expr.typ();
             if typ != actual_typ {
                 Err(virdant_error_at!("Wrong types: {typ} vs {actual_typ}", span))
@@ -227,78 +284,31 @@ fn typecheck_expr(
             }
         },
         ast::Expr::If(c, a, b) => {
-            let typed_c = db.typecheck_expr(moddef_id.clone(), c.clone(), Type::Word(1), ctx.clone())?;
-            let typed_a = db.typecheck_expr(moddef_id.clone(), a.clone(), typ.clone(), ctx.clone())?;
-            let typed_b = db.typecheck_expr(moddef_id.clone(), b.clone(), typ.clone(), ctx.clone())?;
+            let typed_c = db.typecheck_expr(item_id.clone(), c.clone(), Type::Word(1), ctx.clone())?;
+            let typed_a = db.typecheck_expr(item_id.clone(), a.clone(), typ.clone(), ctx.clone())?;
+            let typed_b = db.typecheck_expr(item_id.clone(), b.clone(), typ.clone(), ctx.clone())?;
             Ok(TypedExpr::If(typ, typed_c, typed_a, typed_b).into())
         },
         ast::Expr::Let(x, ascription, e, b) => {
             let typed_e = match ascription {
                 Some(ascribed_typ) => {
-                    let resolved_ascribed_typ = db.resolve_typ(ascribed_typ.clone(), moddef_id.package())?;
-                    db.typecheck_expr(moddef_id.clone(), e.clone(), resolved_ascribed_typ, ctx.clone())?
+                    let resolved_ascribed_typ = resolve_item_typ(db, ascribed_typ.clone(), item_id.clone())?;
+                    db.typecheck_expr(item_id.clone(), e.clone(), resolved_ascribed_typ, ctx.clone())?
                 },
-                None => db.typeinfer_expr(moddef_id.clone(), e.clone(), ctx.clone())?,
+                None => db.typeinfer_expr(item_id.clone(), e.clone(), ctx.clone())?,
             };
 
             let new_ctx = ctx.extend(x.clone(), typed_e.typ());
-            let typed_b = db.typecheck_expr(moddef_id, b.clone(), typ.clone(), new_ctx)?;
+            let typed_b = db.typecheck_expr(item_id, b.clone(), typ.clone(), new_ctx)?;
             Ok(TypedExpr::Let(typed_b.typ(), x.clone(), ascription.clone(), typed_e, typed_b).into())
         },
-        ast::Expr::Match(subject, ascription, arms) => {
-            let typed_subject = if let Some(ascription_typ) = ascription {
-                let ascription_typ = db.resolve_typ(ascription_typ.clone(), moddef_id.package())?;
-                db.typecheck_expr(moddef_id.clone(), subject.clone(), ascription_typ, ctx.clone())?
-            } else {
-                 db.typeinfer_expr(moddef_id.clone(), subject.clone(), ctx.clone())?
-            };
-
-            let uniondef_id = match typed_subject.typ() {
-                Type::Union(uniondef_id, _typeargs) => uniondef_id,
-                _ => return Err(virdant_error_at!("Can only match against a union type", span)),
-            };
-
-
-            let ctor_element_ids  = db.item_elements(uniondef_id.as_item())?;
-            let ctors: Vec<Ident> = ctor_element_ids.iter().map(|element| element.clone().name()).collect();
-
-            let mut typed_arms: Vec<TypedMatchArm> = vec![];
-            for ast::MatchArm(pat, e) in arms {
-                let mut new_ctx = ctx.clone();
-                match pat {
-                    ast::Pat::At(ctor, subpats) => {
-                        let CtorSig(arg_typs, _typ) = db.ctor_sig(typed_subject.typ(), ctor.clone())?;
-
-                        if subpats.len() != arg_typs.len() {
-                            return Err(virdant_error_at!("Pattern for {ctor} has the wrong number of arguments", span));
-                        }
-
-                        for (subpat, arg_typ) in subpats.iter().zip(arg_typs) {
-                            if let ast::Pat::Bind(x) = subpat {
-                                eprintln!("Extending ctx with {x} : {arg_typ}");
-                                new_ctx = new_ctx.extend(x.clone(), arg_typ);
-                            } else {
-                                return Err(virdant_error_at!("TODO subpats", span));
-                            }
-                        }
-                    },
-                    ast::Pat::Bind(x) => todo!(),
-                    ast::Pat::Otherwise => todo!(),
-                }
-                let typed_e = db.typecheck_expr(moddef_id.clone(), e.clone(), typ.clone(), new_ctx)?;
-                let typed_pat = TypedPat::from(pat, typed_subject.typ(), db)?;
-                let typed_arm = TypedMatchArm(typed_pat, typed_e);
-                typed_arms.push(typed_arm);
-            }
-            // TODO type ascription
-            Ok(TypedExpr::Match(typ.clone(), typed_subject, None, typed_arms).into())
-        },
+        ast::Expr::Match(_subject, _ascription, _arms) => typecheck_match(db, item_id, &expr, typ, ctx),
     }
 }
 
 fn typeinfer_expr(
     db: &dyn TypecheckQ,
-    moddef_id: ModDefId,
+    item_id: ItemId,
     expr: Ast<ast::Expr>,
     ctx: Context<Ident, Type>,
 ) -> VirdantResult<Arc<TypedExpr>> {
@@ -310,17 +320,42 @@ fn typeinfer_expr(
                 if let Some(actual_typ) = ctx.lookup(&ident) {
                     return Ok(TypedExpr::Reference(actual_typ, Referent::Local(ident)).into());
                 }
+
+                // Components shadow constants.
+                if db.resolve_element(item_id.clone(), ident.clone()).is_err() {
+                    if let Ok(ItemId::ConstDef(constdef_id)) = db.item(ident.into(), item_id.package()) {
+                        let typ = db.constdef_typ(constdef_id.clone())?;
+                        return Ok(TypedExpr::Reference(typ, Referent::Const(constdef_id)).into());
+                    }
+                }
+            }
+            if mem_subject(db, &item_id, &expr, &ctx)?.is_some() {
+                return Err(virdant_error_at!("Memory {path} can only be read with {path}->read(addr) or {path}->read_sync(addr)", span));
             }
+            let moddef_id = match item_id {
+                ItemId::ModDef(moddef_id) => moddef_id,
+                _ => return Err(virdant_error_at!("Unknown variable {path}", span)),
+            };
+            // A name which is neither bound by a let or a match, nor a component, is caught here rather than in a later phase.
+            let Ok(element_id) = db.resolve_component_by_path(moddef_id.clone(), path.clone()) else {
+                return Err(virdant_error_at!("Unknown variable {path}", span));
+            };
             let actual_typ = db.moddef_reference_type(moddef_id.clone(), path.clone())?;
-            let element_id: ElementId = db.resolve_component_by_path(moddef_id.clone(), path.clone())?;
 
-            if path.is_local() {
+            if let ItemId::PortDef(_portdef_id) = element_id.item() {
+                Ok(TypedExpr::Reference(actual_typ, Referent::PortChannel(path.clone())).into())
+            } else if path.is_local() {
                 Ok(TypedExpr::Reference(actual_typ, Referent::LocalComponent(element_id)).into())
             } else {
                 let submodule_element_id = db.resolve_component_by_path(moddef_id.clone(), path.head().as_path())?;
                 Ok(TypedExpr::Reference(actual_typ, Referent::NonLocalComponent(submodule_element_id, element_id)).into())
             }
         },
+        ast::Expr::QualReference(name) => {
+            let constdef_id = db.constdef(name.clone(), item_id.package())?;
+            let typ = db.constdef_typ(constdef_id.clone())?;
+            Ok(TypedExpr::Reference(typ, Referent::Const(constdef_id)).into())
+        },
         ast::Expr::Word(lit) => {
             if let Some(n) = lit.width {
                 Ok(TypedExpr::Word(Type::Word(n), lit.clone()).into())
@@ -330,9 +365,25 @@ fn typeinfer_expr(
         },
         ast::Expr::Vec(_) => todo!(),
         ast::Expr::Struct(_, _) => todo!(),
-        ast::Expr::MethodCall(subject, method, args) => {
-            let typed_subject = db.typeinfer_expr(moddef_id.clone(), subject.clone(), ctx.clone())?;
-            let MethodSig(arg_types, ret_typ) = db.method_sig(typed_subject.typ(), method.clone())?;
+        ast::Expr::MethodCall(subject, method, _width, args) if mem_subject(db, &item_id, subject, &ctx)?.is_some() => {
+            let (component_id, mem_typ) = mem_subject(db, &item_id, subject, &ctx)?.unwrap();
+            let Type::Vec(elem_typ, depth) = mem_typ else {
+                return Err(virdant_error_at!("Memory {component_id} must have a Vec type", span));
+            };
+            let sync = match method.as_str() {
+                "read" => false,
+                "read_sync" => true,
+                _ => return Err(virdant_error_at!("No such method `{method}` on memory {component_id}. Use read or read_sync", span)),
+            };
+            if args.len() != 1 {
+                return Err(virdant_error_at!("Method `{method}` takes an address", span));
+            }
+            let addr = db.typecheck_expr(item_id.clone(), args[0].clone(), Type::Word(clog2(depth)), ctx)?;
+            Ok(TypedExpr::MemRead(elem_typ.as_ref().clone(), component_id, addr, sync).into())
+        },
+        ast::Expr::MethodCall(subject, method, width, args) => {
+            let typed_subject = db.typeinfer_expr(item_id.clone(), subject.clone(), ctx.clone())?;
+            let MethodSig(arg_types, ret_typ) = method_sig(db, typed_subject.typ(), method.clone(), *width)?;
 
             if args.len() != arg_types.len() {
                 return Err(VirdantError::Unknown);
@@ -340,7 +391,7 @@ fn typeinfer_expr(
 
             let mut typed_args = vec![];
             for (arg, arg_type) in args.iter().zip(arg_types) {
-                let typed_arg = db.typecheck_expr(moddef_id.clone(), arg.clone(), arg_type, ctx.clone())?;
+                let typed_arg = db.typecheck_expr(item_id.clone(), arg.clone(), arg_type, ctx.clone())?;
                 typed_args.push(typed_arg);
             }
 
@@ -349,22 +400,63 @@ fn typeinfer_expr(
         ast::Expr::Ctor(_ctor, _args) => {
             Err(TypeError::CantInfer.into())
         },
-        ast::Expr::As(_, _) => todo!(),
+        ast::Expr::FnCall(name, args) => {
+            if let Some(from_bits_typ) = from_bits_typ(db, &item_id, name, None, span.clone())? {
+                return typecheck_from_bits(db, item_id, span, name, from_bits_typ, args, ctx);
+            }
+
+            let fndef_id = db.fndef(name.clone(), item_id.package())?;
+            let FnSig(arg_typs, ret_typ) = db.fndef_sig(fndef_id.clone())?;
+            if args.len() != arg_typs.len() {
+                let n = arg_typs.len();
+                return Err(virdant_error_at!("Function {name} takes {n} arguments", span));
+            }
+
+            let mut typed_args = vec![];
+            for (arg, arg_typ) in args.iter().zip(arg_typs) {
+                let typed_arg = db.typecheck_expr(item_id.clone(), arg.clone(), arg_typ, ctx.clone())?;
+                typed_args.push(typed_arg);
+            }
+            Ok(TypedExpr::FnCall(ret_typ, fndef_id, typed_args).into())
+        },
+        ast::Expr::As(subject, ascribed_typ) => {
+            let typ = resolve_item_typ(db, ascribed_typ.clone(), item_id.clone())?;
+            let typed_subject = db.typecheck_expr(item_id, subject.clone(), typ.clone(), ctx)?;
+            Ok(TypedExpr::As(typ, typed_subject, ascribed_typ.clone()).into())
+        },
+        // Copies with a known index are renamed by moddef_ast.
+        ast::Expr::IndexedReference(ast::IndexedName(name, index), _rest) => {
+            let index = match index {
+                ast::NameIndex::Lit(i) => i.to_string(),
+                ast::NameIndex::Var(var) => var.to_string(),
+            };
+            Err(virdant_error_at!("{name}[{index}] is indexed by {index}, which is not the variable of an enclosing for", span))
+        },
         ast::Expr::Idx(subject, i) => {
-            eprintln!("TODO: Check i fits in the size of the subject");
-            let typed_subject = db.typeinfer_expr(moddef_id.clone(), subject.clone(), ctx)?;
+            let typed_subject = db.typeinfer_expr(item_id.clone(), subject.clone(), ctx)?;
+            let subject_typ = typed_subject.typ();
+            let width = db.bitwidth(subject_typ.clone())?;
+            if *i >= width {
+                return Err(virdant_error_at!("Index [{i}] is out of bounds for {subject_typ}, which has {width} bits", span));
+            }
             Ok(TypedExpr::Idx(Type::Word(1), typed_subject, *i).into())
         },
         ast::Expr::IdxRange(subject, j, i) => {
-            eprintln!("TODO: Check i fits in the size of the subject");
-            let typed_subject = db.typeinfer_expr(moddef_id.clone(), subject.clone(), ctx)?;
+            let typed_subject = db.typeinfer_expr(item_id.clone(), subject.clone(), ctx)?;
+            let subject_typ = typed_subject.typ();
+            let width = db.bitwidth(subject_typ.clone())?;
+            if j <= i {
+                return Err(virdant_error_at!("Slice [{j}..{i}] is empty. The upper index goes first, and is excluded", span));
+            } else if *j > width {
+                return Err(virdant_error_at!("Slice [{j}..{i}] is out of bounds for {subject_typ}, which has {width} bits", span));
+            }
             Ok(TypedExpr::IdxRange(Type::Word(j - i), typed_subject, *j, *i).into())
         },
         ast::Expr::Cat(es) => {
             let mut typed_es = vec![];
             let mut width = 0;
             for e in es {
-                let typed_e = db.typeinfer_expr(moddef_id.clone(), e.clone(), ctx.clone())?;
+                let typed_e = db.typeinfer_expr(item_id.clone(), e.clone(), ctx.clone())?;
                 let e_typ = typed_e.typ();
                 if let Type::Word(w) = e_typ {
                     width += w;
@@ -378,24 +470,277 @@ fn typeinfer_expr(
 
             Ok(TypedExpr::Cat(typ, typed_es).into())
         },
-        ast::Expr::If(_, _, _) => Err(virdant_error_at!("Can't infer", span)),
+        ast::Expr::With(subject, updates) => {
+            let typed_subject = db.typeinfer_expr(item_id.clone(), subject.clone(), ctx.clone())?;
+            let typ = typed_subject.typ();
+
+            let mut slots: Vec<(Offset, Width)> = vec![];
+            let mut typed_updates = vec![];
+            for (update, e) in updates {
+                let (update_typ, offset) = match db.update_slot(typ.clone(), update.clone()) {
+                    Ok(slot) => slot,
+                    Err(err) => return Err(virdant_error_at!("Invalid update in ->with", span).because(err)),
+                };
+                let width = db.bitwidth(update_typ.clone())?;
+                if slots.iter().any(|(other_offset, other_width)| offset < other_offset + other_width && *other_offset < offset + width) {
+                    return Err(virdant_error_at!("The updates in ->with overlap", span));
+                }
+                slots.push((offset, width));
+
+                let typed_e = db.typecheck_expr(item_id.clone(), e.clone(), update_typ, ctx.clone())?;
+                typed_updates.push((update.clone(), typed_e));
+            }
+
+            Ok(TypedExpr::With(typ, typed_subject, typed_updates).into())
+        },
+        ast::Expr::If(c, a, b) => {
+            // The type is taken from whichever branch can be inferred.
+            let typed_c = db.typecheck_expr(item_id.clone(), c.clone(), Type::Word(1), ctx.clone())?;
+            let (typed_a, typed_b) = match db.typeinfer_expr(item_id.clone(), a.clone(), ctx.clone()) {
+                Ok(typed_a) => {
+                    let typed_b = db.typecheck_expr(item_id.clone(), b.clone(), typed_a.typ(), ctx)?;
+                    (typed_a, typed_b)
+                },
+                Err(e) => {
+                    let typed_b = match db.typeinfer_expr(item_id.clone(), b.clone(), ctx.clone()) {
+                        Ok(typed_b) => typed_b,
+                        Err(_) => return Err(virdant_error_at!("Can't infer the type of either branch", span).because(e)),
+                    };
+                    let typed_a = db.typecheck_expr(item_id.clone(), a.clone(), typed_b.typ(), ctx)?;
+                    (typed_a, typed_b)
+                },
+            };
+            Ok(TypedExpr::If(typed_a.typ(), typed_c, typed_a, typed_b).into())
+        },
         ast::Expr::Let(x, ascription, e, b) => {
             let typed_e = match ascription {
                 Some(ascribed_typ) => {
-                    let resolved_ascribed_typ = db.resolve_typ(ascribed_typ.clone(), moddef_id.package())?;
-                    db.typecheck_expr(moddef_id.clone(), e.clone(), resolved_ascribed_typ, ctx.clone())?
+                    let resolved_ascribed_typ = resolve_item_typ(db, ascribed_typ.clone(), item_id.clone())?;
+                    db.typecheck_expr(item_id.clone(), e.clone(), resolved_ascribed_typ, ctx.clone())?
                 },
-                None => db.typeinfer_expr(moddef_id.clone(), e.clone(), ctx.clone())?,
+                None => db.typeinfer_expr(item_id.clone(), e.clone(), ctx.clone())?,
             };
 
             let new_ctx = ctx.extend(x.clone(), typed_e.typ());
-            let typed_b = db.typeinfer_expr(moddef_id, b.clone(), new_ctx)?;
+            let typed_b = db.typeinfer_expr(item_id, b.clone(), new_ctx)?;
             Ok(TypedExpr::Let(typed_b.typ(), x.clone(), ascription.clone(), typed_e, typed_b).into())
         },
-        ast::Expr::Match(_subject, _ascription, _arms) => Err(TypeError::CantInfer.into()),
+        ast::Expr::Match(subject, ascription, arms) => {
+            // The type is taken from the first arm which can be inferred.
+            let typed_subject = typecheck_match_subject(db, &item_id, subject, ascription, &ctx)?;
+            for ast::MatchArm(pat, e) in arms {
+                let arm_ctx = match_arm_ctx(db, typed_subject.typ(), pat, &ctx, span.clone())?;
+                if let Ok(typed_e) = db.typeinfer_expr(item_id.clone(), e.clone(), arm_ctx) {
+                    return typecheck_match(db, item_id, &expr, typed_e.typ(), ctx);
+                }
+            }
+            Err(virdant_error_at!("Can't infer the type of any arm", span))
+        },
+    }
+}
+
+fn typecheck_match(
+    db: &dyn TypecheckQ,
+    item_id: ItemId,
+    expr: &Ast<ast::Expr>,
+    typ: Type,
+    ctx: Context<Ident, Type>,
+) -> VirdantResult<Arc<TypedExpr>> {
+    let span = db.span(expr.span());
+    let ast::Expr::Match(subject, ascription, arms) = expr.as_ref() else { unreachable!() };
+    let typed_subject = typecheck_match_subject(db, &item_id, subject, ascription, &ctx)?;
+
+    let typedef_id = match typed_subject.typ() {
+        Type::Union(uniondef_id, _typeargs) => uniondef_id.as_item(),
+        Type::Enum(enumdef_id) => enumdef_id.as_item(),
+        _ => return Err(virdant_error_at!("Can only match against a union or enum type", span)),
+    };
+
+
+    let ctor_element_ids  = db.item_elements(typedef_id.clone())?;
+    let ctors: Vec<Ident> = ctor_element_ids.iter().map(|element| element.clone().name()).collect();
+
+    let mut covered: Vec<Ident> = vec![];
+    let mut has_otherwise = false;
+    let mut typed_arms: Vec<TypedMatchArm> = vec![];
+    for ast::MatchArm(pat, e) in arms {
+        match pat {
+            ast::Pat::At(ctor, _subpats) => covered.push(ctor.clone()),
+            ast::Pat::Bind(x) => {
+                return Err(virdant_error_at!("Match arm binds the whole subject to {x}. Use else, and refer to the subject instead", span));
+            },
+            ast::Pat::Otherwise => has_otherwise = true,
+        }
+        let new_ctx = match_arm_ctx(db, typed_subject.typ(), pat, &ctx, span.clone())?;
+        let typed_e = db.typecheck_expr(item_id.clone(), e.clone(), typ.clone(), new_ctx)?;
+        let typed_pat = TypedPat::from(pat, typed_subject.typ(), db)?;
+        let typed_arm = TypedMatchArm(typed_pat, typed_e);
+        typed_arms.push(typed_arm);
+    }
+
+    // Every value of an enum must be handled, or else there must be an else arm.
+    if let (ItemId::EnumDef(_), false) = (&typedef_id, has_otherwise) {
+        let missing: Vec<String> = ctors.iter().filter(|ctor| !covered.contains(ctor)).map(|ctor| format!("@{ctor}")).collect();
+        if !missing.is_empty() {
+            let missing = missing.join(", ");
+            return Err(virdant_error_at!("Match on {typedef_id} is missing arms for {missing}", span));
+        }
+    }
+
+    // TODO type ascription
+    Ok(TypedExpr::Match(typ.clone(), typed_subject, None, typed_arms).into())
+}
+
+fn typecheck_match_subject(
+    db: &dyn TypecheckQ,
+    item_id: &ItemId,
+    subject: &Ast<ast::Expr>,
+    ascription: &Option<Ast<ast::Type>>,
+    ctx: &Context<Ident, Type>,
+) -> VirdantResult<Arc<TypedExpr>> {
+    if let Some(ascription_typ) = ascription {
+        let ascription_typ = resolve_item_typ(db, ascription_typ.clone(), item_id.clone())?;
+        db.typecheck_expr(item_id.clone(), subject.clone(), ascription_typ, ctx.clone())
+    } else {
+        db.typeinfer_expr(item_id.clone(), subject.clone(), ctx.clone())
+    }
+}
+
+/// The context of the body of a match arm, with the variables bound by its pattern.
+fn match_arm_ctx(db: &dyn TypecheckQ, subject_typ: Type, pat: &ast::Pat, ctx: &Context<Ident, Type>, span: Span) -> VirdantResult<Context<Ident, Type>> {
+    let mut new_ctx = ctx.clone();
+    if let ast::Pat::At(ctor, subpats) = pat {
+        let CtorSig(arg_typs, _typ) = db.ctor_sig(subject_typ, ctor.clone())?;
+
+        if subpats.len() != arg_typs.len() {
+            return Err(virdant_error_at!("Pattern for {ctor} has the wrong number of arguments", span));
+        }
+
+        for (subpat, arg_typ) in subpats.iter().zip(arg_typs) {
+            if let ast::Pat::Bind(x) = subpat {
+                eprintln!("Extending ctx with {x} : {arg_typ}");
+                new_ctx = new_ctx.extend(x.clone(), arg_typ);
+            } else {
+                return Err(virdant_error_at!("TODO subpats", span));
+            }
+        }
+    }
+    Ok(new_ctx)
+}
+
+/// The signature of a method, where `x->bits()` converts a struct, union or vec to a word as wide as its layout.
+fn method_sig(db: &dyn TypecheckQ, typ: Type, method: Ident, width: Option<Width>) -> VirdantResult<MethodSig> {
+    match &typ {
+        Type::Struct(_, _) | Type::Union(_, _) | Type::Vec(_, _) if method == "bits".into() => {
+            Ok(MethodSig(vec![], Type::Word(db.bitwidth(typ)?)))
+        },
+        Type::Vec(elem_typ, n) if method == "get".into() && width.is_none() => {
+            Ok(MethodSig(vec![Type::Word(index_width(*n))], elem_typ.as_ref().clone()))
+        },
+        Type::Vec(elem_typ, n) if method == "slice".into() => {
+            let Some(m) = width else {
+                return Err(virdant_error!("Method {method} needs a width, as in {method}[8]"));
+            };
+            if m > *n || m == 0 {
+                return Err(virdant_error!("Can't slice a Vec of {m} elements out of {typ}"));
+            }
+            Ok(MethodSig(vec![Type::Word(index_width(*n))], Type::Vec(elem_typ.clone(), m)))
+        },
+        _ => db.method_sig(typ, method, width),
+    }
+}
+
+/// The type `T` which `T::from_bits(w)` converts to, if `name` is of that form.
+/// The arguments of a generic type can only be taken from the expected type.
+fn from_bits_typ(db: &dyn TypecheckQ, item_id: &ItemId, name: &QualIdent, expected: Option<&Type>, span: Span) -> VirdantResult<Option<Type>> {
+    let Some(namespace) = name.namespace() else {
+        return Ok(None);
+    };
+    if name.name() != "from_bits".into() {
+        return Ok(None);
+    }
+
+    let (typ, params) = match db.item(namespace.into(), item_id.package()) {
+        Ok(ItemId::EnumDef(enumdef_id)) => (Type::Enum(enumdef_id), vec![]),
+        Ok(ItemId::StructDef(structdef_id)) => {
+            let params = db.structdef_ast(structdef_id.clone())?.params.clone();
+            (Type::Struct(structdef_id, vec![]), params)
+        },
+        Ok(ItemId::UnionDef(uniondef_id)) => {
+            let params = db.uniondef_ast(uniondef_id.clone())?.params.clone();
+            (Type::Union(uniondef_id, vec![]), params)
+        },
+        _ => return Ok(None),
+    };
+
+    match (&typ, expected) {
+        (Type::Struct(structdef_id, _), Some(expected @ Type::Struct(expected_id, _))) if structdef_id == expected_id => Ok(Some(expected.clone())),
+        (Type::Union(uniondef_id, _), Some(expected @ Type::Union(expected_id, _))) if uniondef_id == expected_id => Ok(Some(expected.clone())),
+        _ if params.is_empty() => Ok(Some(typ)),
+        _ => Err(virdant_error_at!("Can't infer the arguments of the generic type in {name}. Use an ascription, as in {name}(w)->as(T)", span)),
     }
 }
 
+/// `T::from_bits(w)` takes a word as wide as the layout of `T`.
+fn typecheck_from_bits(
+    db: &dyn TypecheckQ,
+    item_id: ItemId,
+    span: Span,
+    name: &QualIdent,
+    typ: Type,
+    args: &[Ast<ast::Expr>],
+    ctx: Context<Ident, Type>,
+) -> VirdantResult<Arc<TypedExpr>> {
+    if args.len() != 1 {
+        return Err(virdant_error_at!("Function {name} takes 1 argument", span));
+    }
+    let width = db.bitwidth(typ.clone())?;
+    let typed_arg = db.typecheck_expr(item_id, args[0].clone(), Type::Word(width), ctx)?;
+    Ok(TypedExpr::FromBits(typ, typed_arg).into())
+}
+
+/// If `subject` names a `mem` component, gives it together with its type.
+fn mem_subject(db: &dyn TypecheckQ, item_id: &ItemId, subject: &Ast<ast::Expr>, ctx: &Context<Ident, Type>) -> VirdantResult<Option<(ComponentId, Type)>> {
+    let (ItemId::ModDef(moddef_id), ast::Expr::Reference(path)) = (item_id, subject.as_ref()) else {
+        return Ok(None);
+    };
+    let Some(name) = path.as_ident() else {
+        return Ok(None);
+    };
+    if ctx.lookup(&name).is_some() {
+        return Ok(None);
+    }
+
+    let component_id = ComponentId::from_ident(moddef_id.clone(), name);
+    match db.component_ast(component_id.clone()) {
+        Ok(component) if component.kind == ast::ComponentKind::Mem => {
+            let typ = db.resolve_moddef_typ(component.typ.clone(), moddef_id.clone())?;
+            Ok(Some((component_id, typ)))
+        },
+        _ => Ok(None),
+    }
+}
+
+/// Resolves a type in the body of a moddef or fn def.
+fn resolve_item_typ(db: &dyn TypecheckQ, typ: Ast<ast::Type>, item_id: ItemId) -> VirdantResult<Type> {
+    match item_id {
+        ItemId::ModDef(moddef_id) => db.resolve_moddef_typ(typ, moddef_id),
+        item_id => db.resolve_typ(typ, item_id.package()),
+    }
+}
+
+/// The body of a fn def, typechecked with its arguments in scope.
+fn fndef_body(db: &dyn TypecheckQ, fndef_id: FnDefId) -> VirdantResult<Arc<TypedExpr>> {
+    let fndef_ast = db.fndef_ast(fndef_id.clone())?;
+    let FnSig(arg_typs, ret_typ) = db.fndef_sig(fndef_id.clone())?;
+
+    let mut ctx = Context::empty();
+    for ((arg, _ast_typ), typ) in fndef_ast.args.iter().zip(arg_typs) {
+        ctx = ctx.extend(arg.clone(), typ);
+    }
+    db.typecheck_expr(fndef_id.as_item(), fndef_ast.body.clone(), ret_typ, ctx)
+}
+
 fn moddef_reference_type(db: &dyn TypecheckQ, moddef_id: ModDefId, path: Path) -> VirdantResult<Type> {
     eprintln!("moddef_reference_type({moddef_id}, {path})");
 
@@ -410,3 +755,486 @@ fn typecheck_moddef(_db: &dyn TypecheckQ, _moddef: ModDefId) -> VirdantResult<()
 fn typecheck(_db: &dyn TypecheckQ, _moddef: ModDefId) -> VirdantResult<()> {
     todo!()
 }
+
+////////////////////////////////////////////////////////////////////////////////
+// Width inference
+////////////////////////////////////////////////////////////////////////////////
+
+/// Gives each unsized literal in `expr` the width it is forced to have,
+/// so that literals like the `1` in `1->add(x)` can be typechecked where a type can't be pushed down to them.
+///
+/// Every subexpression which is a word of unknown width gets a width variable.
+/// The variables are unified across the operands of methods like `add` and `eq`,
+/// the branches of an `if` or a `match`, and a `let` and its uses,
+/// and they are solved by the widths of references, sized literals, and the expected type `typ`.
+fn infer_widths(
+    db: &dyn TypecheckQ,
+    item_id: &ItemId,
+    expr: Ast<ast::Expr>,
+    typ: &Type,
+    ctx: &Context<Ident, Type>,
+) -> VirdantResult<Ast<ast::Expr>> {
+    if !has_unsized_literal(&expr) {
+        return Ok(expr);
+    }
+
+    let mut widths = WidthVars::new(db, item_id.clone());
+    let var = widths.visit(&expr, &Context::empty(), ctx)?;
+    widths.expect(var, typ);
+    let solution = widths.solve()?;
+    Ok(set_literal_widths(&expr, &solution))
+}
+
+fn has_unsized_literal(expr: &Ast<ast::Expr>) -> bool {
+    match expr.as_ref() {
+        ast::Expr::Word(lit) => lit.width.is_none(),
+        _ => expr.subexprs().into_iter().any(has_unsized_literal),
+    }
+}
+
+fn set_literal_widths(expr: &Ast<ast::Expr>, solution: &HashMap<ast::AstId, Width>) -> Ast<ast::Expr> {
+    match expr.as_ref() {
+        ast::Expr::Word(lit) => match (lit.width, solution.get(&expr.id())) {
+            (None, Some(width)) => expr.derive(ast::Expr::Word(ast::WordLit { width: Some(*width), ..lit.clone() })),
+            _ => expr.clone(),
+        },
+        _ => expr.map_subexprs(&mut |e| set_literal_widths(e, solution)),
+    }
+}
+
+/// The width variables of an expression, kept as a union-find forest.
+struct WidthVars<'a> {
+    db: &'a dyn TypecheckQ,
+    item_id: ItemId,
+    parent: Vec<usize>,
+    width: Vec<Option<Width>>,
+    /// Whether some expression of this width is checked against a type by the typechecker,
+    /// so that if it is never solved here, the typechecker will still catch it.
+    checked: Vec<bool>,
+    /// The spans of the literals and uses of `let` bindings which have this width.
+    spans: Vec<Vec<Span>>,
+    literals: Vec<(Ast<ast::Expr>, usize)>,
+}
+
+impl<'a> WidthVars<'a> {
+    fn new(db: &'a dyn TypecheckQ, item_id: ItemId) -> WidthVars<'a> {
+        WidthVars {
+            db,
+            item_id,
+            parent: vec![],
+            width: vec![],
+            checked: vec![],
+            spans: vec![],
+            literals: vec![],
+        }
+    }
+
+    fn fresh(&mut self, width: Option<Width>) -> usize {
+        let var = self.parent.len();
+        self.parent.push(var);
+        self.width.push(width);
+        self.checked.push(false);
+        self.spans.push(vec![]);
+        var
+    }
+
+    fn find(&self, mut var: usize) -> usize {
+        while self.parent[var] != var {
+            var = self.parent[var];
+        }
+        var
+    }
+
+    /// Two widths which are already known to be different are left alone,
+    /// and the typechecker reports the mismatch.
+    fn unify(&mut self, a: Option<usize>, b: Option<usize>) -> Option<usize> {
+        let (Some(a), Some(b)) = (a, b) else {
+            return a.or(b);
+        };
+        let (ra, rb) = (self.find(a), self.find(b));
+        match (self.width[ra], self.width[rb]) {
+            _ if ra == rb => (),
+            (Some(m), Some(n)) if m != n => (),
+            (width_a, width_b) => {
+                self.parent[rb] = ra;
+                self.width[ra] = width_a.or(width_b);
+                self.checked[ra] |= self.checked[rb];
+                let spans = std::mem::take(&mut self.spans[rb]);
+                self.spans[ra].extend(spans);
+            },
+        }
+        Some(a)
+    }
+
+    fn expect(&mut self, var: Option<usize>, typ: &Type) {
+        if let Some(width_var) = self.of_type(typ) {
+            self.unify(var, Some(width_var));
+        }
+    }
+
+    fn check(&mut self, var: Option<usize>) {
+        if let Some(var) = var {
+            let root = self.find(var);
+            self.checked[root] = true;
+        }
+    }
+
+    fn of_type(&mut self, typ: &Type) -> Option<usize> {
+        match typ {
+            Type::Word(n) => Some(self.fresh(Some(*n))),
+            _ => None,
+        }
+    }
+
+    /// The width variable of `expr`, or `None` if it is not a word.
+    /// Locals whose width is not yet known are bound in `locals` rather than `ctx`.
+    fn visit(&mut self, expr: &Ast<ast::Expr>, locals: &Context<Ident, usize>, ctx: &Context<Ident, Type>) -> VirdantResult<Option<usize>> {
+        let span = self.db.span(expr.span());
+        match expr.as_ref() {
+            ast::Expr::Word(ast::WordLit { width: None, .. }) => {
+                let var = self.fresh(None);
+                self.spans[var].push(span);
+                self.literals.push((expr.clone(), var));
+                return Ok(Some(var));
+            },
+            ast::Expr::Reference(path) => {
+                if let Some(var) = path.as_ident().and_then(|x| locals.lookup(&x)) {
+                    let root = self.find(var);
+                    self.spans[root].push(span);
+                    return Ok(Some(var));
+                }
+            },
+            _ => (),
+        }
+
+        // Inferring these may come back around to this expression, so they are always taken apart.
+        let inferrable = !matches!(expr.as_ref(), ast::Expr::If(_, _, _) | ast::Expr::Let(_, _, _, _) | ast::Expr::Match(_, _, _));
+        if inferrable {
+            if let Ok(typed_expr) = self.db.typeinfer_expr(self.item_id.clone(), expr.clone(), ctx.clone()) {
+                return Ok(self.of_type(&typed_expr.typ()));
+            }
+        }
+
+        Ok(match expr.as_ref() {
+            ast::Expr::Reference(_) | ast::Expr::IndexedReference(_, _) | ast::Expr::QualReference(_) | ast::Expr::Word(_) => None,
+            ast::Expr::Vec(es) | ast::Expr::Ctor(_, es) => {
+                for e in es {
+                    let var = self.visit(e, locals, ctx)?;
+                    self.check(var);
+                }
+                None
+            },
+            ast::Expr::Struct(_name, fields) => {
+                for (_field, e) in fields {
+                    self.visit(e, locals, ctx)?;
+                }
+                None
+            },
+            ast::Expr::FnCall(name, args) => {
+                let sig = self.db.fndef(name.clone(), self.item_id.package()).and_then(|fndef_id| self.db.fndef_sig(fndef_id)).ok();
+                for (i, arg) in args.iter().enumerate() {
+                    let var = self.visit(arg, locals, ctx)?;
+                    match sig.as_ref().and_then(|FnSig(arg_typs, _ret_typ)| arg_typs.get(i)) {
+                        Some(arg_typ) => self.expect(var, arg_typ),
+                        None => self.check(var),
+                    }
+                }
+                sig.and_then(|FnSig(_arg_typs, ret_typ)| self.of_type(&ret_typ))
+            },
+            ast::Expr::MethodCall(subject, method, width, args) => {
+                let subject_var = self.visit(subject, locals, ctx)?;
+
+                let mut arg_vars = vec![];
+                for arg in args {
+                    arg_vars.push(self.visit(arg, locals, ctx)?);
+                }
+
+                match method.as_str() {
+                    "add" | "sub" | "and" | "or" | "xor" | "sll" | "srl" | "sra" | "inc" | "dec" | "not" | "neg" => {
+                        for arg_var in arg_vars {
+                            self.unify(subject_var, arg_var);
+                        }
+                        subject_var
+                    },
+                    "eq" | "neq" |
+                    "lt" | "lte" | "gt" | "gte" |
+                    "ltu" | "lteu" | "gtu" | "gteu" |
+                    "lts" | "ltes" | "gts" | "gtes" => {
+                        for arg_var in arg_vars {
+                            self.unify(subject_var, arg_var);
+                        }
+                        self.of_type(&Type::Word(1))
+                    },
+                    "mul" | "muls" => {
+                        for arg_var in arg_vars {
+                            self.unify(subject_var, arg_var);
+                        }
+                        None
+                    },
+                    "zext" | "sext" | "trunc" => width.map(|width| self.fresh(Some(width))),
+                    _ => {
+                        for arg_var in arg_vars {
+                            self.check(arg_var);
+                        }
+                        let subject_typ = self.db.typeinfer_expr(self.item_id.clone(), subject.clone(), ctx.clone()).map(|typed_subject| typed_subject.typ());
+                        match subject_typ.and_then(|subject_typ| method_sig(self.db, subject_typ, method.clone(), *width)) {
+                            Ok(MethodSig(_arg_typs, ret_typ)) => self.of_type(&ret_typ),
+                            Err(_) if method.as_str() == "get" => self.of_type(&Type::Word(1)),
+                            Err(_) if method.as_str() == "slice" => width.map(|width| self.fresh(Some(width))),
+                            Err(_) => None,
+                        }
+                    },
+                }
+            },
+            ast::Expr::As(e, typ) => {
+                let typ = resolve_item_typ(self.db, typ.clone(), self.item_id.clone())?;
+                let var = self.visit(e, locals, ctx)?;
+                self.expect(var, &typ);
+                self.of_type(&typ)
+            },
+            ast::Expr::Idx(subject, _i) => {
+                self.visit(subject, locals, ctx)?;
+                self.of_type(&Type::Word(1))
+            },
+            ast::Expr::IdxRange(subject, j, i) => {
+                self.visit(subject, locals, ctx)?;
+                self.of_type(&Type::Word(j.saturating_sub(*i)))
+            },
+            ast::Expr::Cat(es) => {
+                for e in es {
+                    self.visit(e, locals, ctx)?;
+                }
+                None
+            },
+            ast::Expr::With(subject, updates) => {
+                let var = self.visit(subject, locals, ctx)?;
+                for (_update, e) in updates {
+                    let update_var = self.visit(e, locals, ctx)?;
+                    self.check(update_var);
+                }
+                var
+            },
+            ast::Expr::If(c, a, b) => {
+                let c_var = self.visit(c, locals, ctx)?;
+                self.expect(c_var, &Type::Word(1));
+                let a_var = self.visit(a, locals, ctx)?;
+                let b_var = self.visit(b, locals, ctx)?;
+                self.unify(a_var, b_var)
+            },
+            ast::Expr::Let(x, ascription, e, b) => {
+                let var = self.visit(e, locals, ctx)?;
+                let typ = match ascription {
+                    Some(ascribed_typ) => {
+                        let typ = resolve_item_typ(self.db, ascribed_typ.clone(), self.item_id.clone())?;
+                        self.expect(var, &typ);
+                        Some(typ)
+                    },
+                    None if var.is_some() => None,
+                    None => self.db.typeinfer_expr(self.item_id.clone(), e.clone(), ctx.clone()).ok().map(|typed_e| typed_e.typ()),
+                };
+                match (typ, var) {
+                    (Some(typ), _) => self.visit(b, locals, &ctx.extend(x.clone(), typ))?,
+                    (None, Some(var)) => self.visit(b, &locals.extend(x.clone(), var), ctx)?,
+                    (None, None) => self.visit(b, locals, ctx)?,
+                }
+            },
+            ast::Expr::Match(subject, ascription, arms) => {
+                let subject_var = self.visit(subject, locals, ctx)?;
+                self.check(subject_var);
+                let subject_typ = typecheck_match_subject(self.db, &self.item_id, subject, ascription, ctx).ok().map(|typed_subject| typed_subject.typ());
+
+                let mut var = None;
+                for ast::MatchArm(pat, e) in arms {
+                    let arm_ctx = subject_typ.clone()
+                        .and_then(|subject_typ| match_arm_ctx(self.db, subject_typ, pat, ctx, span.clone()).ok())
+                        .unwrap_or_else(|| ctx.clone());
+                    let arm_var = self.visit(e, locals, &arm_ctx)?;
+                    var = self.unify(var, arm_var);
+                }
+                var
+            },
+        })
+    }
+
+    /// The widths of the literals which were solved for,
+    /// or an error for each group of literals whose width could not be found.
+    fn solve(self) -> VirdantResult<HashMap<ast::AstId, Width>> {
+        let mut solution = HashMap::new();
+        let mut errors = ErrorReport::new();
+        let mut reported = HashSet::new();
+
+        for (literal, var) in &self.literals {
+            let ast::Expr::Word(lit) = literal.as_ref() else { unreachable!() };
+            let span = self.db.span(literal.span());
+            let spelling = &lit.spelling;
+            let root = self.find(*var);
+            match self.width[root] {
+                Some(n) if n < 64 && lit.value >= pow(2, n) => {
+                    errors.add(virdant_error_at!("Literal {spelling} doesn't fit in Word[{n}]", span));
+                },
+                Some(n) => {
+                    solution.insert(literal.id(), n);
+                },
+                None if !self.checked[root] && reported.insert(root) => {
+                    let causes = self.spans[root].iter()
+                        .map(|span| virdant_error_at!("The width of this expression is unknown", span.clone()))
+                        .collect();
+                    errors.add(virdant_error_at!("Can't infer the width of {spelling}", span).because(VirdantError::Multiple(causes)));
+                },
+                None => (),
+            }
+        }
+
+        errors.check()?;
+        Ok(solution)
+    }
+}
+
+/// Typechecks the driver of `name` in a module `Top` with the given declarations.
+#[cfg(test)]
+fn typecheck_driver(decls: &str, name: &str) -> VirdantResult<Arc<TypedExpr>> {
+    use super::astq::AstQ;
+    use super::item_resolution::ItemResolutionQ;
+    use super::resolve::ResolveQ;
+    use super::type_resolution::TypeResolutionQ;
+
+    let mut db = Db::new();
+    db.set_source("top", &format!("
+        struct type Pixel {{
+            red : Word[4];
+            green : Word[4];
+        }}
+
+        mod Top {{
+            incoming a : Word[8];
+            incoming c : Word[1];
+            incoming p : Pixel;
+            outgoing out : Word[8];
+            outgoing flag : Word[1];
+            outgoing nibble : Word[4];
+            {decls}
+        }}
+
+        mod Delay[W] {{
+            incoming inp : Word[W];
+            outgoing out : Word[W];
+            out := inp;
+        }}
+
+        fn max(x : Word[8], y : Word[8]) -> Word[8] = if x->gt(y) {{ x }} else {{ y }};
+
+        enum type Op : Word[2] {{
+            ADD = 0;
+            SUB = 3;
+        }}
+
+        union type Valid[T] {{
+            Invalid();
+            Valid(T);
+        }}
+    "));
+    let moddef_id = db.moddef(Ident::from("Top").into(), db.resolve_package("top".into())?)?;
+    let typ = db.component_typ(db.resolve_component_by_path(moddef_id.clone(), name.into())?)?;
+    let wire = db.wire_ast(moddef_id.clone(), name.into())?.unwrap();
+    let ast::Wire(_target, _wire_type, expr) = wire.as_ref();
+    db.typecheck_expr(moddef_id.as_item(), expr.clone(), typ, Context::empty())
+}
+
+#[test]
+fn typecheck_width_inference() {
+    let typed = typecheck_driver("out := 1->add(a);", "out").unwrap();
+    let TypedExpr::MethodCall(_typ, subject, _method, _args) = typed.as_ref() else { panic!() };
+    assert_eq!(subject.typ(), Type::Word(8));
+
+    // The width of a let flows from its uses, and the width of a branch from its sibling.
+    let typed = typecheck_driver("flag := let y = 200 in (if c { y } else { 3 })->eq(a);", "flag").unwrap();
+    let TypedExpr::Let(_typ, _x, _ascription, value, body) = typed.as_ref() else { panic!() };
+    assert_eq!(value.typ(), Type::Word(8));
+    let TypedExpr::MethodCall(_typ, subject, _method, _args) = body.as_ref() else { panic!() };
+    let TypedExpr::If(_typ, _c, _a, b) = subject.as_ref() else { panic!() };
+    assert_eq!(b.typ(), Type::Word(8));
+
+    let err = |decls: &str, name: &str| format!("{:?}", typecheck_driver(decls, name).unwrap_err());
+    assert!(err("flag := 1->eq(2);", "flag").contains("Can't infer the width of 1"));
+    assert!(err("flag := 300->eq(a);", "flag").contains("Literal 300 doesn't fit in Word[8]"));
+    assert!(err("out := a->add(0x100);", "out").contains("Literal 0x100 doesn't fit in Word[8]"));
+}
+
+#[test]
+fn typecheck_index_bounds() {
+    let typed = typecheck_driver("nibble := a[8..4];", "nibble").unwrap();
+    assert!(matches!(typed.as_ref(), TypedExpr::IdxRange(Type::Word(4), _subject, 8, 4)));
+    let typed = typecheck_driver("nibble := p[8..4];", "nibble").unwrap();
+    assert!(matches!(typed.as_ref(), TypedExpr::IdxRange(Type::Word(4), _subject, 8, 4)));
+    let typed = typecheck_driver("nibble := cat(a[7], a[2..0], a[0]);", "nibble").unwrap();
+    let TypedExpr::Cat(_typ, parts) = typed.as_ref() else { panic!() };
+    assert!(matches!(parts[0].as_ref(), TypedExpr::Idx(Type::Word(1), _subject, 7)));
+
+    let err = |decls: &str, name: &str| format!("{:?}", typecheck_driver(decls, name).unwrap_err());
+    assert!(err("nibble := a[9..5];", "nibble").contains("Slice [9..5] is out of bounds for Word[8], which has 8 bits"));
+    assert!(err("nibble := a[0..4];", "nibble").contains("Slice [0..4] is empty. The upper index goes first, and is excluded"));
+    assert!(err("out := a[4..4]->zext();", "out").contains("Slice [4..4] is empty"));
+    assert!(err("nibble := cat(a[8], a[3..0]);", "nibble").contains("Index [8] is out of bounds for Word[8], which has 8 bits"));
+    assert!(err("nibble := p[12..8];", "nibble").contains("Slice [12..8] is out of bounds for top::Pixel, which has 8 bits"));
+}
+
+#[test]
+fn typecheck_unknown_variables() {
+    let typed = typecheck_driver("out := let y = a in let z = y in z->add(y);", "out").unwrap();
+    let TypedExpr::Let(_typ, _y, _ascription, _value, body) = typed.as_ref() else { panic!() };
+    let TypedExpr::Let(_typ, _z, _ascription, value, _body) = body.as_ref() else { panic!() };
+    assert_eq!(value.as_ref(), &TypedExpr::Reference(Type::Word(8), Referent::Local("y".into())));
+
+    let err = |decls: &str| format!("{:?}", typecheck_driver(decls, "out").unwrap_err());
+    assert!(err("out := let y = a in q;").contains("Unknown variable q"));
+
+    // A let only binds its variable in its body.
+    let err = err("out := (let y = a in y)->add(y);");
+    assert!(err.contains("Unknown variable y"));
+    assert!(err.contains("Some([14:42:top])"));
+}
+
+#[test]
+fn typecheck_parametric_moddefs() {
+    let typed = typecheck_driver("mod d of Delay[8]; d.inp := a; out := d.out;", "out").unwrap();
+    assert_eq!(typed.typ(), Type::Word(8));
+    let typed = typecheck_driver("mod d of Delay[4]; d.inp := p->bits(); nibble := d.out;", "nibble").unwrap();
+    assert_eq!(typed.typ(), Type::Word(4));
+
+    let err = |decls: &str, name: &str| format!("{:?}", typecheck_driver(decls, name).unwrap_err());
+    assert!(err("mod d of Delay[4]; d.inp := a; nibble := d.out;", "d.inp").contains("Wrong types: a is Word[4] vs Word[8]"));
+    assert!(err("mod d of Delay[4]; d.inp := 0->zext[8](); nibble := d.out;", "d.inp").contains("Can't infer the width of 0"));
+}
+
+#[test]
+fn typecheck_fn_calls() {
+    let typed = typecheck_driver("out := max(a, p->bits());", "out").unwrap();
+    assert!(matches!(typed.as_ref(), TypedExpr::FnCall(Type::Word(8), _fndef_id, _args)));
+
+    let err = |decls: &str, name: &str| format!("{:?}", typecheck_driver(decls, name).unwrap_err());
+    assert!(err("out := max(a);", "out").contains("Function max takes 2 arguments"));
+    assert!(err("out := max(a, a)->eq(a);", "out").contains("Wrong return type"));
+}
+
+#[test]
+fn typecheck_enum_matches() {
+    let typed = typecheck_driver("out := match Op::from_bits(a[2..0]) { @ADD => 1; else => 0; };", "out").unwrap();
+    assert!(matches!(typed.as_ref(), TypedExpr::Match(Type::Word(8), _subject, _ascription, _arms)));
+
+    let err = |decls: &str| format!("{:?}", typecheck_driver(decls, "out").unwrap_err());
+    assert!(err("out := match Op::from_bits(a[2..0]) { @ADD => 1; };").contains("Match on top::Op is missing arms for @SUB"));
+}
+
+#[test]
+fn typecheck_conversions() {
+    let typed = typecheck_driver("out := Valid::from_bits(cat(a, c))->as(Valid[Word[8]])->bits()->trunc();", "out").unwrap();
+    let TypedExpr::MethodCall(_typ, bits, _method, _args) = typed.as_ref() else { panic!() };
+    assert_eq!(bits.typ(), Type::Word(9));
+    let typed = typecheck_driver("out := a->as(Word[8]);", "out").unwrap();
+    assert!(matches!(typed.as_ref(), TypedExpr::As(Type::Word(8), _subject, _typ)));
+
+    let err = |decls: &str| format!("{:?}", typecheck_driver(decls, "out").unwrap_err());
+    assert!(err("out := Pixel::from_bits(cat(a, c))->bits();").contains("Wrong types: Word[8] vs Word[9]"));
+    assert!(err("out := Valid::from_bits(cat(a, c))->bits()->trunc();").contains("Can't infer the arguments of the generic type in Valid::from_bits"));
+    assert!(err("out := a->as(Word[4])->zext();").contains("Wrong types: a is Word[4] vs Word[8]"));
+}
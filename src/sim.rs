@@ -1,3 +1,16 @@
+//! The simulator isn't built (see `lib.rs`).
+//! It still runs on the old `db` and `elab` modules rather than the phase queries,
+//! so the features below only have Verilog support so far.
+//! Each is a follow-up for when the simulator is ported:
+//!
+//! - Signed methods: `sra`, `sext`, `muls` and `lts`, `ltes`, `gts`, `gtes` must evaluate on two's complement values.
+//! - Fns: calls must evaluate the fn body with its arguments bound, as the Verilog backend inlines them.
+//! - Mems: each mem needs a cell per word, with reads combinational and writes taking effect on the clock edge.
+//! - `->with`: updates must be evaluated by splicing the new bits, elements or fields into a copy of the subject.
+//! - Dynamic `get` and `slice`: bits past the end must read as zero or X, following the module's `#[out_of_range]`.
+//! - Unions: ctors and matches must follow `union_layout`, including its one-hot tags and X padding.
+//! - Asserts and covers: a failed assert must report its cycle and instance path, and cover hit counts must be reported at the end.
+
 use std::collections::HashMap;
 use crate::common::*;
 use crate::types::Type;
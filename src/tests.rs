@@ -1,44 +1,203 @@
-/*
-use crate::common::*;
-use crate::parse::{parse_package, parse_expr};
-use crate::phase::*;
+use crate::phase::Db;
+use crate::phase::check::CheckQ;
+
+/// These examples were already broken before they were checked here.
+/// `foo` compares a `Word[2]` with a `Word[1]`, and `hello` and `structs` use struct literals, which have no Verilog yet.
+const BROKEN_EXAMPLES: &[&str] = &["foo.vir", "hello.vir", "structs.vir"];
 
 #[test]
 fn test_examples() {
-    let examples_dir = std::path::Path::new("examples");
     let mut errors = vec![];
-    let mut db = Db::default();
-
-    if let Ok(entries) = std::fs::read_dir(examples_dir) {
-        for entry in entries {
-            if let Ok(entry) = entry {
-                let filename = entry.file_name();
-                if let Some(filename) = filename.to_str() {
-                    if filename.ends_with(".vir") {
-                        let text = match std::fs::read_to_string(entry.path()) {
-                            Ok(text) => text,
-                            Err(_) => panic!("Failed to read file {:?}", entry.path()),
-                        };
-                        db.set_source(Arc::new(text.to_string()));
-
-                        if let Err(_error) = std::panic::catch_unwind(|| {
-                            db.check().unwrap();
-                        }) {
-                            errors.push(filename.to_string());
-                        }
-                    }
-                }
-            }
+    let mut entries: Vec<_> = std::fs::read_dir("examples").unwrap().map(|entry| entry.unwrap().path()).collect();
+    entries.sort();
+
+    for path in entries {
+        let filename = path.file_name().unwrap().to_string_lossy().to_string();
+        if !filename.ends_with(".vir") || BROKEN_EXAMPLES.contains(&filename.as_str()) {
+            continue;
+        }
+
+        let result = std::panic::catch_unwind(|| {
+            let db = Db::load(&path)?;
+            db.check()?;
+            db.verilog(&mut vec![])
+        });
+        match result {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => errors.push(format!("{filename}: {e:?}")),
+            Err(_) => errors.push(format!("{filename}: panicked")),
         }
-    } else {
-        panic!("Failed to read examples directory");
     }
 
-    if errors.len() > 0 {
+    if !errors.is_empty() {
         panic!("Errors in examples:\n  - {}", errors.join("\n  - "))
     }
 }
 
+#[test]
+fn test_match_bind_arm() {
+    let mut db = Db::new();
+    db.set_source("top", "
+        enum type State : Word[1] {
+            Idle = 0;
+            Busy = 1;
+        }
+
+        mod Top {
+            incoming state : State;
+            outgoing out : Word[1];
+            out := match state {
+                @Idle() => 1;
+                s => 0;
+            };
+        }
+    ");
+
+    let err = db.check().unwrap_err();
+    assert!(format!("{err:?}").contains("Match arm binds the whole subject to s"));
+}
+
+#[test]
+fn test_instantiation_cycles() {
+    let db_for = |source: &str| {
+        let mut db = Db::new();
+        db.set_source("top", source);
+        db
+    };
+
+    let db = db_for("
+        mod Top {
+            mod a of A[4];
+        }
+
+        mod A[N] {
+            mod a of A[N];
+        }
+    ");
+    let err = db.check().unwrap_err();
+    assert!(format!("{err:?}").contains("Module instantiation cycle: top::A[4] -> top::A[4]"));
+    assert!(db.verilog(&mut vec![]).is_err());
+
+    let db = db_for("
+        mod Top {
+            mod b of B;
+        }
+
+        mod B {
+            mod c of C;
+        }
+
+        mod C {
+            mod b of B;
+        }
+    ");
+    let err = db.check().unwrap_err();
+    assert!(format!("{err:?}").contains("Module instantiation cycle: top::B -> top::C -> top::B"));
+
+    let db = db_for("
+        mod Top {
+            mod t of Tree[4];
+        }
+
+        mod Tree[N] {
+            for i in 0..N {
+                mod leaf[i] of Tree[0];
+            }
+        }
+    ");
+    db.check().unwrap();
+}
+
+#[test]
+fn test_unchecked_moddefs() {
+    let mut db = Db::new();
+    db.set_source("top", "
+        mod Top {
+            mod used of Used[8];
+        }
+
+        mod Used[W] {
+            incoming inp : Word[W];
+        }
+
+        mod Unused[W] {
+            incoming inp : Word[W];
+            outgoing out : Word[8];
+            out := missing;
+        }
+    ");
+    // The unknown variable in Unused is only reported once Unused is instantiated.
+    db.check().unwrap();
+    let unchecked: Vec<String> = db.unchecked_moddefs().unwrap().iter().map(|moddef_id| moddef_id.to_string()).collect();
+    assert_eq!(unchecked, vec!["top::Unused"]);
+}
+
+#[test]
+fn test_when_errors() {
+    let mut db = Db::new();
+    db.set_source("top", "
+        mod Top {
+            incoming c : Word[1];
+            incoming a : Word[8];
+            outgoing out : Word[8];
+            when c {
+                out := a;
+            }
+        }
+    ");
+
+    let err = format!("{:?}", db.check().unwrap_err());
+    assert!(err.contains("Some([6:13:top])"));
+    assert!(err.contains("out is not driven in every branch of a when"));
+    assert!(!err.contains("Failed Check"));
+
+    let err_for = |decls: &str| {
+        let mut db = Db::new();
+        db.set_source("top", &format!("
+            mod Top {{
+                incoming clock : Clock;
+                incoming c : Word[1];
+                incoming a : Word[8];
+                outgoing out : Word[8];
+                reg r : Word[8] on clock;
+                reg s : Word[8] on clock;
+                {decls}
+            }}
+        "));
+        format!("{:?}", db.check().unwrap_err())
+    };
+
+    assert!(err_for("when c { r <= a; s <= a; out := a; }").contains("out is not driven in every branch of a when"));
+    assert!(err_for("when c { r <= a; r <= s; } out := a; s <= a;").contains("r is driven more than once inside of a when"));
+    assert!(err_for("when c { node n : Word[8]; n := a; } out := a; r <= a; s <= a;").contains("Only wires, mem writes, asserts and covers may appear inside of a when"));
+    assert!(err_for("r <= a; when c { r <= s; } s <= a; out := a;").contains("r is driven both inside and outside of a when"));
+    assert!(err_for("when c { r <= a; } when c { r <= s; } s <= a; out := a;").contains("r is driven inside of more than one when"));
+}
+
+#[test]
+fn test_indexed_names() {
+    let mut db = Db::new();
+    db.set_source("top", "
+        mod Top {
+            incoming clock : Clock;
+            incoming a : Word[8];
+            outgoing out : Word[1];
+            reg r[j] : Word[8] on clock;
+            r[j] <= a;
+            out := a[3];
+        }
+    ");
+
+    let err = format!("{:?}", db.check().unwrap_err());
+    assert!(err.contains("r[j] is indexed by j, which is not the variable of an enclosing for"));
+    assert!(!err.contains("a[3]"));
+}
+
+/*
+use crate::common::*;
+use crate::parse::{parse_package, parse_expr};
+use crate::phase::*;
+
 #[test]
 fn test_parse_exprs() {
     let expr_strs = vec![
@@ -1,70 +1,297 @@
+use std::collections::HashSet;
 use std::io::Write;
 
 use crate::common::*;
 use crate::context::Context;
+use crate::ast;
+use crate::virdant_error;
+use crate::virdant_error_at;
+use crate::verilog_header::*;
+use crate::verilog_ast::*;
 
 use crate::phase::*;
 use crate::phase::check::CheckQ;
 use crate::phase::astq::*;
 use crate::phase::item_resolution::*;
+use crate::phase::type_resolution::TypeResolutionQ;
 use crate::phase::typecheck::*;
 use crate::phase::structure::*;
 use crate::phase::layout::*;
-
-type SsaName = String;
+use crate::phase::sourceq::SourceQ;
 
 impl Db {
     pub fn verilog<F: Write>(&self, writer: &mut F) -> VirdantResult<()> {
-        let mut verilog = Verilog {
-            writer,
-            db: self,
-            gensym: 0,
-        };
+        self.check()?;
+        self.check_ext_moddefs()?;
+        let verilog_file = Verilog::new(self).verilog_packages()?;
+        verilog_file.write(writer)?;
+        Ok(())
+    }
+
+    /// Writes each module to `<out_dir>/<Module>.v`, together with a `filelist.f`
+    /// listing every source (including the sources of `ext mod`s) in dependency order.
+    pub fn verilog_files(&self, out_dir: &std::path::Path) -> VirdantResult<()> {
+        self.check()?;
+        self.check_ext_moddefs()?;
+        std::fs::create_dir_all(out_dir)?;
+
+        let mut filelist: Vec<std::path::PathBuf> = vec![];
+        for moddef_id in moddefs_in_dependency_order(self)? {
+            let moddef_name = verilog_moddef_name(&moddef_id);
+            let moddef = self.structure_moddef(moddef_id.clone())?;
+
+            let filename = if let Some(ext_binding) = moddef.ext_binding() {
+                // Several ext mods may share a source.
+                if filelist.contains(&ext_binding.source()) {
+                    continue;
+                }
+                ext_binding.source()
+            } else {
+                let filename = out_dir.join(format!("{moddef_name}.v"));
+                let verilog_file = VerilogFile {
+                    items: vec![Verilog::new(self).verilog_moddef(moddef_id.clone())?],
+                };
+                let mut file = std::fs::File::create(&filename)?;
+                verilog_file.write(&mut file)?;
+                filename
+            };
 
-        verilog.db.check()?;
-        verilog.verilog_packages()?;
+            if filelist.contains(&filename) {
+                return Err(virdant_error!("Duplicate Verilog module name: {moddef_name}"));
+            }
+            filelist.push(filename);
+        }
+
+        let mut file = std::fs::File::create(out_dir.join("filelist.f"))?;
+        for filename in &filelist {
+            writeln!(file, "{}", filename.to_string_lossy())?;
+        }
         Ok(())
     }
+
+    /// Checks that the Verilog source of each `ext mod` exists,
+    /// and that it defines a module whose ports and parameters agree with the declaration.
+    pub fn check_ext_moddefs(&self) -> VirdantResult<()> {
+        let mut errors = ErrorReport::new();
+        for moddef_id in self.elaborated_moddefs()? {
+            let moddef = self.structure_moddef(moddef_id.clone())?;
+            if let Some(ext_binding) = moddef.ext_binding() {
+                if let Err(err) = check_ext_moddef(self, &moddef, &ext_binding) {
+                    let span = self.span(self.moddef_ast(moddef_id.clone())?.span());
+                    errors.add(virdant_error_at!("Bad ext mod {moddef_id}", span).because(err));
+                }
+            }
+        }
+        errors.check()
+    }
+}
+
+fn check_ext_moddef(db: &Db, moddef: &ModDef, ext_binding: &ExtBinding) -> VirdantResult<()> {
+    let source = ext_binding.source();
+    let verilog_name = ext_binding.verilog_name();
+    let text = std::fs::read_to_string(&source)
+        .map_err(|_err| virdant_error!("Could not read Verilog source {}", source.to_string_lossy()))?;
+    let header = parse_module_header(&text, &verilog_name)?;
+
+    let mut errors = ErrorReport::new();
+    let pins = moddef_pins(db, moddef)?;
+    for pin in &pins {
+        let port_name = pin.name();
+        let width = db.bitwidth(pin.typ.clone())?;
+        let dir = if pin.incoming { VerilogDir::Input } else { VerilogDir::Output };
+
+        match header.ports.iter().find(|verilog_port| verilog_port.name == port_name.as_str()) {
+            None => errors.add(virdant_error!("Port {port_name} is missing from Verilog module {verilog_name}")),
+            Some(verilog_port) if verilog_port.dir != dir => {
+                errors.add(virdant_error!("Port {port_name} has the wrong direction in Verilog module {verilog_name}"));
+            },
+            Some(VerilogPort { width: Some(verilog_width), .. }) if *verilog_width != width => {
+                errors.add(virdant_error!("Port {port_name} is {width} bits, but {verilog_width} bits in Verilog module {verilog_name}"));
+            },
+            Some(_) => (),
+        }
+    }
+
+    for verilog_port in &header.ports {
+        if !pins.iter().any(|pin| pin.name() == verilog_port.name) {
+            let verilog_port_name = &verilog_port.name;
+            errors.add(virdant_error!("Verilog module {verilog_name} has port {verilog_port_name}, which is not declared"));
+        }
+    }
+
+    for (param, _value) in db.moddef_param_values(moddef.id())? {
+        if !header.params.iter().any(|verilog_param| verilog_param == param.as_str()) {
+            errors.add(virdant_error!("Verilog module {verilog_name} has no parameter {param}"));
+        }
+    }
+
+    for (param, _value) in ext_binding.params() {
+        if !header.params.iter().any(|verilog_param| verilog_param == param.as_str()) {
+            errors.add(virdant_error!("Verilog module {verilog_name} has no parameter {param}"));
+        }
+    }
+
+    errors.check()
+}
+
+/// A port of the Verilog module for a moddef.
+/// Each channel of a port instance is flattened into a pin of its own, as in `inc_inp` for the channel `inp` of `inc`.
+struct Pin {
+    /// The path of the component or channel, as in `inc.inp`.
+    path: Path,
+    incoming: bool,
+    typ: Type,
+}
+
+impl Pin {
+    fn name(&self) -> String {
+        let parts: Vec<String> = self.path.parts().iter().map(|part| part.to_string()).collect();
+        parts.join("_")
+    }
+}
+
+fn moddef_pins(db: &Db, moddef: &ModDef) -> VirdantResult<Vec<Pin>> {
+    let mut pins = vec![];
+    for port in moddef.ports() {
+        pins.push(Pin {
+            path: port.id().name().as_path(),
+            incoming: port.is_incoming(),
+            typ: port.typ(),
+        });
+    }
+    for port in moddef.port_instances() {
+        pins.extend(port_pins(db, &port)?);
+    }
+    Ok(pins)
+}
+
+fn port_pins(db: &Db, port: &Port) -> VirdantResult<Vec<Pin>> {
+    let mut pins = vec![];
+    let portdef_ast = db.portdef_ast(port.portdef())?;
+    for ast::Channel(dir, channel, typ) in &portdef_ast.channels {
+        pins.push(Pin {
+            path: port.id().name().as_path().join(&channel.as_path()),
+            incoming: !port.role().drives(*dir),
+            typ: db.resolve_typ(typ.clone(), port.portdef().package())?,
+        });
+    }
+    Ok(pins)
+}
+
+/// The name of the Verilog module for a moddef.
+/// Each specialization of a parametric moddef gets its own module, as in `Fifo__8_16` for `Fifo[8, 16]`.
+fn verilog_moddef_name(moddef_id: &ModDefId) -> String {
+    let args = moddef_id.args();
+    if args.is_empty() {
+        moddef_id.name().to_string()
+    } else {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        format!("{}__{}", moddef_id.name(), args.join("_"))
+    }
+}
+
+/// Every moddef in the design, ordered so that each one comes after the moddefs it instantiates.
+/// `elaborated_moddefs` has already ruled out instantiation cycles.
+fn moddefs_in_dependency_order(db: &Db) -> VirdantResult<Vec<ModDefId>> {
+    let mut ordered = vec![];
+    for moddef_id in db.elaborated_moddefs()? {
+        visit_moddef(db, moddef_id, &mut ordered)?;
+    }
+    Ok(ordered)
+}
+
+fn visit_moddef(db: &Db, moddef_id: ModDefId, ordered: &mut Vec<ModDefId>) -> VirdantResult<()> {
+    if ordered.contains(&moddef_id) {
+        return Ok(());
+    }
+
+    for submodule in db.structure_moddef(moddef_id.clone())?.submodules() {
+        visit_moddef(db, submodule.moddef(), ordered)?;
+    }
+
+    ordered.push(moddef_id);
+    Ok(())
 }
 
 struct Verilog<'a> {
-    writer: &'a mut dyn Write,
     db: &'a Db,
+    statements: Vec<VerilogStatement>,
+    /// The name of the wire whose driver is being lowered.
+    /// Intermediate wires are named after it, and numbered from the start of its driver,
+    /// so that their names stay put when unrelated parts of the module change.
+    destination: String,
     gensym: usize,
+    /// The intermediate wires of the module being lowered, which the passes may remove.
+    temporaries: HashSet<String>,
+    /// The names declared by the module being lowered, which intermediate wires must not take.
+    declared: HashSet<String>,
+    /// The package of the module being lowered.
+    package: Option<PackageId>,
+    /// The constants and enum values referenced by the module being lowered, emitted as `localparam`s.
+    localparams: Vec<(String, Width, u64)>,
+    /// What a dynamic index past the end of its subject reads in the module being lowered.
+    out_of_range: OutOfRange,
 }
 
 impl<'a> Verilog<'a> {
-    fn verilog_packages(&mut self) -> VirdantResult<()> {
-        for package in self.db.packages() {
-            for moddef in self.db.package_moddefs(package)? {
-                self.verilog_moddef(moddef)?;
+    fn new(db: &'a Db) -> Verilog<'a> {
+        Verilog {
+            db,
+            statements: vec![],
+            destination: String::new(),
+            gensym: 0,
+            temporaries: HashSet::new(),
+            declared: HashSet::new(),
+            package: None,
+            localparams: vec![],
+            out_of_range: OutOfRange::default(),
+        }
+    }
+
+    fn verilog_packages(&mut self) -> VirdantResult<VerilogFile> {
+        let mut items = vec![];
+        for moddef in self.db.elaborated_moddefs()? {
+            let item = self.verilog_moddef(moddef)?;
+            // Specializations of an ext mod share their source.
+            if !items.contains(&item) {
+                items.push(item);
             }
         }
-        Ok(())
+        Ok(VerilogFile { items })
     }
 
-    fn verilog_moddef(&mut self, moddef_id: ModDefId) -> VirdantResult<()> {
-        let moddef_name: Ident = moddef_id.name();
-        let moddef = self.db.structure_moddef(moddef_id)?;
+    fn verilog_moddef(&mut self, moddef_id: ModDefId) -> VirdantResult<VerilogItem> {
+        let moddef_name = verilog_moddef_name(&moddef_id);
+        let moddef = self.db.structure_moddef(moddef_id.clone())?;
 
-        if moddef.is_ext() {
-            writeln!(self.writer, "`include \"ext/{moddef_name}.v\"")?;
-            writeln!(self.writer)?;
-            return Ok(());
+        if let Some(ext_binding) = moddef.ext_binding() {
+            return Ok(VerilogItem::Include(ext_binding.source().to_string_lossy().to_string()));
         }
 
-        writeln!(self.writer, "module {}(", moddef_name.clone())?;
-        let ports = moddef.ports();
-        for (i, port) in ports.iter().enumerate() {
-            let is_last = i + 1 == ports.len();
-            self.verilog_port(port.clone(), is_last)?;
+        let mut ports = vec![];
+        for pin in moddef_pins(self.db, &moddef)? {
+            ports.push(VerilogPortDecl {
+                name: pin.name(),
+                dir: if pin.incoming { Dir::Input } else { Dir::Output },
+                width: self.db.bitwidth(pin.typ)?,
+            });
         }
-        writeln!(self.writer, ");")?;
 
+        self.statements = vec![];
+        self.declared = self.declared_names(&moddef)?;
+        self.package = Some(moddef_id.package());
+        self.localparams = vec![];
+        self.out_of_range = moddef.out_of_range();
         for submodule in moddef.submodules() {
             self.verilog_submodule(submodule)?;
         }
 
+        // Mems are declared first, since Verilog doesn't allow arrays to be used before they are declared.
+        for component in moddef.components() {
+            if component.is_mem() {
+                self.verilog_component(component)?;
+            }
+        }
 
         for component in moddef.components() {
             if component.is_outgoing() || component.is_reg() || component.is_node() {
@@ -72,373 +299,1475 @@ impl<'a> Verilog<'a> {
             }
         }
 
-        writeln!(self.writer, "endmodule")?;
-        writeln!(self.writer)?;
-        Ok(())
-    }
+        for port in moddef.port_instances() {
+            self.verilog_port(port)?;
+        }
 
-    fn verilog_port(&mut self, port: Component, is_last_port: bool) -> VirdantResult<()> {
-        let direction = if port.is_incoming() {
-            "input  "
-        } else {
-            "output "
+        for (i, property) in moddef.properties().into_iter().enumerate() {
+            self.verilog_property(property, i + 1)?;
+        }
+
+        let mut statements = vec![];
+        for (name, width, value) in std::mem::take(&mut self.localparams) {
+            statements.push(VerilogStatement::Localparam(name, width, VerilogExpr::Const(value, width)));
+        }
+        if !statements.is_empty() {
+            statements.push(VerilogStatement::Blank);
+        }
+        statements.extend(std::mem::take(&mut self.statements));
+
+        let mut verilog_moddef = VerilogModDef {
+            name: moddef_name,
+            ports,
+            statements,
+            temporaries: std::mem::take(&mut self.temporaries),
         };
-        let port_name = port.id().name();
-        let typ = port.typ();
-
-        if let Type::Word(1) = typ {
-            write!(self.writer, "    {direction} wire            {port_name}")?;
-        } else if let Type::Word(n) = typ {
-            let max_bit = n - 1;
-            let width_str = format!("[{max_bit}:0]");
-            let padded_width_str = format!("{width_str: >8}");
-            write!(self.writer, "    {direction} wire  {padded_width_str} {port_name}")?;
-        } else if let Type::Clock = typ {
-            write!(self.writer, "    {direction} wire            {port_name}")?;
-        } else {
-            let width_str = make_width_str(self.db, typ.clone());
-            write!(self.writer, "    {direction} wire  {width_str}     {port_name}")?;
+        verilog_moddef.run_passes();
+        Ok(VerilogItem::ModDef(verilog_moddef))
+    }
+
+    /// The components and pins of a module, together with the wires bridging to the pins of its submodules.
+    fn declared_names(&self, moddef: &ModDef) -> VirdantResult<HashSet<String>> {
+        let mut names: HashSet<String> = moddef.components().iter().map(|component| component.id().name().to_string()).collect();
+        names.extend(moddef_pins(self.db, moddef)?.iter().map(|pin| pin.name()));
+        for submodule in moddef.submodules() {
+            let submodule_name = submodule.id().name();
+            for pin in moddef_pins(self.db, &self.db.structure_moddef(submodule.moddef())?)? {
+                let pin_name = pin.name();
+                names.insert(format!("{submodule_name}__{pin_name}"));
+            }
         }
+        Ok(names)
+    }
 
-        if is_last_port {
-            writeln!(self.writer)?;
+    /// Names from other packages are prefixed with their package name.
+    fn verilog_item_name(&self, item_id: ItemId, name: &str) -> String {
+        if self.package.as_ref() == Some(&item_id.package()) {
+            name.to_string()
         } else {
-            writeln!(self.writer, ",")?;
+            format!("{}__{name}", item_id.package())
         }
+    }
 
-        Ok(())
+    /// The name of the `localparam` for an enum value, as in `Op__ADD`.
+    fn verilog_enum_value(&mut self, enumdef_id: &EnumDefId, value: &Ident) -> VirdantResult<VerilogExpr> {
+        let width = self.db.enumdef_width(enumdef_id.clone())?;
+        let values = self.db.enumdef_values(enumdef_id.clone())?;
+        let (_name, encoding) = values.iter().find(|(name, _encoding)| name == value).unwrap();
+        let name = self.verilog_item_name(enumdef_id.as_item(), &format!("{}__{value}", enumdef_id.name()));
+        Ok(self.localparam(name, width, *encoding))
+    }
+
+    /// Declares a `localparam` at the top of the module being lowered, and returns a reference to it.
+    fn localparam(&mut self, name: String, width: Width, value: u64) -> VerilogExpr {
+        if !self.localparams.iter().any(|(other, _width, _value)| other == &name) {
+            self.localparams.push((name.clone(), width, value));
+        }
+        VerilogExpr::Ref(name)
     }
 
     fn verilog_component(&mut self, component: Component) -> VirdantResult<()> {
+        let component_name = component.id().name().to_string();
+        let typ = component.typ();
+        let width = self.db.bitwidth(typ.clone())?;
+
         if component.is_outgoing() {
             let expr = component.driver().unwrap();
-            let typ = component.typ();
-            let component_name = component.id().name();
-            writeln!(self.writer, "    // outgoing {component_name} : {typ}")?;
+            self.emit(VerilogStatement::Comment(format!("outgoing {component_name} : {typ}")));
+            self.set_destination(&component_name);
             let ssa = self.verilog_expr(expr, Context::empty())?;
-            writeln!(self.writer, "    assign {component_name} = {ssa};")?;
-            writeln!(self.writer)?;
+            self.emit(VerilogStatement::Assign(component_name, ssa));
+            self.emit(VerilogStatement::Blank);
         } else if component.is_node() {
             let expr = component.driver().unwrap();
-            let typ = expr.typ();
-            let width_str = make_width_str(self.db, typ.clone());
-            let component_name = component.id().name();
-            writeln!(self.writer, "    // node {component_name} : {typ}")?;
-            writeln!(self.writer, "    wire {width_str} {component_name};")?;
+            self.emit(VerilogStatement::Comment(format!("node {component_name} : {typ}")));
+            self.emit(VerilogStatement::Wire(component_name.clone(), width, None));
+            self.set_destination(&component_name);
             let ssa = self.verilog_expr(expr, Context::empty())?;
-            writeln!(self.writer, "    assign {component_name} = {ssa};")?;
-            writeln!(self.writer)?;
+            self.emit(VerilogStatement::Assign(component_name, ssa));
+            self.emit(VerilogStatement::Blank);
         } else if component.is_reg() {
             let expr = component.driver().unwrap();
-            let typ = expr.typ();
-            let width_str = make_width_str(self.db, typ.clone());
-            let component_name = component.id().name();
-            writeln!(self.writer, "    // reg {component_name} : {typ}")?;
-            writeln!(self.writer, "    reg  {width_str} {component_name};")?;
-            let clk = "clock"; //component.clock().unwrap();
+            self.emit(VerilogStatement::Comment(format!("reg {component_name} : {typ}")));
+            self.emit(VerilogStatement::Reg(component_name.clone(), width, None));
+            let clk = VerilogExpr::Ref("clock".to_string()); //component.clock().unwrap();
+            self.set_destination(&component_name);
             let connect_ssa = self.verilog_expr(expr.clone(), Context::empty())?;
-            writeln!(self.writer, "    always @(posedge {clk}) begin")?;
-            writeln!(self.writer, "        {component_name} <= {connect_ssa};")?;
-            writeln!(self.writer, "    end")?;
-            writeln!(self.writer)?;
-            writeln!(self.writer, "    initial begin")?;
-            writeln!(self.writer, "        {component_name} <= 1;")?;
-            writeln!(self.writer, "    end")?;
-            writeln!(self.writer)?;
+            self.emit(VerilogStatement::AlwaysAtPosedge(clk, vec![
+                VerilogStatement::NonblockingAssign(component_name.clone(), connect_ssa),
+            ]));
+            self.emit(VerilogStatement::Blank);
+            self.emit(VerilogStatement::Initial(vec![
+                VerilogStatement::NonblockingAssign(component_name, VerilogExpr::Const(1, width)),
+            ]));
+            self.emit(VerilogStatement::Blank);
+        } else if component.is_mem() {
+            let Type::Vec(elem_typ, depth) = typ.clone() else { unreachable!() };
+            let elem_width = self.db.bitwidth(elem_typ.as_ref().clone())?;
+            self.emit(VerilogStatement::Comment(format!("mem {component_name} : {typ}")));
+            self.emit(VerilogStatement::Memory(component_name.clone(), elem_width, depth));
+            if let Some(init) = component.init() {
+                self.emit(VerilogStatement::Initial(vec![
+                    VerilogStatement::SystemTask("readmemh".to_string(), vec![
+                        VerilogExpr::Str(init.to_string_lossy().to_string()),
+                        VerilogExpr::Ref(component_name.clone()),
+                    ]),
+                ]));
+            }
+
+            // Each write port gets its own always block, in the style synthesis tools infer block RAM from.
+            self.set_destination(&component_name);
+            let clock_ssa = self.verilog_expr(component.clock().unwrap(), Context::empty())?;
+            for write in component.writes() {
+                let addr_ssa = self.verilog_expr(write.addr(), Context::empty())?;
+                let data_ssa = self.verilog_expr(write.data(), Context::empty())?;
+                let mut statement = VerilogStatement::MemWrite(component_name.clone(), addr_ssa, data_ssa);
+                if let Some(enable) = write.enable() {
+                    let enable_ssa = self.verilog_expr(enable, Context::empty())?;
+                    statement = VerilogStatement::If(enable_ssa, vec![statement]);
+                }
+                self.emit(VerilogStatement::AlwaysAtPosedge(clock_ssa.clone(), vec![statement]));
+            }
+            self.emit(VerilogStatement::Blank);
         }
 
         Ok(())
     }
 
+    /// An assert reports an `$error` on each clock edge where its condition doesn't hold,
+    /// along with where it was declared and the instance which failed.
+    /// A cover counts the clock edges where its condition holds in a reg, as in `cover_1__hits`.
+    fn verilog_property(&mut self, property: Property, n: usize) -> VirdantResult<()> {
+        let kind = if property.is_assert() { "assert" } else { "cover" };
+        let name = format!("{kind}_{n}");
+        let span = property.span();
+        self.emit(VerilogStatement::Comment(format!("{kind} at {span}")));
+        self.set_destination(&name);
+        let clock_ssa = self.verilog_expr(property.clock(), Context::empty())?;
+        let cond_ssa = self.verilog_expr(property.cond(), Context::empty())?;
+        let enable_ssa = property.enable().map(|enable| self.verilog_expr(enable, Context::empty())).transpose()?;
+        let enabled = |e: VerilogExpr| match enable_ssa {
+            Some(enable_ssa) => VerilogExpr::Binop(Binop::And, Box::new(enable_ssa), Box::new(e)),
+            None => e,
+        };
+
+        let statement = if property.is_assert() {
+            let failed = enabled(VerilogExpr::Unop(Unop::Not, Box::new(cond_ssa)));
+            let message = VerilogExpr::Str(format!("Assertion failed at {span} in %m"));
+            VerilogStatement::If(failed, vec![
+                VerilogStatement::SystemTask("display".to_string(), vec![message]),
+                VerilogStatement::SystemTask("stop".to_string(), vec![]),
+            ])
+        } else {
+            let hits = format!("{name}__hits");
+            self.emit(VerilogStatement::Reg(hits.clone(), 32, Some(VerilogExpr::Const(0, 32))));
+            let message = VerilogExpr::Str(format!("Cover at {span} in %m was hit %0d times"));
+            self.emit(VerilogStatement::Final(vec![
+                VerilogStatement::SystemTask("display".to_string(), vec![message, VerilogExpr::Ref(hits.clone())]),
+            ]));
+            let incremented = VerilogExpr::Binop(Binop::Add, Box::new(VerilogExpr::Ref(hits.clone())), Box::new(VerilogExpr::Const(1, 32)));
+            VerilogStatement::If(enabled(cond_ssa), vec![VerilogStatement::NonblockingAssign(hits, incremented)])
+        };
+        self.emit(VerilogStatement::AlwaysAtPosedge(clock_ssa, vec![statement]));
+        self.emit(VerilogStatement::Blank);
+        Ok(())
+    }
+
+    /// Drives the channels of a port which flow out of the module.
+    fn verilog_port(&mut self, port: Port) -> VirdantResult<()> {
+        self.emit(VerilogStatement::Comment(format!("port {} of {}", port.id().name(), port.portdef())));
+        for pin in port_pins(self.db, &port)? {
+            if !pin.incoming {
+                let pin_name = pin.name();
+                self.set_destination(&pin_name);
+                let ssa = self.verilog_expr(port.driver_for(pin.path), Context::empty())?;
+                self.emit(VerilogStatement::Assign(pin_name, ssa));
+            }
+        }
+        self.emit(VerilogStatement::Blank);
+        Ok(())
+    }
+
     fn verilog_submodule(&mut self, submodule: Submodule) -> VirdantResult<()> {
-        writeln!(self.writer, "    // Submodule {} of {}", submodule.id(), submodule.moddef())?;
-        let moddef_id = submodule.moddef();
+        self.emit(VerilogStatement::Comment(format!("Submodule {} of {}", submodule.id(), submodule.moddef())));
         let submodule_moddef = self.db.structure_moddef(submodule.moddef())?;
-        let ports = submodule_moddef.ports();
+        let ports = moddef_pins(self.db, &submodule_moddef)?;
+        let submodule_name = submodule.id().name();
 
         // Create wires which bridge between the module and the submodule
         for port in &ports {
-            let typ = port.typ();
-            let width_str = make_width_str(self.db, typ);
-            let submodule_name = submodule.id().name();
-            let port_name = port.id().name();
-            writeln!(self.writer, "    wire {width_str} __TEMP_{submodule_name}_{port_name};")?;
+            let width = self.db.bitwidth(port.typ.clone())?;
+            let port_name = port.name();
+            self.emit(VerilogStatement::Wire(format!("{submodule_name}__{port_name}"), width, None));
         }
 
         // Create drive the submodule's incoming ports.
         for port in &ports {
-            if port.is_incoming() {
-                let path = submodule.id().name().as_path().join(&port.id().name().as_path());
-                eprintln!("IN MODULE {moddef_id}");
-                eprintln!("INST'ING MODULE {}", submodule_moddef.id());
-                eprintln!("LOOKING FOR DRIVER FOR {path}");
+            if port.incoming {
+                let path = submodule_name.as_path().join(&port.path);
                 let expr = submodule.driver_for(path);
+                let port_name = port.name();
+                self.set_destination(&format!("{submodule_name}__{port_name}"));
                 let gs = self.verilog_expr(expr, Context::empty())?;
-                let submodule_name = submodule.id().name();
-                let port_name = port.id().name();
-                writeln!(self.writer, "    assign __TEMP_{submodule_name}_{port_name} = {gs};")?;
+                self.emit(VerilogStatement::Assign(format!("{submodule_name}__{port_name}"), gs));
             }
         }
 
-
         // Instantiate the module and connect the intermediary wires.
-        writeln!(self.writer, "    {} {}(", submodule_moddef.id().name(), submodule.id().name())?;
-        for (i, port) in ports.iter().enumerate() {
-            let last_port = i + 1 == ports.len();
-            let submodule_name = submodule.id().name();
-            let port_name = port.id().name();
-            write!(self.writer, "        .{port_name}(__TEMP_{submodule_name}_{port_name})")?;
-            if last_port {
-                writeln!(self.writer)?;
-            } else {
-                writeln!(self.writer, ",")?;
-            }
-        }
-        writeln!(self.writer, "    );")?;
+        let (verilog_name, params) = if let Some(ext_binding) = submodule_moddef.ext_binding() {
+            // The parameters of a parametric ext mod are passed along as Verilog parameters.
+            let mut params: Vec<(String, VerilogExpr)> = self.db.moddef_param_values(submodule.moddef())?
+                .into_iter()
+                .map(|(param, value)| (param.to_string(), VerilogExpr::Int(value)))
+                .collect();
+            params.extend(ext_binding.params().iter().map(|(param, value)| {
+                let value = match value {
+                    ast::AttrValue::Str(s) => VerilogExpr::Str(s.clone()),
+                    ast::AttrValue::Nat(n) => VerilogExpr::Int(*n),
+                };
+                (param.to_string(), value)
+            }));
+            (ext_binding.verilog_name(), params)
+        } else {
+            (verilog_moddef_name(&submodule_moddef.id()), vec![])
+        };
+
+        let connections = ports.iter().map(|port| {
+            let port_name = port.name();
+            (port_name.to_string(), VerilogExpr::Ref(format!("{submodule_name}__{port_name}")))
+        }).collect();
+
+        self.emit(VerilogStatement::Instance(VerilogInstance {
+            moddef: verilog_name,
+            name: submodule_name.to_string(),
+            params,
+            connections,
+        }));
         Ok(())
     }
 
-    fn verilog_expr(&mut self, expr: Arc<TypedExpr>, ctx: Context<Ident, SsaName>) -> VirdantResult<SsaName> {
+    fn verilog_expr(&mut self, expr: Arc<TypedExpr>, ctx: Context<Ident, VerilogExpr>) -> VirdantResult<VerilogExpr> {
         match expr.as_ref() {
             TypedExpr::Reference(_typ, Referent::Local(x)) => {
-                let ssa = ctx.lookup(x).unwrap();
-                Ok(format!("{ssa}"))
+                ctx.lookup(x).ok_or_else(|| virdant_error!("Unbound local {x}"))
             },
             TypedExpr::Reference(_typ, Referent::LocalComponent(component_id)) => {
-                let path: Path = component_id.name().into();
-                Ok(format!("{path}"))
+                Ok(VerilogExpr::Ref(component_id.name().to_string()))
             },
             TypedExpr::Reference(_typ, Referent::NonLocalComponent(submodule_element_id, component_id)) => {
                 let path: Path = submodule_element_id.name().as_path().join(&component_id.name().into());
                 let parts = path.parts();
                 let sm = &parts[0];
                 let port = &parts[1];
-                Ok(format!("__TEMP_{sm}_{port}"))
+                Ok(VerilogExpr::Ref(format!("{sm}__{port}")))
             },
-            TypedExpr::Word(_typ, w) => {
-                let gs = self.gensym();
-                let typ = expr.typ();
-                let width_str = make_width_str(self.db, typ);
-                writeln!(self.writer, "    wire {width_str} {gs} = {};", w.value)?;
-                Ok(gs)
+            TypedExpr::Reference(_typ, Referent::PortChannel(path)) => {
+                let parts: Vec<String> = path.parts().iter().map(|part| part.to_string()).collect();
+                match parts.as_slice() {
+                    [port, channel] => Ok(VerilogExpr::Ref(format!("{port}_{channel}"))),
+                    [sm, port, channel] => Ok(VerilogExpr::Ref(format!("{sm}__{port}_{channel}"))),
+                    _ => unreachable!(),
+                }
+            },
+            TypedExpr::Reference(_typ, Referent::Const(constdef_id)) => {
+                let width = self.db.const_width(constdef_id.clone())?;
+                let value = self.db.const_value(constdef_id.clone())?;
+                let name = self.verilog_item_name(constdef_id.as_item(), constdef_id.name().as_str());
+                Ok(self.localparam(name, width, value))
+            },
+            TypedExpr::Word(typ, w) => {
+                let width = self.db.bitwidth(typ.clone())?;
+                Ok(VerilogExpr::Const(w.value, width))
             },
             TypedExpr::Cat(typ, args) => {
-                let gs = self.gensym();
-                let mut arg_ssas: Vec<SsaName> = vec![];
+                let gs = self.gensym("cat");
+                let mut arg_ssas: Vec<VerilogExpr> = vec![];
                 for arg in args {
                     let arg_ssa = self.verilog_expr(arg.clone(), ctx.clone())?;
                     arg_ssas.push(arg_ssa);
                 }
-                let width_str = make_width_str(self.db, typ.clone());
-                writeln!(self.writer, "    wire {width_str} {gs} = {{{}}};", arg_ssas.join(", "))?;
-                Ok(gs)
+                self.wire(&gs, typ.clone(), VerilogExpr::Cat(arg_ssas))
+            },
+            TypedExpr::With(typ, subject, updates) => {
+                let gs = self.gensym("with");
+                let subject_ssa = self.verilog_expr(subject.clone(), ctx.clone())?;
+                let subject_ssa = self.named(subject_ssa, subject.typ())?;
+
+                let mut slots = vec![];
+                for (update, e) in updates {
+                    let (update_typ, offset) = self.db.update_slot(typ.clone(), update.clone())?;
+                    let width = self.db.bitwidth(update_typ)?;
+                    let e_ssa = self.verilog_expr(e.clone(), ctx.clone())?;
+                    slots.push((offset, width, e_ssa));
+                }
+                slots.sort_by_key(|(offset, _width, _e_ssa)| std::cmp::Reverse(*offset));
+
+                // The bits between the updated parts are taken from the subject, from the most significant down.
+                let mut parts = vec![];
+                let mut end = self.db.bitwidth(typ.clone())?;
+                for (offset, width, e_ssa) in slots {
+                    if offset + width < end {
+                        parts.push(VerilogExpr::Slice(subject_ssa.clone(), end - 1, offset + width));
+                    }
+                    parts.push(e_ssa);
+                    end = offset;
+                }
+                if end > 0 {
+                    parts.push(VerilogExpr::Slice(subject_ssa.clone(), end - 1, 0));
+                }
+                self.wire(&gs, typ.clone(), VerilogExpr::Cat(parts))
             },
             TypedExpr::Idx(_typ, subject, i) => {
-                let gs = self.gensym();
                 let subject_ssa = self.verilog_expr(subject.clone(), ctx)?;
-                writeln!(self.writer, "    wire {gs} = {subject_ssa}[{i}];")?;
-                Ok(gs)
+                let subject_ssa = self.named(subject_ssa, subject.typ())?;
+                Ok(VerilogExpr::Index(subject_ssa, *i))
             },
-            TypedExpr::IdxRange(typ, subject, j, i) => {
-                let gs = self.gensym();
+            TypedExpr::IdxRange(_typ, subject, j, i) => {
                 let subject_ssa = self.verilog_expr(subject.clone(), ctx)?;
+                let subject_ssa = self.named(subject_ssa, subject.typ())?;
                 let end = *j - 1;
-                let width_str = make_width_str(self.db, typ.clone());
-                writeln!(self.writer, "    wire {width_str} {gs} = {subject_ssa}[{end}:{i}];")?;
-                Ok(gs)
+                Ok(VerilogExpr::Slice(subject_ssa, end, *i))
             },
             TypedExpr::MethodCall(_typ, subject, method, args) => {
-                let gs = self.gensym();
+                let gs = self.gensym(method.as_str());
                 let subject_ssa = self.verilog_expr(subject.clone(), ctx.clone())?;
-                let mut args_ssa: Vec<SsaName> = vec![];
-                self.verilog_expr(subject.clone(), ctx.clone())?;
+                let mut args_ssa: Vec<VerilogExpr> = vec![];
                 for arg in args {
                     let arg_ssa = self.verilog_expr(arg.clone(), ctx.clone())?;
                     args_ssa.push(arg_ssa);
                 }
                 let typ = expr.typ();
-                let width_str = make_width_str(self.db, typ.clone());
-
-                match method.as_str() {
-                    "add" => writeln!(self.writer, "    wire {width_str} {gs} = {subject_ssa} + {};", args_ssa[0])?,
-                    "inc" => writeln!(self.writer, "    wire {width_str} {gs} = {subject_ssa} + 1;")?,
-                    "dec" => writeln!(self.writer, "    wire {width_str} {gs} = {subject_ssa} - 1;")?,
-                    "sub" => writeln!(self.writer, "    wire {width_str} {gs} = {subject_ssa} - {};", args_ssa[0])?,
-                    "and" => writeln!(self.writer, "    wire {width_str} {gs} = {subject_ssa} & {};", args_ssa[0])?,
-                    "or"  => writeln!(self.writer, "    wire {width_str} {gs} = {subject_ssa} | {};", args_ssa[0])?,
-                    "not" => writeln!(self.writer, "    wire {width_str} {gs} = ~{subject_ssa};")?,
-                    "xor" => writeln!(self.writer, "    wire {width_str} {gs} = {subject_ssa} ^ {};", args_ssa[0])?,
-                    "eq"  => writeln!(self.writer, "    wire {width_str} {gs} = {subject_ssa} == {};", args_ssa[0])?,
-                    "mux" => writeln!(self.writer, "    wire {width_str} {gs} = {subject_ssa} ? {};", args_ssa.join(" : "))?,
-                    "sll" => writeln!(self.writer, "    wire {width_str} {gs} = {subject_ssa} << {};", args_ssa.join(" : "))?,
-                    "srl" => writeln!(self.writer, "    wire {width_str} {gs} = {subject_ssa} >> {};", args_ssa.join(" : "))?,
-                    "lt"  => writeln!(self.writer, "    wire {width_str} {gs} = {subject_ssa} < {};", args_ssa.join(" : "))?,
-                    "lte" => writeln!(self.writer, "    wire {width_str} {gs} = {subject_ssa} <= {};", args_ssa.join(" : "))?,
-                    "gt"  => writeln!(self.writer, "    wire {width_str} {gs} = {subject_ssa} > {};", args_ssa.join(" : "))?,
-                    "gte" => writeln!(self.writer, "    wire {width_str} {gs} = {subject_ssa} >= {};", args_ssa.join(" : "))?,
-                    "get" => writeln!(self.writer, "    wire {width_str} {gs} = {subject_ssa}[{}];", args_ssa[0])?,
-                    _ => panic!("Unknown method: {}", method),
-                }
-                Ok(gs)
+                let width = self.db.bitwidth(typ.clone())?;
+
+                let binop = |op: Binop, a: VerilogExpr, b: VerilogExpr| VerilogExpr::Binop(op, Box::new(a), Box::new(b));
+                let signed = |a: VerilogExpr| VerilogExpr::Unop(Unop::Signed, Box::new(a));
+                let subject_width = self.db.bitwidth(subject.typ())?;
+                let e = match method.as_str() {
+                    "add" => binop(Binop::Add, subject_ssa, args_ssa[0].clone()),
+                    "inc" => binop(Binop::Add, subject_ssa, VerilogExpr::Const(1, width)),
+                    "dec" => binop(Binop::Sub, subject_ssa, VerilogExpr::Const(1, width)),
+                    "sub" => binop(Binop::Sub, subject_ssa, args_ssa[0].clone()),
+                    // The operands are widened to the width of the product, since it is assigned straight to a wire.
+                    "mul" => binop(Binop::Mul, subject_ssa, args_ssa[0].clone()),
+                    "muls" => binop(Binop::Mul, signed(subject_ssa), signed(args_ssa[0].clone())),
+                    "and" => binop(Binop::And, subject_ssa, args_ssa[0].clone()),
+                    "or"  => binop(Binop::Or, subject_ssa, args_ssa[0].clone()),
+                    "not" => VerilogExpr::Unop(Unop::Not, Box::new(subject_ssa)),
+                    "neg" => VerilogExpr::Unop(Unop::Neg, Box::new(subject_ssa)),
+                    "xor" => binop(Binop::Xor, subject_ssa, args_ssa[0].clone()),
+                    "eq"  => binop(Binop::Eq, subject_ssa, args_ssa[0].clone()),
+                    "neq" => binop(Binop::Neq, subject_ssa, args_ssa[0].clone()),
+                    "sll" => binop(Binop::Sll, subject_ssa, args_ssa[0].clone()),
+                    "srl" => binop(Binop::Srl, subject_ssa, args_ssa[0].clone()),
+                    "sra" => binop(Binop::Sra, signed(subject_ssa), args_ssa[0].clone()),
+                    "lt" | "ltu"   => binop(Binop::Lt, subject_ssa, args_ssa[0].clone()),
+                    "lte" | "lteu" => binop(Binop::Lte, subject_ssa, args_ssa[0].clone()),
+                    "gt" | "gtu"   => binop(Binop::Gt, subject_ssa, args_ssa[0].clone()),
+                    "gte" | "gteu" => binop(Binop::Gte, subject_ssa, args_ssa[0].clone()),
+                    "lts"  => binop(Binop::Lt, signed(subject_ssa), signed(args_ssa[0].clone())),
+                    "ltes" => binop(Binop::Lte, signed(subject_ssa), signed(args_ssa[0].clone())),
+                    "gts"  => binop(Binop::Gt, signed(subject_ssa), signed(args_ssa[0].clone())),
+                    "gtes" => binop(Binop::Gte, signed(subject_ssa), signed(args_ssa[0].clone())),
+                    "zext" if width == subject_width => subject_ssa,
+                    "zext" => {
+                        let padding = VerilogExpr::Repeat(width - subject_width, Box::new(VerilogExpr::Const(0, 1)));
+                        VerilogExpr::Cat(vec![padding, subject_ssa])
+                    },
+                    "sext" if width == subject_width => subject_ssa,
+                    "sext" => {
+                        let subject_ssa = self.named(subject_ssa, subject.typ())?;
+                        let sign = VerilogExpr::Index(subject_ssa.clone(), subject_width - 1);
+                        let padding = VerilogExpr::Repeat(width - subject_width, Box::new(sign));
+                        VerilogExpr::Cat(vec![padding, VerilogExpr::Ref(subject_ssa)])
+                    },
+                    "trunc" if width == subject_width => subject_ssa,
+                    "trunc" => {
+                        let subject_ssa = self.named(subject_ssa, subject.typ())?;
+                        VerilogExpr::Slice(subject_ssa, width - 1, 0)
+                    },
+                    "bits" => subject_ssa,
+                    "get" | "slice" => {
+                        let subject_ssa = self.named(subject_ssa, subject.typ())?;
+                        let scale = match subject.typ() {
+                            Type::Vec(elem_typ, _n) => self.db.bitwidth(elem_typ.as_ref().clone())?,
+                            _ => 1,
+                        };
+                        let index_width = self.db.bitwidth(args[0].typ())?;
+                        self.dyn_slice(subject_ssa, subject_width, args_ssa[0].clone(), index_width, scale, width)
+                    },
+                    _ => return Err(virdant_error!("No Verilog for method {method}")),
+                };
+                self.wire(&gs, typ, e)
+            },
+            TypedExpr::Ctor(Type::Enum(enumdef_id), ctor, _args) => {
+                self.verilog_enum_value(enumdef_id, ctor)
             },
             TypedExpr::Ctor(typ, ctor, args) => {
-                let gs = self.gensym();
+                let gs = self.gensym(ctor.as_str());
 
                 let layout = self.db.union_layout(typ.clone())?;
 
-                let mut args_ssa: Vec<SsaName> = vec![];
+                let mut args_ssa: Vec<VerilogExpr> = vec![];
                 for arg in args {
                     let arg_ssa = self.verilog_expr(arg.clone(), ctx.clone())?;
                     args_ssa.push(arg_ssa);
                 }
 
-                let width_str = make_width_str(self.db, typ.clone());
-
                 let tag = self.db.union_ctor_tag(typ.clone(), ctor.clone())?;
-                let fill = "1";
-
-                write!(self.writer, "    wire {width_str} {gs} = {{ ")?;
-                for arg_ssa in args_ssa.iter().rev() {
-                    write!(self.writer, "{arg_ssa}, ")?;
-                }
-                let tag_width = layout.tag_width();
-                writeln!(self.writer, "{tag_width}'d{tag} }};")?;
 
+                let mut parts = vec![];
                 if layout.ctor_width(ctor.clone()) < layout.width() {
-                    let bot_bit = layout.ctor_width(ctor.clone());
-                    let top_bit = layout.width() - 1;
-                    let bits = top_bit - bot_bit + 1;
+                    let bits = layout.width() - layout.ctor_width(ctor.clone());
+                    parts.push(match layout.padding() {
+                        UnionPadding::Zero => VerilogExpr::Const(0, bits),
+                        UnionPadding::X => VerilogExpr::X(bits),
+                    });
+                }
+                parts.extend(args_ssa.into_iter().rev());
+                parts.push(VerilogExpr::Const(tag, layout.tag_width()));
 
-                    writeln!(self.writer, "    // fill remaining space with {fill}")?;
-                    writeln!(self.writer, "    assign {gs}[{top_bit}:{bot_bit}] = {bits}'b{};", fill.repeat(bits as usize))?;
+                self.wire(&gs, typ.clone(), VerilogExpr::Cat(parts))
+            },
+            TypedExpr::MemRead(typ, component_id, addr, sync) => {
+                let addr_ssa = self.verilog_expr(addr.clone(), ctx.clone())?;
+                let read = VerilogExpr::DynIndex(component_id.name().to_string(), Box::new(addr_ssa));
+                if !*sync {
+                    let gs = self.gensym("read");
+                    return self.wire(&gs, typ.clone(), read);
                 }
 
-                Ok(gs)
+                // A synchronous read registers the data on the mem's clock.
+                let gs = self.gensym("read_sync");
+                let moddef = self.db.structure_moddef(component_id.moddef())?;
+                let mem = moddef.components().into_iter().find(|component| &component.id() == component_id).unwrap();
+                let clock_ssa = self.verilog_expr(mem.clock().unwrap(), Context::empty())?;
+                self.emit(VerilogStatement::Reg(gs.clone(), self.db.bitwidth(typ.clone())?, None));
+                self.emit(VerilogStatement::AlwaysAtPosedge(clock_ssa, vec![
+                    VerilogStatement::NonblockingAssign(gs.clone(), read),
+                ]));
+                Ok(VerilogExpr::Ref(gs))
+            },
+            TypedExpr::FromBits(_typ, subject) => {
+                self.verilog_expr(subject.clone(), ctx.clone())
             },
             TypedExpr::As(_typ, subject, _typ_ast) => {
                 self.verilog_expr(subject.clone(), ctx.clone())
             },
             TypedExpr::If(_typ, c, a, b) => {
-                let gs = self.gensym();
+                let gs = self.gensym("if");
                 let cond_ssa = self.verilog_expr(c.clone(), ctx.clone())?;
                 let a_ssa = self.verilog_expr(a.clone(), ctx.clone())?;
                 let b_ssa = self.verilog_expr(b.clone(), ctx.clone())?;
-                let typ = expr.typ();
-                let width_str = make_width_str(self.db, typ.clone());
-                writeln!(self.writer, "    wire {width_str} {gs} = {cond_ssa} ? {a_ssa} : {b_ssa};")?;
-                Ok(gs)
+                let e = VerilogExpr::Mux(Box::new(cond_ssa), Box::new(a_ssa), Box::new(b_ssa));
+                self.wire(&gs, expr.typ(), e)
             },
-            TypedExpr::Let(typ, x, _ascription, e, b) => {
-                let gs = self.gensym();
+            TypedExpr::FnCall(typ, fndef_id, args) => {
+                // Calls are inlined, with the arguments bound to the fn's parameters.
+                let gs = self.gensym(fndef_id.name().as_str());
+                let fndef_ast = self.db.fndef_ast(fndef_id.clone())?;
+                let mut fn_ctx = Context::empty();
+                for ((param, _param_typ), arg) in fndef_ast.args.iter().zip(args) {
+                    let arg_ssa = self.verilog_expr(arg.clone(), ctx.clone())?;
+                    fn_ctx = fn_ctx.extend(param.clone(), arg_ssa);
+                }
+                let body_ssa = self.verilog_expr(self.db.fndef_body(fndef_id.clone())?, fn_ctx)?;
+                self.wire(&gs, typ.clone(), body_ssa)
+            },
+            TypedExpr::Let(_typ, x, _ascription, e, b) => {
+                let x_ssa = self.gensym(x.as_str());
+                let start = self.statements.len();
                 let e_ssa = self.verilog_expr(e.clone(), ctx.clone())?;
-                let new_ctx = ctx.extend(x.clone(), e_ssa);
-                let b_ssa = self.verilog_expr(b.clone(), new_ctx)?;
-                let width_str = make_width_str(self.db, typ.clone());
-                writeln!(self.writer, "    wire {width_str} {gs} = {b_ssa};")?;
-                Ok(gs)
+                // When the value was given a wire of its own, that wire takes the name of the variable instead.
+                let x_ssa = match (&e_ssa, self.statements[start..].last_mut()) {
+                    (VerilogExpr::Ref(name), Some(VerilogStatement::Wire(wire, _width, Some(_)))) if name == wire => {
+                        *wire = x_ssa.clone();
+                        VerilogExpr::Ref(x_ssa)
+                    },
+                    _ => self.wire(&x_ssa, e.typ(), e_ssa)?,
+                };
+                let new_ctx = ctx.extend(x.clone(), x_ssa);
+                self.verilog_expr(b.clone(), new_ctx)
             },
             TypedExpr::Match(_typ, subject, _ascription, arms) => {
-                let gs = self.gensym_hint("match");
+                let gs = self.gensym("match");
                 let subject_ssa = self.verilog_expr(subject.clone(), ctx.clone())?;
+                let subject_ssa = self.named(subject_ssa, subject.typ())?;
                 let typ = expr.typ();
+                let width = self.db.bitwidth(typ.clone())?;
+                if let Type::Enum(enumdef_id) = subject.typ() {
+                    return self.verilog_enum_match(gs, enumdef_id, subject_ssa, width, arms, ctx);
+                }
                 let layout = self.db.union_layout(subject.typ())?;
-                let width_str = make_width_str(self.db, typ.clone());
 
-                let tag_ssa = self.gensym();
+                let tag_ssa = self.gensym("tag");
                 let tag_width = layout.tag_width();
-                let tag_top = tag_width - 1;
 
-                let mut arm_ssas: Vec<(Tag, Ident, SsaName)> = vec![];
-                writeln!(self.writer, "    // match arm")?;
+                let mut case_arms = vec![];
+                self.emit(VerilogStatement::Comment("match arm".to_string()));
                 for TypedMatchArm(pat, e) in arms {
                     match pat {
                         TypedPat::At(_typ, ctor, pats) => {
-                            writeln!(self.writer, "    // case {ctor}")?;
+                            self.emit(VerilogStatement::Comment(format!("case {ctor}")));
                             let tag = layout.tag_for(ctor.clone());
                             let mut new_ctx = ctx.clone();
-                            writeln!(self.writer, "    // (pats are {pats:?})")?;
                             for (i, pat) in pats.iter().enumerate() {
                                 let (offset, width) = layout.ctor_slot(ctor.clone(), i);
-                                let width_minus_1 = width - 1;
                                 if let TypedPat::Bind(_typ, x) = pat {
-                                    let x_ssa = self.gensym_hint(&x.to_string());
-                                    new_ctx = new_ctx.extend(x.clone(), x_ssa.clone());
+                                    let x_ssa = self.gensym(x.as_str());
+                                    new_ctx = new_ctx.extend(x.clone(), VerilogExpr::Ref(x_ssa.clone()));
                                     let bot_bit = offset;
                                     let top_bit = offset + width - 1;
-                                    writeln!(self.writer, "    // binding variable {x} to slot")?;
-                                    writeln!(self.writer, "    wire [{width_minus_1}:0] {x_ssa} = {subject_ssa}[{top_bit}:{bot_bit}];")?;
+                                    self.emit(VerilogStatement::Comment(format!("binding variable {x} to slot")));
+                                    let slot = VerilogExpr::Slice(subject_ssa.clone(), top_bit, bot_bit);
+                                    self.emit(VerilogStatement::Wire(x_ssa, width, Some(slot)));
                                 } else {
                                     panic!()
                                 }
                             }
                             let arm_ssa = self.verilog_expr(e.clone(), new_ctx)?;
-                            arm_ssas.push((tag, ctor.clone(), arm_ssa));
+                            case_arms.push(VerilogCaseArm {
+                                label: Some(VerilogExpr::Const(tag, tag_width)),
+                                comment: Some(format!("@{ctor}:")),
+                                body: vec![VerilogStatement::BlockingAssign(gs.clone(), arm_ssa)],
+                            });
+                        },
+                        TypedPat::Otherwise(_typ) => {
+                            let arm_ssa = self.verilog_expr(e.clone(), ctx.clone())?;
+                            case_arms.push(VerilogCaseArm {
+                                label: None,
+                                comment: Some("else:".to_string()),
+                                body: vec![VerilogStatement::BlockingAssign(gs.clone(), arm_ssa)],
+                            });
                         },
                         _ => todo!(),
                     }
                 }
+                if !case_arms.iter().any(|arm| arm.label.is_none()) {
+                    case_arms.push(VerilogCaseArm {
+                        label: None,
+                        comment: None,
+                        body: vec![VerilogStatement::BlockingAssign(gs.clone(), VerilogExpr::X(width))],
+                    });
+                }
 
-                writeln!(self.writer, "    // project tag ({tag_width} bits)")?;
-                let tag_width_str = if tag_width == 1 {
-                    format!("")
-                } else {
-                    format!("[{tag_top}:0]")
-                };
+                self.emit(VerilogStatement::Comment(format!("project tag ({tag_width} bits)")));
+                self.emit(VerilogStatement::Reg(gs.clone(), width, None));
 
-                let subject_tag_idx = if tag_width == 1 {
-                    format!("[0]")
+                let subject_tag = if tag_width == 1 {
+                    VerilogExpr::Index(subject_ssa, 0)
                 } else {
-                    format!("[{tag_top}:0]")
+                    VerilogExpr::Slice(subject_ssa, tag_width - 1, 0)
                 };
+                self.emit(VerilogStatement::Wire(tag_ssa.clone(), tag_width, Some(subject_tag)));
+                self.emit(VerilogStatement::AlwaysComb(vec![
+                    VerilogStatement::Case(VerilogExpr::Ref(tag_ssa), case_arms),
+                ]));
 
-                writeln!(self.writer, "    reg {width_str} {gs};")?;
-
-                writeln!(self.writer, "    wire {tag_width_str} {tag_ssa} = {subject_ssa}{subject_tag_idx};")?;
-
-                writeln!(self.writer, "    always @(*) begin")?;
-                writeln!(self.writer, "        case ({tag_ssa})")?;
-
-                for (tag, ctor, arm_ssa) in &arm_ssas {
-                    writeln!(self.writer, "            // @{ctor}:")?;
-                    writeln!(self.writer, "            {tag}: {gs} <= {arm_ssa};")?;
-                }
-
-                writeln!(self.writer, "            default: {gs} <= 32'bx;")?;
-                writeln!(self.writer, "        endcase")?;
-                writeln!(self.writer, "    end")?;
-
-                Ok(gs)
+                Ok(VerilogExpr::Ref(gs))
             },
-            TypedExpr::Struct(typ, _name, fields) => {
-                let gs = self.gensym();
-                writeln!(self.writer, "    {gs} = ...{expr:?}")?;
+            TypedExpr::Struct(_typ, _name, _fields) => {
                 todo!()
             },
             _ => {
-                let gs = self.gensym();
-                writeln!(self.writer, "    {gs} = ...{expr:?}")?;
                 todo!()
             },
         }
     }
 
-    fn gensym(&mut self) -> SsaName {
-        self.gensym += 1;
-        format!("__TEMP_{}", self.gensym)
+    /// Enums are matched on their encodings directly, with each arm labeled by the value's `localparam`.
+    fn verilog_enum_match(
+        &mut self,
+        gs: String,
+        enumdef_id: EnumDefId,
+        subject_ssa: String,
+        width: Width,
+        arms: &[TypedMatchArm],
+        ctx: Context<Ident, VerilogExpr>,
+    ) -> VirdantResult<VerilogExpr> {
+        let mut case_arms = vec![];
+        for TypedMatchArm(pat, e) in arms {
+            let arm_ssa = self.verilog_expr(e.clone(), ctx.clone())?;
+            let (label, comment) = match pat {
+                TypedPat::At(_typ, value, _pats) => (Some(self.verilog_enum_value(&enumdef_id, value)?), format!("@{value}:")),
+                _ => (None, "else:".to_string()),
+            };
+            case_arms.push(VerilogCaseArm {
+                label,
+                comment: Some(comment),
+                body: vec![VerilogStatement::BlockingAssign(gs.clone(), arm_ssa)],
+            });
+        }
+        // A word converted with from_bits may not be the encoding of any value.
+        if !case_arms.iter().any(|arm| arm.label.is_none()) {
+            case_arms.push(VerilogCaseArm {
+                label: None,
+                comment: None,
+                body: vec![VerilogStatement::BlockingAssign(gs.clone(), VerilogExpr::X(width))],
+            });
+        }
+
+        self.emit(VerilogStatement::Reg(gs.clone(), width, None));
+        self.emit(VerilogStatement::AlwaysComb(vec![
+            VerilogStatement::Case(VerilogExpr::Ref(subject_ssa), case_arms),
+        ]));
+        Ok(VerilogExpr::Ref(gs))
+    }
+
+    fn emit(&mut self, statement: VerilogStatement) {
+        self.statements.push(statement);
+    }
+
+    /// Declares a wire named `name`, driven by `e`, and returns a reference to it.
+    fn wire(&mut self, name: &str, typ: Type, e: VerilogExpr) -> VirdantResult<VerilogExpr> {
+        let width = self.db.bitwidth(typ)?;
+        self.emit(VerilogStatement::Wire(name.to_string(), width, Some(e)));
+        Ok(VerilogExpr::Ref(name.to_string()))
+    }
+
+    fn set_destination(&mut self, destination: &str) {
+        self.destination = destination.to_string();
+        self.gensym = 0;
+    }
+
+    /// A fresh name for an intermediate wire, skipping over any name the module declares itself.
+    fn gensym(&mut self, kind: &str) -> String {
+        loop {
+            self.gensym += 1;
+            let name = format!("{}__{kind}_{}", self.destination, self.gensym);
+            if !self.declared.contains(&name) {
+                self.temporaries.insert(name.clone());
+                return name;
+            }
+        }
+    }
+
+    /// The `width` bits of `subject` starting at bit `index * scale`, where bits past the end read as zero.
+    /// When no index can reach past the end, this is an indexed part-select.
+    /// Otherwise, the subject is shifted down, which fills in the missing bits with zeros.
+    fn dyn_slice(&mut self, subject: String, subject_width: Width, index: VerilogExpr, index_width: Width, scale: Width, width: Width) -> VerilogExpr {
+        let base = if scale == 1 {
+            index
+        } else {
+            // The product is as wide as its widest operand, so the scale is made wide enough to hold it.
+            let scale_width = index_width + (u64::BITS - scale.leading_zeros()) as u64;
+            VerilogExpr::Binop(Binop::Mul, Box::new(index), Box::new(VerilogExpr::Const(scale, scale_width)))
+        };
+
+        // A part-select reads X for the bits past the end of its subject.
+        // When the largest index doesn't even fit in a u64, it may well be past the end.
+        let in_range = (index_width < u64::BITS as Width)
+            .then(|| (1u64 << index_width) - 1)
+            .and_then(|max_index| max_index.checked_mul(scale))
+            .and_then(|max_offset| max_offset.checked_add(width))
+            .is_some_and(|end| end <= subject_width);
+        if in_range || self.out_of_range == OutOfRange::X {
+            if width == 1 {
+                VerilogExpr::DynIndex(subject, Box::new(base))
+            } else {
+                VerilogExpr::DynSlice(subject, Box::new(base), width)
+            }
+        } else {
+            let gs = self.gensym("shifted");
+            let shifted = VerilogExpr::Binop(Binop::Srl, Box::new(VerilogExpr::Ref(subject)), Box::new(base));
+            self.emit(VerilogStatement::Wire(gs.clone(), subject_width, Some(shifted)));
+            if width == 1 {
+                VerilogExpr::Index(gs, 0)
+            } else {
+                VerilogExpr::Slice(gs, width - 1, 0)
+            }
+        }
+    }
+
+    /// Simple expressions are inlined rather than given their own wire.
+    /// Where Verilog needs a name (such as the subject of an index), this binds them to one.
+    fn named(&mut self, e: VerilogExpr, typ: Type) -> VirdantResult<String> {
+        if let VerilogExpr::Ref(name) = e {
+            Ok(name)
+        } else {
+            let gs = self.gensym("tmp");
+            self.wire(&gs, typ, e)?;
+            Ok(gs)
+        }
     }
+}
 
-    fn gensym_hint(&mut self, hint: &str) -> SsaName {
-        self.gensym += 1;
-        format!("__TEMP_{}_{hint}", self.gensym)
+/// The Verilog for a single package named `top`.
+#[cfg(test)]
+fn verilog_of(source: &str) -> VirdantResult<String> {
+    verilog_of_packages(&[("top", source)])
+}
+
+/// The Verilog for the given packages, by name.
+#[cfg(test)]
+fn verilog_of_packages(sources: &[(&str, &str)]) -> VirdantResult<String> {
+    let mut db = Db::new();
+    for (package, source) in sources {
+        db.set_source(package, source);
     }
+    let mut out = vec![];
+    db.verilog(&mut out).map(|()| String::from_utf8(out).unwrap())
 }
 
-fn make_width_str(db: &Db, typ: Type) -> String {
-    let n = db.bitwidth(typ.clone()).unwrap();
-    if n == 1 {
-        "".to_string()
-    } else {
-        let max_bit = n - 1;
-        format!("[{max_bit}:0]")
+#[test]
+fn verilog_files() {
+    let mut db = Db::new();
+    db.set_source("top", "
+        mod Top {
+            incoming clock : Clock;
+            outgoing out : Word[1];
+
+            mod edge of Edge;
+            edge.clock := clock;
+            edge.inp := 1;
+            out := edge.out;
+        }
+
+        mod Edge {
+            incoming clock : Clock;
+            incoming inp : Word[1];
+            outgoing out : Word[1];
+
+            reg last : Word[1] on clock;
+            last <= inp;
+            out := last->not()->and(inp);
+        }
+
+        ext mod Memory {
+            incoming clock : Clock;
+            incoming read_addr : Word[16];
+            outgoing read_data : Word[8];
+        }
+    ");
+    db.set_source_dir("top", std::path::Path::new("examples"));
+
+    let out_dir = std::env::temp_dir().join("virdant_verilog_files");
+    db.verilog_files(&out_dir).unwrap();
+
+    let filelist = std::fs::read_to_string(out_dir.join("filelist.f")).unwrap();
+    let expected = vec![
+        out_dir.join("Edge.v").to_string_lossy().to_string(),
+        out_dir.join("Top.v").to_string_lossy().to_string(),
+        "examples/ext/Memory.v".to_string(),
+    ];
+    assert_eq!(filelist.lines().collect::<Vec<_>>(), expected);
+
+    let top = std::fs::read_to_string(out_dir.join("Top.v")).unwrap();
+    assert!(top.starts_with("module Top("));
+    assert!(!top.contains("module Edge("));
+}
+
+#[test]
+fn verilog_ext_binding() {
+    let ext_dir = std::env::temp_dir().join("virdant_verilog_ext_binding");
+    std::fs::create_dir_all(&ext_dir).unwrap();
+    std::fs::write(ext_dir.join("ram.v"), "
+        module SB_RAM #(parameter DEPTH = 256) (
+            input  wire       clock,
+            input  wire [7:0] addr,
+            output wire [7:0] data
+        );
+        endmodule
+    ").unwrap();
+
+    let top_source = "
+        mod Top {
+            incoming clock : Clock;
+            outgoing out : Word[8];
+            mod ram of Ram;
+            ram.clock := clock;
+            ram.addr := 0;
+            out := ram.data;
+        }
+
+        #[source(\"ram.v\")]
+        #[module(\"SB_RAM\")]
+        #[param(DEPTH = 1024)]
+        ext mod Ram {
+            incoming clock : Clock;
+            incoming addr : Word[8];
+            outgoing data : Word[WIDTH];
+        }
+    ";
+
+    let mut db = Db::new();
+    db.set_source_dir("top", &ext_dir);
+    db.set_source("top", &top_source.replace("WIDTH", "8"));
+
+    let mut output: Vec<u8> = vec![];
+    db.verilog(&mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains(&format!("`include \"{}\"", ext_dir.join("ram.v").to_string_lossy())));
+    assert!(output.contains("SB_RAM #(.DEPTH(1024)) ram("));
+
+    db.set_source("top", &top_source.replace("WIDTH", "16"));
+    assert!(db.verilog(&mut vec![]).is_err());
+}
+
+#[test]
+fn verilog_stable_names() {
+    let verilog_for = |extra: &str| verilog_of(&format!("
+        mod Top {{
+            incoming clock : Clock;
+            incoming inp : Word[8];
+            outgoing out : Word[8];
+            {extra}
+            reg r : Word[8] on clock;
+            r <= inp->add(1)->add(r);
+            out := r;
+        }}
+    ")).unwrap();
+
+    let before = verilog_for("");
+    assert!(before.contains("wire [7:0] r__add_2 = inp + 8'd1;"));
+    assert!(before.contains("wire [7:0] r__add_1 = r__add_2 + r;"));
+    assert!(!before.contains("__TEMP"));
+
+    let after = verilog_for("outgoing out2 : Word[8]; node n : Word[8]; n := inp->sub(3); out2 := n;");
+    assert!(after.contains("wire [7:0] r__add_1 = r__add_2 + r;"));
+    assert!(after.contains("n__sub_1"));
+
+    // An intermediate wire never takes the name of a wire bridging to a pin of a submodule.
+    let verilog = verilog_of("
+        mod Top {
+            incoming inp : Word[8];
+            outgoing out : Word[8];
+            mod x of Adder;
+            x.inp := inp->add(1);
+            x.inp__add_1 := inp;
+            out := x.sum;
+        }
+
+        mod Adder {
+            incoming inp : Word[8];
+            incoming inp__add_1 : Word[8];
+            outgoing sum : Word[8];
+            sum := inp->add(inp__add_1);
+        }
+    ").unwrap();
+    assert!(verilog.contains("wire [7:0] x__inp__add_2 = inp + 8'd1;"));
+    assert!(verilog.contains("assign x__inp__add_1 = inp;"));
+    assert_eq!(verilog.matches("wire [7:0] x__inp__add_1").count(), 1);
+}
+
+#[test]
+fn verilog_methods() {
+    let cases = [
+        ("add", "b", "Word[8]", "wire [7:0] out__add_1 = a + b;"),
+        ("inc", "", "Word[8]", "wire [7:0] out__inc_1 = a + 8'd1;"),
+        ("dec", "", "Word[8]", "wire [7:0] out__dec_1 = a - 8'd1;"),
+        ("sll", "b", "Word[8]", "wire [7:0] out__sll_1 = a << b;"),
+        ("srl", "b", "Word[8]", "wire [7:0] out__srl_1 = a >> b;"),
+        ("sub", "b", "Word[8]", "wire [7:0] out__sub_1 = a - b;"),
+        ("and", "b", "Word[8]", "wire [7:0] out__and_1 = a & b;"),
+        ("or", "b", "Word[8]", "wire [7:0] out__or_1 = a | b;"),
+        ("xor", "b", "Word[8]", "wire [7:0] out__xor_1 = a ^ b;"),
+        ("lt", "b", "Word[1]", "wire out__lt_1 = a < b;"),
+        ("lte", "b", "Word[1]", "wire out__lte_1 = a <= b;"),
+        ("gt", "b", "Word[1]", "wire out__gt_1 = a > b;"),
+        ("gte", "b", "Word[1]", "wire out__gte_1 = a >= b;"),
+        ("eq", "b", "Word[1]", "wire out__eq_1 = a == b;"),
+        ("neq", "b", "Word[1]", "wire out__neq_1 = a != b;"),
+        ("not", "", "Word[8]", "wire [7:0] out__not_1 = ~a;"),
+        ("neg", "", "Word[8]", "wire [7:0] out__neg_1 = -a;"),
+        ("sra", "b", "Word[8]", "wire [7:0] out__sra_1 = $signed(a) >>> b;"),
+        ("mul", "b", "Word[16]", "wire [15:0] out__mul_1 = a * b;"),
+        ("muls", "b", "Word[16]", "wire [15:0] out__muls_1 = $signed(a) * $signed(b);"),
+        ("ltu", "b", "Word[1]", "wire out__ltu_1 = a < b;"),
+        ("lteu", "b", "Word[1]", "wire out__lteu_1 = a <= b;"),
+        ("gtu", "b", "Word[1]", "wire out__gtu_1 = a > b;"),
+        ("gteu", "b", "Word[1]", "wire out__gteu_1 = a >= b;"),
+        ("lts", "b", "Word[1]", "wire out__lts_1 = $signed(a) < $signed(b);"),
+        ("ltes", "b", "Word[1]", "wire out__ltes_1 = $signed(a) <= $signed(b);"),
+        ("gts", "b", "Word[1]", "wire out__gts_1 = $signed(a) > $signed(b);"),
+        ("gtes", "b", "Word[1]", "wire out__gtes_1 = $signed(a) >= $signed(b);"),
+        ("zext[16]", "", "Word[16]", "wire [15:0] out__zext_1 = {{8{1'd0}}, a};"),
+        ("sext[16]", "", "Word[16]", "wire [15:0] out__sext_1 = {{8{a[7]}}, a};"),
+        ("trunc[4]", "", "Word[4]", "wire [3:0] out__trunc_1 = a[3:0];"),
+        ("get", "i", "Word[1]", "wire out__get_1 = a[i];"),
+    ];
+
+    for (method, args, typ, expected) in cases {
+        let verilog = verilog_of(&format!("
+            mod Top {{
+                incoming a : Word[8];
+                incoming b : Word[8];
+                incoming i : Word[3];
+                outgoing out : {typ};
+                out := a->{method}({args});
+            }}
+        ")).unwrap();
+
+        let wires: Vec<&str> = verilog.lines().map(|line| line.trim()).filter(|line| line.starts_with("wire")).collect();
+        assert_eq!(wires, vec![expected], "{method}");
+        let method_name = method.split('[').next().unwrap();
+        assert!(verilog.contains(&format!("assign out = out__{method_name}_1;")), "{method}");
     }
 }
+
+#[test]
+fn verilog_parametric_moddefs() {
+    let verilog_for = |top: &str| verilog_of(&format!("
+        mod Top {{
+            incoming clock : Clock;
+            {top}
+        }}
+
+        mod Delay[W] {{
+            incoming clock : Clock;
+            incoming inp : Word[W];
+            outgoing out : Word[W];
+            reg r : Word[W] on clock;
+            r <= inp;
+            out := r;
+        }}
+
+        mod Pair[W] {{
+            incoming clock : Clock;
+            incoming inp : Word[W];
+            outgoing out : Word[W];
+            mod delay of Delay[W];
+            delay.clock := clock;
+            delay.inp := inp;
+            out := delay.out;
+        }}
+    "));
+
+    let verilog = verilog_for("
+        incoming a : Word[8];
+        outgoing out : Word[4];
+        mod p of Pair[8];
+        mod d of Delay[4];
+        p.clock := clock;
+        p.inp := a;
+        d.clock := clock;
+        d.inp := p.out->trunc[4]();
+        out := d.out;
+    ").unwrap();
+    assert!(verilog.contains("module Pair__8("));
+    assert!(verilog.contains("module Delay__8("));
+    assert!(verilog.contains("module Delay__4("));
+    assert!(verilog.contains("Delay__8 delay("));
+    assert!(verilog.contains("Pair__8 p("));
+    assert!(!verilog.contains("module Delay("));
+}
+
+#[test]
+fn verilog_fns() {
+    let verilog_for = |fns: &str, driver: &str| verilog_of(&format!("
+        {fns}
+
+        mod Top {{
+            incoming a : Word[8];
+            incoming b : Word[8];
+            outgoing out : Word[8];
+            out := {driver};
+        }}
+    "));
+
+    let fns = "
+        fn max(x : Word[8], y : Word[8]) -> Word[8] = if x->gt(y) { x } else { y };
+        fn max3(x : Word[8], y : Word[8], z : Word[8]) -> Word[8] = max(max(x, y), z);
+    ";
+    let verilog = verilog_for(fns, "max3(a, b, 1)").unwrap();
+    assert!(verilog.contains("wire [7:0] out__max3_1"));
+    assert!(verilog.contains("a > b"));
+    assert!(verilog.contains("assign out = out__max3_1;"));
+}
+
+#[test]
+fn verilog_consts() {
+    let verilog_for = |consts: &str, driver: &str| verilog_of_packages(&[
+        ("lib", "
+            const DEPTH : Word[16] = 4;
+        "),
+        ("top", &format!("
+            import lib;
+
+            {consts}
+
+            mod Top {{
+                incoming a : Word[WIDTH];
+                outgoing out : Word[WIDTH];
+                out := {driver};
+            }}
+        ")),
+    ]);
+
+    let consts = "
+        const WIDTH : Word[8] = 8w8->add(8);
+        const LIMIT : Word[WIDTH] = 1250;
+    ";
+    let verilog = verilog_for(consts, "a->add(LIMIT)").unwrap();
+    assert!(verilog.contains("output  wire    [15:0] out"));
+    assert!(verilog.contains("localparam [15:0] LIMIT = 16'd1250;"));
+    assert!(verilog.contains("a + LIMIT"));
+
+    let verilog = verilog_for(consts, "a->add(lib::DEPTH)").unwrap();
+    assert!(verilog.contains("localparam [15:0] lib__DEPTH = 16'd4;"));
+
+    // Arithmetic on constants wraps around, as it does in hardware.
+    let verilog = verilog_for("const WIDTH : Word[8] = 255->add(9);", "a").unwrap();
+    assert!(verilog.contains("[7:0] out"));
+}
+
+#[test]
+fn verilog_enums() {
+    let verilog_for = |op: &str, driver: &str| verilog_of(&format!("
+        enum type Op : Word[2] {{
+            {op}
+        }}
+
+        mod Top {{
+            incoming a : Word[2];
+            outgoing out : Word[8];
+            out := {driver};
+        }}
+    "));
+
+    let op = "ADD = 0; SUB = 3;";
+    let verilog = verilog_for(op, "match Op::from_bits(a) { @ADD => 1; @SUB => 2; }").unwrap();
+    assert!(verilog.contains("localparam [1:0] Op__SUB = 2'd3;"));
+    assert!(verilog.contains("Op__SUB: out__match_1 = 8'd2;"));
+
+    let verilog = verilog_for(op, "if Op::from_bits(a)->eq(@SUB) { Op::from_bits(a)->bits()->zext[8]() } else { 0 }").unwrap();
+    assert!(verilog.contains("a == Op__SUB"));
+}
+
+#[test]
+fn verilog_mems() {
+    let verilog_for = |decls: &str| verilog_of(&format!("
+        mod Top {{
+            incoming clock : Clock;
+            incoming addr : Word[4];
+            incoming data : Word[8];
+            incoming we : Word[1];
+            outgoing out : Word[8];
+            outgoing out_sync : Word[8];
+            {decls}
+        }}
+    "));
+
+    let verilog = verilog_for("
+        mem m : Vec[Word[8], 16] on clock init \"m.hex\";
+        m[addr] <= data when we;
+        out := m->read(addr);
+        out_sync := m->read_sync(addr->inc());
+    ").unwrap();
+    assert!(verilog.contains("reg [7:0] m [0:15];"));
+    assert!(verilog.contains("$readmemh(\"m.hex\", m);"));
+    assert!(verilog.contains("if (we) begin"));
+    assert!(verilog.contains("m[addr] <= data;"));
+    assert!(verilog.contains("wire [7:0] out__read_1 = m[addr];"));
+    assert!(verilog.contains("out_sync__read_sync_2 <= m[out_sync__inc_1];"));
+    assert!(verilog.find("reg [7:0] m").unwrap() < verilog.find("out__read_1").unwrap());
+}
+
+#[test]
+fn verilog_ports() {
+    let verilog_for = |top: &str| verilog_of(&format!("
+        port Inc {{
+            mosi inp : Word[8];
+            miso out : Word[8];
+        }}
+
+        mod Top {{
+            slave port inc of Inc;
+            {top}
+        }}
+
+        mod Incrementer {{
+            slave port inc of Inc;
+            inc.out := inc.inp->inc();
+        }}
+
+        mod Driver {{
+            master port inc of Inc;
+            outgoing out : Word[8];
+            inc.inp := 1;
+            out := inc.out;
+        }}
+    "));
+
+    let verilog = verilog_for("mod incrementer of Incrementer; incrementer.inc :=: inc;").unwrap();
+    assert!(verilog.contains("input   wire     [7:0] inc_inp"));
+    assert!(verilog.contains("output  wire     [7:0] inc_out"));
+    assert!(verilog.contains("assign incrementer__inc_inp = inc_inp;"));
+    assert!(verilog.contains("assign inc_out = incrementer__inc_out;"));
+    assert!(verilog.contains("wire [7:0] inc_out__inc_1 = inc_inp + 8'd1;"));
+
+    // A master port flows the other way.
+    assert!(verilog.contains("output  wire     [7:0] inc_inp"));
+    assert!(verilog.contains("assign out = inc_out;"));
+
+    let verilog = verilog_for("
+        mod incrementer of Incrementer;
+        mod driver of Driver;
+        driver.inc :=: incrementer.inc;
+        inc.out := driver.out;
+    ").unwrap();
+    assert!(verilog.contains("assign incrementer__inc_inp = driver__inc_inp;"));
+    assert!(verilog.contains("assign driver__inc_out = incrementer__inc_out;"));
+}
+
+#[test]
+fn verilog_for_loops() {
+    let verilog_for = |top: &str| verilog_of(&format!("
+        mod Top {{
+            incoming clock : Clock;
+            incoming inp : Word[8];
+            outgoing out : Word[8];
+            {top}
+        }}
+
+        mod Delay[N] {{
+            incoming clock : Clock;
+            incoming inp : Word[8];
+            outgoing out : Word[8];
+            reg r[0] : Word[8] on clock;
+            r[0] <= inp;
+            for i in 1..N {{
+                reg r[i] : Word[8] on clock;
+                r[i] <= inp->add(i);
+            }}
+            out := r[2];
+        }}
+    "));
+
+    let verilog = verilog_for("
+        for i in 0..2 {
+            mod delay[i] of Delay[3];
+            delay[i].clock := clock;
+            delay[i].inp := inp->add(i);
+        }
+        out := delay[0].out->add(delay[1].out);
+    ").unwrap();
+    assert!(verilog.contains("Delay__3 delay_1_("));
+    assert!(verilog.contains("wire [7:0] delay_1___inp__add_1 = inp + 8'd1;"));
+
+    let verilog = verilog_for("
+        for i in 0..2 {
+            node x[i] : Word[8];
+            x[i] := if inp[i] { inp->add(i) } else { 0 };
+        }
+        out := x[1]->add(x[0]);
+    ").unwrap();
+    assert!(verilog.contains("wire [7:0] x_1___add_2 = inp + 8'd1;"));
+    assert!(verilog.contains("wire [7:0] x_1___if_1 = inp[1] ? x_1___add_2 : 8'd0;"));
+    assert!(verilog.contains("x_1_ + x_0_"));
+
+    let verilog = verilog_for("
+        mod delay of Delay[3];
+        delay.clock := clock;
+        delay.inp := inp;
+        out := delay.out;
+    ").unwrap();
+    assert!(verilog.contains("module Delay__3("));
+    assert!(verilog.contains("reg [7:0] r_2_;"));
+    assert!(!verilog.contains("r_3_"));
+
+    assert!(verilog_for("for i in 0..top::N { node x[i] : Word[8]; x[i] := 0; } out := 0;").is_err());
+}
+
+#[test]
+fn verilog_width_inference() {
+    let verilog_top = |top: &str| verilog_of(&format!("
+        mod Top {{
+            incoming a : Word[8];
+            incoming c : Word[1];
+            outgoing out : Word[8];
+            outgoing flag : Word[1];
+            {top}
+        }}
+    "));
+
+    let verilog = verilog_top("
+        out := 1->add(a);
+        flag := let y = 200 in (if c { y } else { 3 })->eq(a);
+    ").unwrap();
+    assert!(verilog.contains("8'd1 + a"));
+    assert!(verilog.contains("c ? 8'd200 : 8'd3"));
+}
+
+#[test]
+fn verilog_conversions() {
+    let verilog_for = |driver: &str| verilog_of(&format!("
+        struct type Pixel {{
+            red : Word[4];
+            green : Word[4];
+        }}
+
+        union type Valid[T] {{
+            Invalid();
+            Valid(T);
+        }}
+
+        mod Top {{
+            incoming a : Word[8];
+            outgoing out : Word[9];
+            out := {driver};
+        }}
+    "));
+
+    let verilog = verilog_for("Pixel::from_bits(a)->bits()->zext()").unwrap();
+    assert!(verilog.contains("wire [7:0] out__bits_2 = a;"));
+    let verilog = verilog_for("Valid::from_bits(cat(a, 1w1))->as(Valid[Word[8]])->bits()").unwrap();
+    assert!(verilog.contains("wire [8:0] out__bits_1 = out__cat_2;"));
+}
+
+#[test]
+fn verilog_whens() {
+    let verilog_for = |decls: &str| verilog_of(&format!("
+        mod Top {{
+            incoming clock : Clock;
+            incoming c : Word[1];
+            incoming d : Word[1];
+            incoming a : Word[8];
+            outgoing out : Word[8];
+            reg r : Word[8] on clock;
+            reg s : Word[8] on clock;
+            {decls}
+        }}
+    "));
+
+    let verilog = verilog_for("
+        when c {
+            r <= a;
+            out := a;
+        } else when d {
+            s <= a;
+            out := r;
+        } else {
+            out := s;
+        }
+    ").unwrap();
+    assert!(verilog.contains("wire [7:0] r__if_1 = c ? a : r;"));
+    assert!(verilog.contains("s__if_2 = d ? a : s;"));
+    assert!(verilog.contains("wire [7:0] out__if_1 = c ? a : out__if_2;"));
+}
+
+#[test]
+fn verilog_literals() {
+    let verilog_for = |driver: &str| verilog_of(&format!("
+        mod Top {{
+            incoming a : Word[8];
+            outgoing out : Word[8];
+            out := {driver};
+        }}
+    "));
+
+    assert!(verilog_for("a->add(0xff)").unwrap().contains("a + 8'd255"));
+    assert!(verilog_for("a->and(0b1111_0000)").unwrap().contains("a & 8'd240"));
+    assert!(verilog_for("0x0_fw8->add(a)").unwrap().contains("8'd15 + a"));
+}
+
+#[test]
+fn verilog_with() {
+    let verilog_for = |typ: &str, driver: &str| verilog_of(&format!("
+        struct type Pixel {{
+            red : Word[4];
+            green : Word[4];
+            blue : Word[4];
+        }}
+
+        mod Top {{
+            incoming a : {typ};
+            incoming b : Word[4];
+            outgoing out : {typ};
+            out := {driver};
+        }}
+    "));
+
+    let verilog = verilog_for("Word[12]", "a->with([8..4] = b, [0] = 1)").unwrap();
+    assert!(verilog.contains("wire [11:0] out__with_1 = {a[11:8], b, a[3:1], 1'd1};"));
+    let verilog = verilog_for("Pixel", "a->with(green = b)").unwrap();
+    assert!(verilog.contains("wire [11:0] out__with_1 = {a[11:8], b, a[3:0]};"));
+    let verilog = verilog_for("Word[12]", "a->with([12..8] = b)").unwrap();
+    assert!(verilog.contains("wire [11:0] out__with_1 = {b, a[7:0]};"));
+
+    assert!(verilog_for("Word[12]", "a->with([13..9] = b)").is_err());
+    assert!(verilog_for("Word[12]", "a->with([8..4] = b, [4] = 0)").is_err());
+    assert!(verilog_for("Word[12]", "a->with([8..5] = b)").is_err());
+    assert!(verilog_for("Pixel", "a->with(alpha = b)").is_err());
+}
+
+#[test]
+fn verilog_static_indices() {
+    let verilog_for = |driver: &str| verilog_of(&format!("
+        struct type Pixel {{
+            red : Word[4];
+            green : Word[4];
+        }}
+
+        mod Top {{
+            incoming a : Word[8];
+            incoming p : Pixel;
+            outgoing out : Word[4];
+            out := {driver};
+        }}
+    "));
+
+    assert!(verilog_for("a[8..4]").unwrap().contains("assign out = a[7:4];"));
+    assert!(verilog_for("p[8..4]").unwrap().contains("assign out = p[7:4];"));
+    assert!(verilog_for("cat(a[7], a[2..0], a[0])").unwrap().contains("{a[7], a[1:0], a[0]}"));
+}
+
+#[test]
+fn verilog_dynamic_index() {
+    let verilog_for = |driver: &str, typ: &str| verilog_of(&format!("
+        mod Top {{
+            incoming a : Word[8];
+            incoming b : Word[6];
+            incoming i : Word[3];
+            incoming v : Vec[Word[8], 4];
+            incoming k : Word[2];
+            outgoing out : {typ};
+            out := {driver};
+        }}
+    "));
+
+    let verilog = verilog_for("a->get(i)", "Word[1]").unwrap();
+    assert!(verilog.contains("wire out__get_1 = a[i];"));
+
+    let verilog = verilog_for("b->get(i)", "Word[1]").unwrap();
+    assert!(verilog.contains("wire [5:0] out__shifted_2 = b >> i;"));
+    assert!(verilog.contains("wire out__get_1 = out__shifted_2[0];"));
+
+    let verilog = verilog_for("a->slice(i)", "Word[4]").unwrap();
+    assert!(verilog.contains("wire [7:0] out__shifted_2 = a >> i;"));
+    assert!(verilog.contains("wire [3:0] out__slice_1 = out__shifted_2[3:0];"));
+
+    let verilog = verilog_for("a->slice[2](i)", "Word[2]").unwrap();
+    assert!(verilog.contains("wire [1:0] out__slice_1 = out__shifted_2[1:0];"));
+
+    let verilog = verilog_for("v->get(k)", "Word[8]").unwrap();
+    assert!(verilog.contains("wire [7:0] out__get_1 = v[k * 6'd8 +: 8];"));
+
+    let verilog = verilog_for("v->slice(k)", "Vec[Word[8], 2]").unwrap();
+    assert!(verilog.contains("wire [31:0] out__shifted_2 = v >> (k * 6'd8);"));
+    assert!(verilog.contains("wire [15:0] out__slice_1 = out__shifted_2[15:0];"));
+
+    assert!(verilog_for("a->get(k)", "Word[1]").is_err());
+    assert!(verilog_for("a->slice[9](i)", "Word[9]").is_err());
+    assert!(verilog_for("v->slice[5](k)", "Vec[Word[8], 5]").is_err());
+    assert!(verilog_for("v->get(i)", "Word[8]").is_err());
+}
+
+#[test]
+fn verilog_dynamic_index_out_of_range() {
+    let verilog_for = |attr: &str| verilog_of(&format!("
+        {attr}
+        mod Top {{
+            incoming b : Word[6];
+            incoming i : Word[3];
+            outgoing out : Word[4];
+            out := b->slice(i);
+        }}
+    "));
+
+    assert!(verilog_for("").unwrap().contains("wire [5:0] out__shifted_2 = b >> i;"));
+    assert!(verilog_for("#[out_of_range(\"zero\")]").unwrap().contains("wire [5:0] out__shifted_2 = b >> i;"));
+    assert!(verilog_for("#[out_of_range(\"x\")]").unwrap().contains("wire [3:0] out__slice_1 = b[i +: 4];"));
+    // With a 64-bit index, the end of the last slice doesn't fit in a u64.
+    let verilog = verilog_of("
+        mod Top {
+            incoming b : Word[9223372036854775809];
+            incoming i : Word[64];
+            outgoing out : Word[1];
+            out := b->get(i);
+        }
+    ").unwrap();
+    assert!(verilog.contains("out__shifted_2 = b >> i;"));
+
+    assert!(verilog_for("#[out_of_range(\"one\")]").is_err());
+    assert!(verilog_for("#[source(\"Top.v\")]").is_err());
+}
+
+#[test]
+fn verilog_lets() {
+    let verilog_for = |driver: &str| verilog_of(&format!("
+        mod Top {{
+            incoming a : Word[8];
+            outgoing out : Word[8];
+            out := {driver};
+        }}
+    "));
+
+    let verilog = verilog_for("let y = a->inc() in let z = y->add(y) in z").unwrap();
+    assert!(verilog.contains("wire [7:0] out__y_1 = a + 8'd1;"));
+    assert!(verilog.contains("wire [7:0] out__z_3 = out__y_1 + out__y_1;"));
+    assert!(verilog.contains("assign out = out__z_3;"));
+
+    let verilog = verilog_for("let y = a in let z = y in z->add(y)").unwrap();
+    assert!(verilog.contains("wire [7:0] out__y_1 = a;"));
+    assert!(verilog.contains("wire [7:0] out__z_2 = out__y_1;"));
+    assert!(verilog.contains("wire [7:0] out__add_3 = out__z_2 + out__y_1;"));
+}
+
+#[test]
+fn verilog_union_layouts() {
+    let verilog_for = |attrs: &str, driver: &str| verilog_of(&format!("
+        {attrs}
+        union type Op {{
+            Nop();
+            Load(Word[4], Word[2]);
+            Jump(Word[3]);
+        }}
+
+        union type Only {{
+            Only(Word[4]);
+        }}
+
+        mod Top {{
+            incoming a : Word[4];
+            incoming only : Only;
+            outgoing op : Op;
+            outgoing out : Word[4];
+            op := {driver};
+            out := match only {{
+                @Only(x) => x;
+            }};
+        }}
+    "));
+
+    let verilog = verilog_for("", "@Jump(a[3..0])").unwrap();
+    assert!(verilog.contains("wire [7:0] op__Jump_1 = {3'd0, a[2:0], 2'd2};"));
+    assert!(verilog.contains("input   wire     [4:0] only,"));
+    assert!(verilog.contains("wire out__tag_2 = only[0];"));
+    assert!(verilog.contains("wire [3:0] out__x_3 = only[4:1];"));
+
+    let verilog = verilog_for("", "@Load(a, a[2..0])").unwrap();
+    assert!(verilog.contains("wire [7:0] op__Load_1 = {a[1:0], a, 2'd1};"));
+
+    let verilog = verilog_for("#[layout(tag = \"onehot\", padding = \"x\")]", "@Jump(a[3..0])").unwrap();
+    assert!(verilog.contains("wire [8:0] op__Jump_1 = {3'bx, a[2:0], 3'd4};"));
+}
+
+#[test]
+fn verilog_properties() {
+    let verilog_for = |decls: &str| verilog_of(&format!("
+        mod Top {{
+            incoming clock : Clock;
+            incoming a : Word[8];
+            incoming go : Word[1];
+            {decls}
+        }}
+    "));
+
+    let verilog = verilog_for("
+        assert a->lt(200) on clock;
+        cover a->eq(5) on clock;
+        when go {
+            assert a->neq(0) on clock;
+        }
+    ").unwrap();
+    assert!(verilog.contains("if (~assert_1__lt_1) begin"));
+    assert!(verilog.contains("$display(\"Assertion failed at [7:9:top] in %m\");"));
+    assert!(verilog.contains("$stop();"));
+    assert!(verilog.contains("reg [31:0] cover_2__hits = 32'd0;"));
+    assert!(verilog.contains("$display(\"Cover at [8:9:top] in %m was hit %0d times\", cover_2__hits);"));
+    assert!(verilog.contains("cover_2__hits <= cover_2__hits + 32'd1;"));
+    assert!(verilog.contains("if (go & (~assert_3__neq_1)) begin"));
+
+    assert!(verilog_for("assert a on clock;").is_err());
+    assert!(verilog_for("cover go on a;").is_err());
+}
@@ -0,0 +1,868 @@
+//! A structured representation of the Verilog emitted by the backend.
+//!
+//! The backend in [`crate::verilog`] lowers each moddef into a [`VerilogModDef`].
+//! Passes then run over it (see [`VerilogModDef::run_passes`]) before it is printed with [`VerilogFile::write`].
+//! The printer is the only place which knows about Verilog's concrete syntax.
+
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+
+use crate::common::*;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerilogFile {
+    pub items: Vec<VerilogItem>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerilogItem {
+    /// An `` `include `` of a hand-written Verilog source.
+    Include(String),
+    ModDef(VerilogModDef),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerilogModDef {
+    pub name: String,
+    pub ports: Vec<VerilogPortDecl>,
+    pub statements: Vec<VerilogStatement>,
+    /// The wires made up by the backend, as opposed to those declared in the source.
+    pub temporaries: HashSet<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerilogPortDecl {
+    pub name: String,
+    pub dir: Dir,
+    pub width: Width,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dir {
+    Input,
+    Output,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerilogExpr {
+    /// A sized constant: the value, then the width.
+    Const(u64, Width),
+    /// An unsized integer, such as the value of a parameter.
+    Int(u64),
+    /// An all-`x` value of the given width.
+    X(Width),
+    Str(String),
+    Ref(String),
+    Index(String, u64),
+    /// A part-select `x[hi:lo]`. Both bounds are inclusive.
+    Slice(String, u64, u64),
+    DynIndex(String, Box<VerilogExpr>),
+    /// An indexed part-select `x[base +: width]`.
+    DynSlice(String, Box<VerilogExpr>, Width),
+    Unop(Unop, Box<VerilogExpr>),
+    Binop(Binop, Box<VerilogExpr>, Box<VerilogExpr>),
+    Mux(Box<VerilogExpr>, Box<VerilogExpr>, Box<VerilogExpr>),
+    /// A concatenation. The first element is the most significant.
+    Cat(Vec<VerilogExpr>),
+    Repeat(Width, Box<VerilogExpr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unop {
+    Not,
+    Neg,
+    /// Reinterprets its operand as signed, as with `$signed(x)`.
+    Signed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Binop {
+    Add,
+    Sub,
+    Mul,
+    And,
+    Or,
+    Xor,
+    Eq,
+    Neq,
+    Lt,
+    Lte,
+    Gt,
+    Gte,
+    Sll,
+    Srl,
+    Sra,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerilogStatement {
+    Comment(String),
+    Blank,
+    Wire(String, Width, Option<VerilogExpr>),
+    Reg(String, Width, Option<VerilogExpr>),
+    Localparam(String, Width, VerilogExpr),
+    /// An array of `depth` words, each `width` bits wide.
+    Memory(String, Width, u64),
+    Assign(String, VerilogExpr),
+    AlwaysAtPosedge(VerilogExpr, Vec<VerilogStatement>),
+    AlwaysComb(Vec<VerilogStatement>),
+    Initial(Vec<VerilogStatement>),
+    /// A block run when the simulation ends. This is SystemVerilog rather than Verilog-2001.
+    Final(Vec<VerilogStatement>),
+    NonblockingAssign(String, VerilogExpr),
+    BlockingAssign(String, VerilogExpr),
+    /// A nonblocking assignment to one word of a memory, `mem[addr] <= data`.
+    MemWrite(String, VerilogExpr, VerilogExpr),
+    If(VerilogExpr, Vec<VerilogStatement>),
+    /// A call to a system task, such as `$readmemh`.
+    SystemTask(String, Vec<VerilogExpr>),
+    Case(VerilogExpr, Vec<VerilogCaseArm>),
+    Instance(VerilogInstance),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerilogCaseArm {
+    /// The value matched by the arm, or `None` for the `default` arm.
+    pub label: Option<VerilogExpr>,
+    pub comment: Option<String>,
+    pub body: Vec<VerilogStatement>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerilogInstance {
+    pub moddef: String,
+    pub name: String,
+    pub params: Vec<(String, VerilogExpr)>,
+    pub connections: Vec<(String, VerilogExpr)>,
+}
+
+impl VerilogModDef {
+    /// Runs every pass, in order.
+    pub fn run_passes(&mut self) {
+        fold_constants(self);
+        eliminate_dead_wires(self);
+        legalize_names(self);
+    }
+
+    fn exprs(&self) -> Vec<&VerilogExpr> {
+        self.statements.iter().flat_map(|statement| statement.exprs()).collect()
+    }
+
+    fn exprs_mut(&mut self) -> Vec<&mut VerilogExpr> {
+        self.statements.iter_mut().flat_map(|statement| statement.exprs_mut()).collect()
+    }
+}
+
+impl VerilogStatement {
+    fn exprs(&self) -> Vec<&VerilogExpr> {
+        match self {
+            VerilogStatement::Comment(_) | VerilogStatement::Blank | VerilogStatement::Memory(..) => vec![],
+            VerilogStatement::Wire(_, _, init) | VerilogStatement::Reg(_, _, init) => init.iter().collect(),
+            VerilogStatement::MemWrite(_, addr, data) => vec![addr, data],
+            VerilogStatement::If(cond, statements) => {
+                let mut exprs = vec![cond];
+                exprs.extend(statements.iter().flat_map(|statement| statement.exprs()));
+                exprs
+            },
+            VerilogStatement::SystemTask(_, args) => args.iter().collect(),
+            VerilogStatement::Localparam(_, _, expr) |
+            VerilogStatement::Assign(_, expr) |
+            VerilogStatement::NonblockingAssign(_, expr) |
+            VerilogStatement::BlockingAssign(_, expr) => vec![expr],
+            VerilogStatement::AlwaysAtPosedge(clock, statements) => {
+                let mut exprs = vec![clock];
+                exprs.extend(statements.iter().flat_map(|statement| statement.exprs()));
+                exprs
+            },
+            VerilogStatement::AlwaysComb(statements) | VerilogStatement::Initial(statements) | VerilogStatement::Final(statements) => {
+                statements.iter().flat_map(|statement| statement.exprs()).collect()
+            },
+            VerilogStatement::Case(subject, arms) => {
+                let mut exprs = vec![subject];
+                for arm in arms {
+                    exprs.extend(arm.label.iter());
+                    exprs.extend(arm.body.iter().flat_map(|statement| statement.exprs()));
+                }
+                exprs
+            },
+            VerilogStatement::Instance(instance) => {
+                instance.params.iter().chain(instance.connections.iter()).map(|(_, expr)| expr).collect()
+            },
+        }
+    }
+
+    fn exprs_mut(&mut self) -> Vec<&mut VerilogExpr> {
+        match self {
+            VerilogStatement::Comment(_) | VerilogStatement::Blank | VerilogStatement::Memory(..) => vec![],
+            VerilogStatement::Wire(_, _, init) | VerilogStatement::Reg(_, _, init) => init.iter_mut().collect(),
+            VerilogStatement::MemWrite(_, addr, data) => vec![addr, data],
+            VerilogStatement::If(cond, statements) => {
+                let mut exprs = vec![cond];
+                exprs.extend(statements.iter_mut().flat_map(|statement| statement.exprs_mut()));
+                exprs
+            },
+            VerilogStatement::SystemTask(_, args) => args.iter_mut().collect(),
+            VerilogStatement::Localparam(_, _, expr) |
+            VerilogStatement::Assign(_, expr) |
+            VerilogStatement::NonblockingAssign(_, expr) |
+            VerilogStatement::BlockingAssign(_, expr) => vec![expr],
+            VerilogStatement::AlwaysAtPosedge(clock, statements) => {
+                let mut exprs = vec![clock];
+                exprs.extend(statements.iter_mut().flat_map(|statement| statement.exprs_mut()));
+                exprs
+            },
+            VerilogStatement::AlwaysComb(statements) | VerilogStatement::Initial(statements) | VerilogStatement::Final(statements) => {
+                statements.iter_mut().flat_map(|statement| statement.exprs_mut()).collect()
+            },
+            VerilogStatement::Case(subject, arms) => {
+                let mut exprs = vec![subject];
+                for arm in arms {
+                    exprs.extend(arm.label.iter_mut());
+                    exprs.extend(arm.body.iter_mut().flat_map(|statement| statement.exprs_mut()));
+                }
+                exprs
+            },
+            VerilogStatement::Instance(instance) => {
+                instance.params.iter_mut().chain(instance.connections.iter_mut()).map(|(_, expr)| expr).collect()
+            },
+        }
+    }
+
+    /// The names this statement declares or assigns to.
+    fn names_mut(&mut self) -> Vec<&mut String> {
+        match self {
+            VerilogStatement::Wire(name, _, _) |
+            VerilogStatement::Reg(name, _, _) |
+            VerilogStatement::Localparam(name, _, _) |
+            VerilogStatement::Memory(name, _, _) |
+            VerilogStatement::MemWrite(name, _, _) |
+            VerilogStatement::Assign(name, _) |
+            VerilogStatement::NonblockingAssign(name, _) |
+            VerilogStatement::BlockingAssign(name, _) => vec![name],
+            VerilogStatement::AlwaysAtPosedge(_, statements) |
+            VerilogStatement::AlwaysComb(statements) |
+            VerilogStatement::Initial(statements) |
+            VerilogStatement::Final(statements) |
+            VerilogStatement::If(_, statements) => {
+                statements.iter_mut().flat_map(|statement| statement.names_mut()).collect()
+            },
+            VerilogStatement::Case(_, arms) => {
+                arms.iter_mut().flat_map(|arm| arm.body.iter_mut()).flat_map(|statement| statement.names_mut()).collect()
+            },
+            VerilogStatement::Instance(instance) => vec![&mut instance.name],
+            VerilogStatement::Comment(_) | VerilogStatement::Blank | VerilogStatement::SystemTask(..) => vec![],
+        }
+    }
+}
+
+impl VerilogExpr {
+    fn names(&self, names: &mut HashSet<String>) {
+        match self {
+            VerilogExpr::Const(..) | VerilogExpr::Int(_) | VerilogExpr::X(_) | VerilogExpr::Str(_) => (),
+            VerilogExpr::Ref(name) | VerilogExpr::Index(name, _) | VerilogExpr::Slice(name, _, _) => {
+                names.insert(name.clone());
+            },
+            VerilogExpr::DynIndex(name, index) | VerilogExpr::DynSlice(name, index, _) => {
+                names.insert(name.clone());
+                index.names(names);
+            },
+            VerilogExpr::Unop(_, a) | VerilogExpr::Repeat(_, a) => a.names(names),
+            VerilogExpr::Binop(_, a, b) => {
+                a.names(names);
+                b.names(names);
+            },
+            VerilogExpr::Mux(c, a, b) => {
+                c.names(names);
+                a.names(names);
+                b.names(names);
+            },
+            VerilogExpr::Cat(args) => {
+                for arg in args {
+                    arg.names(names);
+                }
+            },
+        }
+    }
+
+    fn names_mut(&mut self) -> Vec<&mut String> {
+        match self {
+            VerilogExpr::Const(..) | VerilogExpr::Int(_) | VerilogExpr::X(_) | VerilogExpr::Str(_) => vec![],
+            VerilogExpr::Ref(name) | VerilogExpr::Index(name, _) | VerilogExpr::Slice(name, _, _) => vec![name],
+            VerilogExpr::DynIndex(name, index) | VerilogExpr::DynSlice(name, index, _) => {
+                let mut names = vec![name];
+                names.extend(index.names_mut());
+                names
+            },
+            VerilogExpr::Unop(_, a) | VerilogExpr::Repeat(_, a) => a.names_mut(),
+            VerilogExpr::Binop(_, a, b) => {
+                let mut names = a.names_mut();
+                names.extend(b.names_mut());
+                names
+            },
+            VerilogExpr::Mux(c, a, b) => {
+                let mut names = c.names_mut();
+                names.extend(a.names_mut());
+                names.extend(b.names_mut());
+                names
+            },
+            VerilogExpr::Cat(args) => args.iter_mut().flat_map(|arg| arg.names_mut()).collect(),
+        }
+    }
+
+    /// Replaces references to the constant wires in `consts` and folds operations on constants.
+    fn fold(&mut self, consts: &HashMap<String, VerilogExpr>) {
+        match self {
+            VerilogExpr::Ref(name) => {
+                if let Some(value) = consts.get(name) {
+                    *self = value.clone();
+                }
+                return;
+            },
+            VerilogExpr::DynIndex(_, index) | VerilogExpr::DynSlice(_, index, _) => index.fold(consts),
+            VerilogExpr::Unop(_, a) | VerilogExpr::Repeat(_, a) => a.fold(consts),
+            VerilogExpr::Binop(_, a, b) => {
+                a.fold(consts);
+                b.fold(consts);
+            },
+            VerilogExpr::Mux(c, a, b) => {
+                c.fold(consts);
+                a.fold(consts);
+                b.fold(consts);
+            },
+            VerilogExpr::Cat(args) => {
+                for arg in args.iter_mut() {
+                    arg.fold(consts);
+                }
+            },
+            _ => (),
+        }
+
+        let folded = match self {
+            VerilogExpr::Unop(Unop::Not, a) => match a.as_ref() {
+                VerilogExpr::Const(v, w) if *w <= 64 => Some(VerilogExpr::Const(!v & mask(*w), *w)),
+                _ => None,
+            },
+            VerilogExpr::Unop(Unop::Neg, a) => match a.as_ref() {
+                VerilogExpr::Const(v, w) if *w <= 64 => Some(VerilogExpr::Const(v.wrapping_neg() & mask(*w), *w)),
+                _ => None,
+            },
+            VerilogExpr::Unop(Unop::Signed, _a) => None,
+            VerilogExpr::Binop(op, a, b) => match (a.as_ref(), b.as_ref()) {
+                (VerilogExpr::Const(a, aw), VerilogExpr::Const(b, bw)) if *aw <= 64 && *op != Binop::Sra => fold_binop(*op, *a, *aw, *b, *bw),
+                (VerilogExpr::Unop(Unop::Signed, a), VerilogExpr::Const(b, bw)) if *op == Binop::Sra => match a.as_ref() {
+                    VerilogExpr::Const(a, aw) if *aw <= 64 => fold_binop(*op, *a, *aw, *b, *bw),
+                    _ => None,
+                },
+                _ => None,
+            },
+            VerilogExpr::Mux(c, a, b) => match c.as_ref() {
+                VerilogExpr::Const(0, _) => Some(b.as_ref().clone()),
+                VerilogExpr::Const(_, _) => Some(a.as_ref().clone()),
+                _ => None,
+            },
+            VerilogExpr::Cat(args) => {
+                let mut value: u64 = 0;
+                let mut width: Width = 0;
+                for arg in args.iter() {
+                    match arg {
+                        VerilogExpr::Const(v, w) if width + w <= 64 => {
+                            value = if *w == 64 { *v } else { (value << w) | v };
+                            width += w;
+                        },
+                        _ => return,
+                    }
+                }
+                Some(VerilogExpr::Const(value, width))
+            },
+            _ => None,
+        };
+
+        if let Some(folded) = folded {
+            *self = folded;
+        }
+    }
+
+    fn is_compound(&self) -> bool {
+        matches!(self, VerilogExpr::Unop(Unop::Not | Unop::Neg, _) | VerilogExpr::Binop(..) | VerilogExpr::Mux(..))
+    }
+}
+
+fn mask(width: Width) -> u64 {
+    if width >= 64 {
+        u64::MAX
+    } else {
+        (1 << width) - 1
+    }
+}
+
+fn fold_binop(op: Binop, a: u64, a_width: Width, b: u64, b_width: Width) -> Option<VerilogExpr> {
+    let m = mask(a_width);
+    let value = match op {
+        // A product is wide enough to hold every bit of the result.
+        Binop::Mul if a_width + b_width <= 64 => return Some(VerilogExpr::Const(a * b, a_width + b_width)),
+        Binop::Mul => return None,
+        Binop::Sll => if b >= a_width { 0 } else { (a << b) & m },
+        Binop::Srl => if b >= a_width { 0 } else { a >> b },
+        // The subject of an arithmetic shift is signed, so its sign bit is shifted in.
+        Binop::Sra => {
+            let negative = a_width > 0 && (a >> (a_width - 1)) & 1 == 1;
+            let fill = if !negative { 0 } else if b >= a_width { m } else { m & !(m >> b) };
+            if b >= a_width { fill } else { (a >> b) | fill }
+        },
+        _ if a_width != b_width => return None,
+        Binop::Add => a.wrapping_add(b) & m,
+        Binop::Sub => a.wrapping_sub(b) & m,
+        Binop::And => a & b,
+        Binop::Or => a | b,
+        Binop::Xor => a ^ b,
+        Binop::Eq => return Some(VerilogExpr::Const((a == b) as u64, 1)),
+        Binop::Neq => return Some(VerilogExpr::Const((a != b) as u64, 1)),
+        Binop::Lt => return Some(VerilogExpr::Const((a < b) as u64, 1)),
+        Binop::Lte => return Some(VerilogExpr::Const((a <= b) as u64, 1)),
+        Binop::Gt => return Some(VerilogExpr::Const((a > b) as u64, 1)),
+        Binop::Gte => return Some(VerilogExpr::Const((a >= b) as u64, 1)),
+    };
+    Some(VerilogExpr::Const(value, a_width))
+}
+
+/// Propagates wires which are driven by constants into their uses, and folds operations on constants.
+pub fn fold_constants(moddef: &mut VerilogModDef) {
+    loop {
+        let wires: HashMap<String, Width> = moddef.statements.iter().filter_map(|statement| match statement {
+            VerilogStatement::Wire(name, width, _) => Some((name.clone(), *width)),
+            _ => None,
+        }).collect();
+
+        // A constant driving a wire of a different width would change its value, so those are left alone.
+        let mut consts: HashMap<String, VerilogExpr> = HashMap::new();
+        for statement in &moddef.statements {
+            match statement {
+                VerilogStatement::Wire(name, _, Some(value @ VerilogExpr::Const(_, w))) |
+                VerilogStatement::Assign(name, value @ VerilogExpr::Const(_, w)) if wires.get(name) == Some(w) => {
+                    consts.insert(name.clone(), value.clone());
+                },
+                _ => (),
+            }
+        }
+
+        let before = moddef.clone();
+        for expr in moddef.exprs_mut() {
+            expr.fold(&consts);
+        }
+        if *moddef == before {
+            return;
+        }
+    }
+}
+
+/// Removes temporaries which are never read, along with whatever drives them.
+/// Wires declared in the source are kept, so they can still be probed in a waveform.
+pub fn eliminate_dead_wires(moddef: &mut VerilogModDef) {
+    loop {
+        let mut used = HashSet::new();
+        for expr in moddef.exprs() {
+            expr.names(&mut used);
+        }
+
+        let dead: HashSet<String> = moddef.statements.iter().filter_map(|statement| match statement {
+            VerilogStatement::Wire(name, _, _) if moddef.temporaries.contains(name) && !used.contains(name) => Some(name.clone()),
+            _ => None,
+        }).collect();
+
+        if dead.is_empty() {
+            return;
+        }
+
+        moddef.statements.retain(|statement| match statement {
+            VerilogStatement::Wire(name, _, _) | VerilogStatement::Assign(name, _) => !dead.contains(name),
+            _ => true,
+        });
+    }
+}
+
+/// Renames wires, regs, and instances whose names are not legal Verilog identifiers.
+/// Port names are left alone, since they are part of the module's interface.
+/// The printer escapes them instead.
+pub fn legalize_names(moddef: &mut VerilogModDef) {
+    let mut taken: HashSet<String> = moddef.ports.iter().map(|port| port.name.clone()).collect();
+    let mut declared = vec![];
+    for statement in moddef.statements.iter_mut() {
+        match statement {
+            VerilogStatement::Wire(name, _, _) |
+            VerilogStatement::Reg(name, _, _) |
+            VerilogStatement::Localparam(name, _, _) |
+            VerilogStatement::Memory(name, _, _) => declared.push(name.clone()),
+            VerilogStatement::Instance(instance) => declared.push(instance.name.clone()),
+            _ => (),
+        }
+    }
+    taken.extend(declared.iter().filter(|name| is_legal_ident(name)).cloned());
+
+    let mut renames: HashMap<String, String> = HashMap::new();
+    for name in declared {
+        if is_legal_ident(&name) || renames.contains_key(&name) {
+            continue;
+        }
+
+        let mut base: String = name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' }).collect();
+        if base.is_empty() || base.starts_with(|c: char| c.is_ascii_digit()) {
+            base.insert(0, '_');
+        }
+        if is_reserved(&base) {
+            base.push('_');
+        }
+
+        let mut legal = base.clone();
+        let mut i = 1;
+        while taken.contains(&legal) {
+            legal = format!("{base}_{i}");
+            i += 1;
+        }
+        taken.insert(legal.clone());
+        renames.insert(name, legal);
+    }
+
+    if renames.is_empty() {
+        return;
+    }
+
+    for statement in moddef.statements.iter_mut() {
+        for name in statement.names_mut() {
+            if let Some(legal) = renames.get(name) {
+                *name = legal.clone();
+            }
+        }
+        for expr in statement.exprs_mut() {
+            for name in expr.names_mut() {
+                if let Some(legal) = renames.get(name) {
+                    *name = legal.clone();
+                }
+            }
+        }
+    }
+}
+
+fn is_legal_ident(name: &str) -> bool {
+    let mut chars = name.chars();
+    let starts_well = match chars.next() {
+        Some(c) => c.is_ascii_alphabetic() || c == '_',
+        None => false,
+    };
+    starts_well && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$') && !is_reserved(name)
+}
+
+fn is_reserved(name: &str) -> bool {
+    const RESERVED: &[&str] = &[
+        "always", "and", "assign", "automatic", "begin", "buf", "bufif0", "bufif1", "case", "casex", "casez",
+        "cell", "cmos", "config", "deassign", "default", "defparam", "design", "disable", "edge", "else",
+        "end", "endcase", "endconfig", "endfunction", "endgenerate", "endmodule", "endprimitive", "endspecify",
+        "endtable", "endtask", "event", "for", "force", "forever", "fork", "function", "generate", "genvar",
+        "highz0", "highz1", "if", "ifnone", "incdir", "include", "initial", "inout", "input", "instance",
+        "integer", "join", "large", "liblist", "library", "localparam", "macromodule", "medium", "module",
+        "nand", "negedge", "nmos", "nor", "noshowcancelled", "not", "notif0", "notif1", "or", "output",
+        "parameter", "pmos", "posedge", "primitive", "pull0", "pull1", "pulldown", "pullup",
+        "pulsestyle_ondetect", "pulsestyle_onevent", "rcmos", "real", "realtime", "reg", "release", "repeat",
+        "rnmos", "rpmos", "rtran", "rtranif0", "rtranif1", "scalared", "showcancelled", "signed", "small",
+        "specify", "specparam", "strong0", "strong1", "supply0", "supply1", "table", "task", "time", "tran",
+        "tranif0", "tranif1", "tri", "tri0", "tri1", "triand", "trior", "trireg", "unsigned", "use", "uwire",
+        "vectored", "wait", "wand", "weak0", "weak1", "while", "wire", "wor", "xnor", "xor",
+        // SystemVerilog
+        "bit", "byte", "int", "logic", "shortint", "longint", "interface", "package", "enum", "struct",
+        "union", "typedef", "assert", "assume", "cover", "property", "sequence", "always_comb", "always_ff",
+        "always_latch", "unique", "priority", "return", "break", "continue", "final", "import", "export",
+    ];
+    RESERVED.contains(&name)
+}
+
+/// Prints `name`, escaping it if it is not a legal identifier.
+fn ident(name: &str) -> String {
+    if is_legal_ident(name) {
+        name.to_string()
+    } else {
+        format!("\\{name} ")
+    }
+}
+
+fn range(width: Width) -> String {
+    if width == 1 {
+        String::new()
+    } else {
+        format!("[{}:0] ", width - 1)
+    }
+}
+
+impl std::fmt::Display for VerilogExpr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerilogExpr::Const(value, width) => write!(f, "{width}'d{value}"),
+            VerilogExpr::Int(value) => write!(f, "{value}"),
+            VerilogExpr::X(width) => write!(f, "{width}'bx"),
+            VerilogExpr::Str(s) => write!(f, "\"{s}\""),
+            VerilogExpr::Ref(name) => write!(f, "{}", ident(name)),
+            VerilogExpr::Index(name, i) => write!(f, "{}[{i}]", ident(name)),
+            VerilogExpr::Slice(name, hi, lo) => write!(f, "{}[{hi}:{lo}]", ident(name)),
+            VerilogExpr::DynIndex(name, index) => write!(f, "{}[{index}]", ident(name)),
+            VerilogExpr::DynSlice(name, base, width) => write!(f, "{}[{base} +: {width}]", ident(name)),
+            VerilogExpr::Unop(Unop::Not, a) => write!(f, "~{}", Operand(a)),
+            VerilogExpr::Unop(Unop::Neg, a) => write!(f, "-{}", Operand(a)),
+            VerilogExpr::Unop(Unop::Signed, a) => write!(f, "$signed({a})"),
+            VerilogExpr::Binop(op, a, b) => {
+                let op = match op {
+                    Binop::Add => "+",
+                    Binop::Sub => "-",
+                    Binop::Mul => "*",
+                    Binop::And => "&",
+                    Binop::Or => "|",
+                    Binop::Xor => "^",
+                    Binop::Eq => "==",
+                    Binop::Neq => "!=",
+                    Binop::Lt => "<",
+                    Binop::Lte => "<=",
+                    Binop::Gt => ">",
+                    Binop::Gte => ">=",
+                    Binop::Sll => "<<",
+                    Binop::Srl => ">>",
+                    Binop::Sra => ">>>",
+                };
+                write!(f, "{} {op} {}", Operand(a), Operand(b))
+            },
+            VerilogExpr::Mux(c, a, b) => write!(f, "{} ? {} : {}", Operand(c), Operand(a), Operand(b)),
+            VerilogExpr::Cat(args) => {
+                let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                write!(f, "{{{}}}", args.join(", "))
+            },
+            VerilogExpr::Repeat(n, a) => write!(f, "{{{n}{{{a}}}}}"),
+        }
+    }
+}
+
+/// An expression in operand position, parenthesized when needed.
+struct Operand<'a>(&'a VerilogExpr);
+
+impl std::fmt::Display for Operand<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0.is_compound() {
+            write!(f, "({})", self.0)
+        } else {
+            write!(f, "{}", self.0)
+        }
+    }
+}
+
+impl VerilogFile {
+    pub fn write(&self, f: &mut dyn Write) -> std::io::Result<()> {
+        for item in &self.items {
+            match item {
+                VerilogItem::Include(source) => writeln!(f, "`include \"{source}\"")?,
+                VerilogItem::ModDef(moddef) => moddef.write(f)?,
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl VerilogModDef {
+    pub fn write(&self, f: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(f, "module {}(", ident(&self.name))?;
+        self.write_ports(f)?;
+        writeln!(f, ");")?;
+        for statement in &self.statements {
+            statement.write(f, 1)?;
+        }
+        writeln!(f, "endmodule")?;
+        Ok(())
+    }
+
+    fn write_ports(&self, f: &mut dyn Write) -> std::io::Result<()> {
+        for (i, port) in self.ports.iter().enumerate() {
+            let direction = match port.dir {
+                Dir::Input => "input  ",
+                Dir::Output => "output ",
+            };
+            let name = ident(&port.name);
+
+            if port.width == 1 {
+                write!(f, "    {direction} wire            {name}")?;
+            } else {
+                let width_str = format!("[{}:0]", port.width - 1);
+                write!(f, "    {direction} wire  {width_str: >8} {name}")?;
+            }
+
+            let last_port = i + 1 == self.ports.len();
+            if last_port {
+                writeln!(f)?;
+            } else {
+                writeln!(f, ",")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl VerilogStatement {
+    fn write(&self, f: &mut dyn Write, indent: usize) -> std::io::Result<()> {
+        let indentation = " ".repeat(4 * indent);
+
+        match self {
+            VerilogStatement::Comment(comment) => writeln!(f, "{indentation}// {comment}")?,
+            VerilogStatement::Blank => writeln!(f)?,
+            VerilogStatement::Wire(name, width, init) | VerilogStatement::Reg(name, width, init) => {
+                let kind = if let VerilogStatement::Wire(..) = self { "wire" } else { "reg" };
+                let name = ident(name);
+                let range = range(*width);
+                match init {
+                    Some(expr) => writeln!(f, "{indentation}{kind} {range}{name} = {expr};")?,
+                    None => writeln!(f, "{indentation}{kind} {range}{name};")?,
+                }
+            },
+            VerilogStatement::Localparam(name, width, expr) => {
+                writeln!(f, "{indentation}localparam {}{} = {expr};", range(*width), ident(name))?
+            },
+            VerilogStatement::Memory(name, width, depth) => {
+                writeln!(f, "{indentation}reg {}{} [0:{}];", range(*width), ident(name), depth - 1)?
+            },
+            VerilogStatement::Assign(name, expr) => writeln!(f, "{indentation}assign {} = {expr};", ident(name))?,
+            VerilogStatement::NonblockingAssign(name, expr) => writeln!(f, "{indentation}{} <= {expr};", ident(name))?,
+            VerilogStatement::BlockingAssign(name, expr) => writeln!(f, "{indentation}{} = {expr};", ident(name))?,
+            VerilogStatement::MemWrite(name, addr, data) => writeln!(f, "{indentation}{}[{addr}] <= {data};", ident(name))?,
+            VerilogStatement::If(cond, statements) => {
+                writeln!(f, "{indentation}if ({cond}) begin")?;
+                write_block(f, statements, indent)?;
+            },
+            VerilogStatement::SystemTask(task, args) => {
+                let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                writeln!(f, "{indentation}${task}({});", args.join(", "))?
+            },
+            VerilogStatement::AlwaysAtPosedge(clock, statements) => {
+                writeln!(f, "{indentation}always @(posedge {clock}) begin")?;
+                write_block(f, statements, indent)?;
+            },
+            VerilogStatement::AlwaysComb(statements) => {
+                writeln!(f, "{indentation}always @(*) begin")?;
+                write_block(f, statements, indent)?;
+            },
+            VerilogStatement::Initial(statements) => {
+                writeln!(f, "{indentation}initial begin")?;
+                write_block(f, statements, indent)?;
+            },
+            VerilogStatement::Final(statements) => {
+                writeln!(f, "{indentation}final begin")?;
+                write_block(f, statements, indent)?;
+            },
+            VerilogStatement::Case(subject, arms) => {
+                writeln!(f, "{indentation}case ({subject})")?;
+                let arm_indentation = " ".repeat(4 * (indent + 1));
+                for arm in arms {
+                    if let Some(comment) = &arm.comment {
+                        writeln!(f, "{arm_indentation}// {comment}")?;
+                    }
+                    match &arm.label {
+                        Some(label) => write!(f, "{arm_indentation}{label}: ")?,
+                        None => write!(f, "{arm_indentation}default: ")?,
+                    }
+                    if let [statement] = arm.body.as_slice() {
+                        let mut line = vec![];
+                        statement.write(&mut line, 0)?;
+                        f.write_all(&line)?;
+                    } else {
+                        writeln!(f, "begin")?;
+                        write_block(f, &arm.body, indent + 1)?;
+                    }
+                }
+                writeln!(f, "{indentation}endcase")?;
+            },
+            VerilogStatement::Instance(instance) => {
+                write!(f, "{indentation}{}", ident(&instance.moddef))?;
+                if !instance.params.is_empty() {
+                    let params: Vec<String> = instance.params.iter()
+                        .map(|(param, value)| format!(".{}({value})", ident(param)))
+                        .collect();
+                    write!(f, " #({})", params.join(", "))?;
+                }
+                writeln!(f, " {}(", ident(&instance.name))?;
+                for (i, (port, expr)) in instance.connections.iter().enumerate() {
+                    write!(f, "{indentation}    .{}({expr})", ident(port))?;
+                    let last_port = i + 1 == instance.connections.len();
+                    if last_port {
+                        writeln!(f)?;
+                    } else {
+                        writeln!(f, ",")?;
+                    }
+                }
+                writeln!(f, "{indentation});")?;
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Writes the statements of a `begin` ... `end` block, and the closing `end`.
+fn write_block(f: &mut dyn Write, statements: &[VerilogStatement], indent: usize) -> std::io::Result<()> {
+    for statement in statements {
+        statement.write(f, indent + 1)?;
+    }
+    writeln!(f, "{}end", " ".repeat(4 * indent))
+}
+
+#[test]
+fn verilog_passes() {
+    let mut moddef = VerilogModDef {
+        name: "Top".to_string(),
+        ports: vec![
+            VerilogPortDecl { name: "clock".to_string(), dir: Dir::Input, width: 1 },
+            VerilogPortDecl { name: "in".to_string(), dir: Dir::Input, width: 8 },
+            VerilogPortDecl { name: "out".to_string(), dir: Dir::Output, width: 8 },
+        ],
+        statements: vec![
+            VerilogStatement::Wire("three".to_string(), 8, Some(VerilogExpr::Const(3, 8))),
+            VerilogStatement::Wire("sum".to_string(), 8, Some(VerilogExpr::Binop(
+                Binop::Add,
+                Box::new(VerilogExpr::Ref("three".to_string())),
+                Box::new(VerilogExpr::Const(4, 8)),
+            ))),
+            VerilogStatement::Wire("unused".to_string(), 8, Some(VerilogExpr::Ref("in".to_string()))),
+            VerilogStatement::Wire("n".to_string(), 8, Some(VerilogExpr::Ref("in".to_string()))),
+            VerilogStatement::Reg("x[0]".to_string(), 8, None),
+            VerilogStatement::Reg("wire".to_string(), 8, None),
+            VerilogStatement::AlwaysAtPosedge(
+                VerilogExpr::Ref("clock".to_string()),
+                vec![
+                    VerilogStatement::NonblockingAssign("x[0]".to_string(), VerilogExpr::Ref("sum".to_string())),
+                    VerilogStatement::NonblockingAssign("wire".to_string(), VerilogExpr::Ref("x[0]".to_string())),
+                ],
+            ),
+            VerilogStatement::Assign("out".to_string(), VerilogExpr::Ref("wire".to_string())),
+        ],
+        temporaries: ["three", "sum", "unused"].into_iter().map(|name| name.to_string()).collect(),
+    };
+    moddef.run_passes();
+
+    let mut out = vec![];
+    moddef.write(&mut out).unwrap();
+    let text = String::from_utf8(out).unwrap();
+    assert!(!text.contains("three"));
+    assert!(!text.contains("unused"));
+    assert!(text.contains("wire [7:0] n = in;"));
+    assert!(text.contains("x_0_ <= 8'd7;"));
+    assert!(text.contains("wire_ <= x_0_;"));
+    assert!(text.contains("assign out = wire_;"));
+    assert!(text.contains("    input   wire            clock,"));
+}
+
+#[test]
+fn verilog_fold_mul_and_sra() {
+    let binop = |op: Binop, a: VerilogExpr, b: VerilogExpr| VerilogExpr::Binop(op, Box::new(a), Box::new(b));
+    let signed = |a: VerilogExpr| VerilogExpr::Unop(Unop::Signed, Box::new(a));
+    let cases = vec![
+        (binop(Binop::Mul, VerilogExpr::Const(255, 8), VerilogExpr::Const(255, 8)), VerilogExpr::Const(65025, 16)),
+        (binop(Binop::Sra, signed(VerilogExpr::Const(0b1000_0000, 8)), VerilogExpr::Const(2, 8)), VerilogExpr::Const(0b1110_0000, 8)),
+        (binop(Binop::Sra, signed(VerilogExpr::Const(0b0100_0000, 8)), VerilogExpr::Const(2, 8)), VerilogExpr::Const(0b0001_0000, 8)),
+        (binop(Binop::Sra, signed(VerilogExpr::Const(0b1000_0000, 8)), VerilogExpr::Const(9, 8)), VerilogExpr::Const(0b1111_1111, 8)),
+        (binop(Binop::Add, VerilogExpr::Const(255, 8), VerilogExpr::Const(1, 8)), VerilogExpr::Const(0, 8)),
+    ];
+    for (mut expr, expected) in cases {
+        expr.fold(&HashMap::new());
+        assert_eq!(expr, expected);
+    }
+}
@@ -0,0 +1,257 @@
+//! A small reader for the headers of hand-written Verilog modules.
+//!
+//! This is used to check that the Verilog implementation of an `ext mod` agrees with its declaration.
+//! It only understands enough of Verilog to recover the parameter and port lists of a module.
+
+use crate::common::*;
+use crate::virdant_error;
+
+#[derive(Debug, Clone, PartialEq, Eq, Copy)]
+pub enum VerilogDir {
+    Input,
+    Output,
+    Inout,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerilogPort {
+    pub name: String,
+    pub dir: VerilogDir,
+    /// The width of the port, when it is given with constant bounds.
+    pub width: Option<Width>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerilogHeader {
+    pub params: Vec<String>,
+    pub ports: Vec<VerilogPort>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Ident(String),
+    Range(String),
+    Punct(char),
+}
+
+/// Finds the module `module_name` in `text` and reads its parameters and ports.
+pub fn parse_module_header(text: &str, module_name: &str) -> VirdantResult<VerilogHeader> {
+    let tokens = tokenize(text);
+
+    let mut pos = 0;
+    loop {
+        match (tokens.get(pos), tokens.get(pos + 1)) {
+            (Some(Token::Ident(kw)), Some(Token::Ident(name))) if kw == "module" && name == module_name => {
+                pos += 2;
+                break;
+            },
+            (Some(_), _) => pos += 1,
+            (None, _) => return Err(virdant_error!("No module named {module_name} found")),
+        }
+    }
+
+    let mut params = vec![];
+    if tokens.get(pos) == Some(&Token::Punct('#')) {
+        let (param_tokens, end) = parenthesized(&tokens, pos + 1)?;
+        for entry in param_tokens.split(|token| *token == Token::Punct(',')) {
+            if let Some(name) = declared_param(entry, false) {
+                params.push(name);
+            }
+        }
+        pos = end;
+    }
+
+    let mut ports = vec![];
+    let mut port_names = vec![];
+    if tokens.get(pos) == Some(&Token::Punct('(')) {
+        let (port_tokens, end) = parenthesized(&tokens, pos)?;
+        let mut last: Option<(VerilogDir, Option<Width>)> = None;
+        for entry in port_tokens.split(|token| *token == Token::Punct(',')) {
+            let (decl, names) = port_decl(entry);
+            if decl.is_some() {
+                last = decl;
+            }
+            match (last, names.last()) {
+                (Some((dir, width)), Some(name)) => ports.push(VerilogPort { name: name.clone(), dir, width }),
+                (None, Some(name)) => port_names.push(name.clone()),
+                (_, None) => (),
+            }
+        }
+        pos = end;
+    }
+
+    // Non-ANSI style headers declare their ports in the body of the module.
+    let body_end = tokens[pos..].iter()
+        .position(|token| *token == Token::Ident("endmodule".to_string()))
+        .map(|i| pos + i)
+        .unwrap_or(tokens.len());
+
+    for statement in tokens[pos..body_end].split(|token| *token == Token::Punct(';')) {
+        if let Some(name) = declared_param(statement, true) {
+            params.push(name);
+        } else if let (Some((dir, width)), names) = port_decl(statement) {
+            for name in names {
+                if port_names.contains(&name) {
+                    ports.push(VerilogPort { name, dir, width });
+                }
+            }
+        }
+    }
+
+    Ok(VerilogHeader {
+        params,
+        ports,
+    })
+}
+
+fn tokenize(text: &str) -> Vec<Token> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c == '/' && chars.get(i + 1) == Some(&'/') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c == '/' && chars.get(i + 1) == Some(&'*') {
+            i += 2;
+            while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                i += 1;
+            }
+            i += 2;
+        } else if c == '[' {
+            let start = i + 1;
+            while i < chars.len() && chars[i] != ']' {
+                i += 1;
+            }
+            tokens.push(Token::Range(chars[start..i.min(chars.len())].iter().collect()));
+            i += 1;
+        } else if c.is_alphanumeric() || c == '_' || c == '$' || c == '`' || c == '\'' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '$' || chars[i] == '\'') {
+                i += 1;
+            }
+            i = i.max(start + 1);
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else if c.is_whitespace() {
+            i += 1;
+        } else {
+            tokens.push(Token::Punct(c));
+            i += 1;
+        }
+    }
+
+    tokens
+}
+
+/// Returns the tokens inside the parentheses which open at `start`, and the position just past them.
+fn parenthesized(tokens: &[Token], start: usize) -> VirdantResult<(&[Token], usize)> {
+    if tokens.get(start) != Some(&Token::Punct('(')) {
+        return Err(virdant_error!("Expected ( in module header"));
+    }
+
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate().skip(start) {
+        match token {
+            Token::Punct('(') => depth += 1,
+            Token::Punct(')') => {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok((&tokens[start + 1..i], i + 1));
+                }
+            },
+            _ => (),
+        }
+    }
+    Err(virdant_error!("Unbalanced parentheses in module header"))
+}
+
+/// Reads a parameter declaration such as `parameter DEPTH = 1024`, returning its name.
+/// Inside of a `#(...)` list, the `parameter` keyword is optional.
+fn declared_param(tokens: &[Token], require_keyword: bool) -> Option<String> {
+    let eq = tokens.iter().position(|token| *token == Token::Punct('='))?;
+    match tokens.first() {
+        Some(Token::Ident(kw)) if kw == "localparam" => return None,
+        Some(Token::Ident(kw)) if kw == "parameter" => (),
+        _ if require_keyword => return None,
+        _ => (),
+    }
+
+    match tokens[..eq].last() {
+        Some(Token::Ident(name)) => Some(name.clone()),
+        _ => None,
+    }
+}
+
+/// Reads a port declaration such as `input wire [7:0] a, b`,
+/// returning its direction and width (if it has a direction) and the names it declares.
+fn port_decl(tokens: &[Token]) -> (Option<(VerilogDir, Option<Width>)>, Vec<String>) {
+    let mut dir = None;
+    let mut width = Some(1);
+    let mut names = vec![];
+
+    for token in tokens {
+        match token {
+            Token::Ident(kw) if kw == "input" => dir = Some(VerilogDir::Input),
+            Token::Ident(kw) if kw == "output" => dir = Some(VerilogDir::Output),
+            Token::Ident(kw) if kw == "inout" => dir = Some(VerilogDir::Inout),
+            Token::Ident(kw) if ["wire", "reg", "logic", "signed", "unsigned", "var"].contains(&kw.as_str()) => (),
+            Token::Ident(name) => names.push(name.clone()),
+            Token::Range(range) => width = range_width(range),
+            Token::Punct(_) => (),
+        }
+    }
+
+    (dir.map(|dir| (dir, width)), names)
+}
+
+fn range_width(range: &str) -> Option<Width> {
+    let (hi, lo) = range.split_once(':')?;
+    let hi: u64 = hi.trim().parse().ok()?;
+    let lo: u64 = lo.trim().parse().ok()?;
+    Some(hi.max(lo) - hi.min(lo) + 1)
+}
+
+#[test]
+fn parse_module_headers() {
+    let text = "
+        module Other(input wire x);
+        endmodule
+
+        // An ANSI-style header
+        module Memory #(
+            parameter DEPTH = 1024,
+            parameter WIDTH = 8
+        ) (
+          input  wire clock,
+          input  wire [15:0] read_addr, /* the address */
+          output reg  [WIDTH-1:0]  read_data
+        );
+        endmodule
+
+        module Old(clock, out);
+            parameter INIT = 0;
+            input clock;
+            output [3:0] out;
+        endmodule
+    ";
+
+    let header = parse_module_header(text, "Memory").unwrap();
+    assert_eq!(header.params, vec!["DEPTH".to_string(), "WIDTH".to_string()]);
+    assert_eq!(header.ports, vec![
+        VerilogPort { name: "clock".to_string(), dir: VerilogDir::Input, width: Some(1) },
+        VerilogPort { name: "read_addr".to_string(), dir: VerilogDir::Input, width: Some(16) },
+        VerilogPort { name: "read_data".to_string(), dir: VerilogDir::Output, width: None },
+    ]);
+
+    let header = parse_module_header(text, "Old").unwrap();
+    assert_eq!(header.params, vec!["INIT".to_string()]);
+    assert_eq!(header.ports, vec![
+        VerilogPort { name: "clock".to_string(), dir: VerilogDir::Input, width: Some(1) },
+        VerilogPort { name: "out".to_string(), dir: VerilogDir::Output, width: Some(4) },
+    ]);
+
+    assert!(parse_module_header(text, "Missing").is_err());
+}